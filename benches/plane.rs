@@ -0,0 +1,40 @@
+use catenary::state::{ChatMessageIn, Plane, Trace};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use uuid::Uuid;
+
+// fills a plane with messages spread over germany and compares the grid lookup to a full scan
+fn get_messages(c: &mut Criterion) {
+    let mut plane = Plane::new();
+    let authors: Vec<Uuid> = (0..1000).map(|_| Uuid::new_v4()).collect();
+
+    // deterministic pseudo random numbers in [0, 1)
+    let mut seed: u64 = 42;
+    let mut random = move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        (seed >> 11) as f64 / (1u64 << 53) as f64
+    };
+
+    for i in 0..100_000 {
+        let location = (6.0 + random() * 9.0, 47.5 + random() * 7.5);
+        let trace = Trace::new(location, 5.0 + random() * 70.0, random() * 360.0);
+        plane.add_message(ChatMessageIn::new(
+            authors[i % authors.len()],
+            "hello".to_string(),
+            trace,
+        ));
+    }
+
+    let trace = Trace::new((9.99, 53.55), 35.0, 15.0);
+
+    let mut group = c.benchmark_group("get_messages");
+    group.bench_function("grid", |b| {
+        b.iter(|| plane.get_messages(None, black_box(trace.clone())))
+    });
+    group.bench_function("full scan", |b| {
+        b.iter(|| plane.get_messages_unindexed(None, black_box(trace.clone())))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, get_messages);
+criterion_main!(benches);
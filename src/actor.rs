@@ -0,0 +1,299 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::conversation::{ConversationError, ConversationOut, DirectMessage};
+    use crate::crypto::Sealed;
+    use crate::room::Membership;
+    use crate::state::{
+        ChangeMessageError, ChatMessage, ChatMessageIn, ChatMessageOut, Plane, PlaneEvent,
+        PlaneStats, Report, Trace, TraceError, UsernameError,
+    };
+    use std::panic::{self, AssertUnwindSafe};
+    use tokio::sync::{mpsc, oneshot};
+    use uuid::Uuid;
+
+    // max. amount of commands waiting for the plane, senders wait while the queue is full
+    const QUEUE_SIZE: usize = 1000;
+
+    type Command = Box<dyn FnOnce(&mut Plane) + Send>;
+
+    #[derive(Debug, Clone, thiserror::Error)]
+    #[error("plane is not running anymore or the command panicked")]
+    pub struct PlaneStopped;
+
+    // the plane is owned by a thread of its own that runs one command after another,
+    // callers wait in the queue instead of failing to get a lock, so nothing is dropped under load
+    #[derive(Debug, Clone)]
+    pub struct PlaneHandle {
+        tx: mpsc::Sender<Command>,
+    }
+
+    impl PlaneHandle {
+        // a plain thread instead of a task, because message stores may block, e.g. sqlite
+        pub fn spawn(mut plane: Plane) -> Self {
+            let (tx, mut rx) = mpsc::channel::<Command>(QUEUE_SIZE);
+            std::thread::Builder::new()
+                .name("plane".to_string())
+                .spawn(move || {
+                    while let Some(command) = rx.blocking_recv() {
+                        // a panic only fails its caller, the plane keeps running the other commands
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| command(&mut plane)));
+                        if let Err(payload) = result {
+                            let reason = payload
+                                .downcast_ref::<&str>()
+                                .copied()
+                                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                                .unwrap_or("unknown");
+                            log::error!("command panicked on the plane, error: {}", reason);
+                        }
+                    }
+                    log::info!("plane stopped, all handles are gone");
+                })
+                .expect("couldn't spawn plane thread");
+
+            Self { tx }
+        }
+
+        // runs `f` on the plane after all commands sent before and returns its result
+        pub async fn call<R: Send + 'static>(
+            &self,
+            f: impl FnOnce(&mut Plane) -> R + Send + 'static,
+        ) -> Result<R, PlaneStopped> {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let command: Command = Box::new(move |plane| {
+                // the caller may be gone by now, e.g. a closed websocket
+                let _ = reply_tx.send(f(plane));
+            });
+            self.tx.send(command).await.map_err(|_| PlaneStopped)?;
+
+            reply_rx.await.map_err(|_| PlaneStopped)
+        }
+
+        // amount of commands waiting
+        pub fn queue_len(&self) -> usize {
+            self.tx.max_capacity() - self.tx.capacity()
+        }
+
+        pub async fn add_message(&self, msg: ChatMessageIn) -> Result<ChatMessage, PlaneStopped> {
+            self.call(move |plane| plane.add_message(msg)).await
+        }
+
+        pub async fn vote_message(
+            &self,
+            id: Uuid,
+            user_id: Uuid,
+            up: bool,
+        ) -> Result<Option<ChatMessage>, PlaneStopped> {
+            self.call(move |plane| plane.vote_message(id, user_id, up)).await
+        }
+
+        pub async fn react_message(
+            &self,
+            id: Uuid,
+            user_id: Uuid,
+            emoji: String,
+        ) -> Result<Option<ChatMessage>, PlaneStopped> {
+            self.call(move |plane| plane.react_message(id, user_id, &emoji)).await
+        }
+
+        pub async fn get_messages(
+            &self,
+            user_id: Option<Uuid>,
+            trace: Trace,
+        ) -> Result<Vec<ChatMessageOut>, PlaneStopped> {
+            self.call(move |plane| plane.get_messages(user_id, trace)).await
+        }
+
+        pub async fn check_trace(
+            &self,
+            user_id: Option<Uuid>,
+            trace: Trace,
+        ) -> Result<Result<(), TraceError>, PlaneStopped> {
+            self.call(move |plane| plane.check_trace(user_id, &trace)).await
+        }
+
+        pub async fn join_room(
+            &self,
+            user_id: Uuid,
+            trace: Trace,
+        ) -> Result<Option<Membership>, PlaneStopped> {
+            self.call(move |plane| plane.join_room(user_id, &trace)).await
+        }
+
+        pub async fn apply_event(
+            &self,
+            event: PlaneEvent,
+        ) -> Result<Option<ChatMessage>, PlaneStopped> {
+            self.call(move |plane| plane.apply_event(event)).await
+        }
+
+        pub async fn report_message(
+            &self,
+            id: Uuid,
+            reporter: Uuid,
+            reason: String,
+        ) -> Result<bool, PlaneStopped> {
+            self.call(move |plane| plane.report_message(id, reporter, &reason)).await
+        }
+
+        pub async fn username(&self, user_id: Uuid) -> Result<String, PlaneStopped> {
+            self.call(move |plane| plane.username(user_id)).await
+        }
+
+        pub async fn reroll_username(&self, user_id: Uuid) -> Result<String, PlaneStopped> {
+            self.call(move |plane| plane.reroll_username(user_id)).await
+        }
+
+        pub async fn set_username(
+            &self,
+            user_id: Uuid,
+            username: String,
+        ) -> Result<Result<String, UsernameError>, PlaneStopped> {
+            self.call(move |plane| plane.set_username(user_id, &username)).await
+        }
+
+        pub async fn send_direct_message(
+            &self,
+            id: Uuid,
+            user_id: Uuid,
+            sealed: Sealed,
+        ) -> Result<Result<(DirectMessage, (Uuid, Uuid)), ConversationError>, PlaneStopped> {
+            self.call(move |plane| plane.send_direct_message(id, user_id, sealed)).await
+        }
+
+        pub async fn receive_direct_message(
+            &self,
+            id: Uuid,
+            msg: DirectMessage,
+        ) -> Result<Option<(Uuid, Uuid)>, PlaneStopped> {
+            self.call(move |plane| plane.receive_direct_message(id, msg)).await
+        }
+
+        pub async fn conversation(
+            &self,
+            id: Uuid,
+            user_id: Uuid,
+        ) -> Result<Result<ConversationOut, ConversationError>, PlaneStopped> {
+            self.call(move |plane| plane.conversation(id, user_id)).await
+        }
+
+        pub async fn conversations(
+            &self,
+            user_id: Uuid,
+        ) -> Result<Vec<ConversationOut>, PlaneStopped> {
+            self.call(move |plane| plane.conversations(user_id)).await
+        }
+
+        pub async fn block_conversation(
+            &self,
+            id: Uuid,
+            user_id: Uuid,
+            blocked: bool,
+        ) -> Result<Result<Uuid, ConversationError>, PlaneStopped> {
+            self.call(move |plane| plane.block_conversation(id, user_id, blocked)).await
+        }
+
+        pub async fn reports(&self) -> Result<Vec<Report>, PlaneStopped> {
+            self.call(|plane| plane.reports()).await
+        }
+
+        pub async fn delete_message(&self, id: Uuid) -> Result<Option<ChatMessage>, PlaneStopped> {
+            self.call(move |plane| plane.delete_message(id)).await
+        }
+
+        pub async fn edit_message(
+            &self,
+            id: Uuid,
+            user_id: Uuid,
+            text: String,
+        ) -> Result<Result<ChatMessage, ChangeMessageError>, PlaneStopped> {
+            self.call(move |plane| plane.edit_message(id, user_id, text)).await
+        }
+
+        pub async fn delete_own_message(
+            &self,
+            id: Uuid,
+            user_id: Uuid,
+        ) -> Result<Result<ChatMessage, ChangeMessageError>, PlaneStopped> {
+            self.call(move |plane| plane.delete_own_message(id, user_id)).await
+        }
+
+        pub async fn ban_author(&self, author: Uuid) -> Result<(), PlaneStopped> {
+            self.call(move |plane| plane.ban_author(author)).await
+        }
+
+        pub async fn stats(&self) -> Result<PlaneStats, PlaneStopped> {
+            self.call(|plane| plane.stats()).await
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes() {
+        const USERS: usize = 32;
+        const MESSAGES_PER_USER: usize = 50;
+
+        let plane = PlaneHandle::spawn(Plane::new());
+        let train = Trace::new((10.006, 53.553), 30.0, 90.0);
+        let first = plane
+            .add_message(ChatMessageIn::new(
+                Uuid::new_v4(),
+                "first".to_string(),
+                train.clone(),
+            ))
+            .await
+            .expect("couldn't add message");
+
+        // every user posts, reads and votes at the same time as all others
+        let tasks: Vec<_> = (0..USERS)
+            .map(|_| {
+                let plane = plane.clone();
+                let train = train.clone();
+                tokio::spawn(async move {
+                    let user_id = Uuid::new_v4();
+                    for i in 0..MESSAGES_PER_USER {
+                        let msg = ChatMessageIn::new(user_id, i.to_string(), train.clone());
+                        plane.add_message(msg).await.expect("couldn't add message");
+                        if i % 10 == 0 {
+                            plane
+                                .get_messages(Some(user_id), train.clone())
+                                .await
+                                .expect("couldn't get messages");
+                        }
+                    }
+                    plane
+                        .vote_message(first.id, user_id, true)
+                        .await
+                        .expect("couldn't vote")
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.expect("couldn't join task");
+        }
+
+        let messages = plane
+            .get_messages(None, train)
+            .await
+            .expect("couldn't get messages");
+        assert_eq!(messages.len(), 1 + USERS * MESSAGES_PER_USER);
+        let first = messages
+            .iter()
+            .find(|msg| msg.id == first.id)
+            .expect("couldn't find first message");
+        assert_eq!(first.upvoters, USERS, "no vote lost");
+        assert_eq!(plane.queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn survive_panics() {
+        let plane = PlaneHandle::spawn(Plane::new());
+
+        assert!(plane.call(|_| panic!("broken command")).await.is_err());
+        assert!(plane.stats().await.is_ok(), "still running after a panic");
+    }
+}
@@ -1,78 +1,701 @@
-use crate::state::{ChatMessageOut, Config, Trace};
+use crate::conversation::{ConversationError, ConversationOut};
+use crate::crypto::{PublicKey, Sealed};
+use crate::state::{
+    ChangeMessageError, ChatMessageOut, Config, PlaneStats, Report, Trace, TraceError,
+    UsernameError,
+};
 use leptos::*;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+#[cfg(feature = "ssr")]
+use crate::actor::PlaneHandle;
+#[cfg(feature = "ssr")]
+use crate::fanout::Fanout;
+#[cfg(feature = "ssr")]
+use crate::limit::Limiter;
+#[cfg(feature = "ssr")]
+use crate::metrics::METRICS;
+#[cfg(feature = "ssr")]
+use crate::moderation::{self, Moderator, Verdict};
 #[cfg(feature = "ssr")]
 use crate::state::*;
 #[cfg(feature = "ssr")]
-use leptos_axum::ResponseOptions;
+use std::net::IpAddr;
 #[cfg(feature = "ssr")]
 use std::sync::{Arc, Mutex};
 #[cfg(feature = "ssr")]
-use std::time::Duration;
-#[cfg(feature = "ssr")]
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{broadcast, mpsc::Sender};
+
+// why a message was not sent, shown in the send form
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum SendMessageError {
+    #[error("{0}")]
+    InvalidTrace(TraceError),
+    #[error("You are sending messages too fast, please wait {retry_after_seconds} seconds.")]
+    RateLimited { retry_after_seconds: u32 },
+    #[error("You already sent this message.")]
+    Duplicate,
+    #[error("You are muted, because too many of your messages were downvoted.")]
+    Muted,
+    #[error("You are banned for breaking the rules.")]
+    Banned,
+    #[error("The message you are replying to is not visible to you anymore.")]
+    InvalidReply,
+    #[error("{0}")]
+    InvalidConversation(ConversationError),
+    #[error("This message can't be deleted after the chosen time.")]
+    InvalidTtl,
+    #[error("This text is not allowed, please follow the rules.")]
+    Rejected,
+    #[error("{0}")]
+    InvalidChange(ChangeMessageError),
+}
 
 #[server(SendMessage, "/api")]
-pub async fn send_message(text: String, trace: Trace) -> Result<(), ServerFnError> {
+pub async fn send_message(
+    text: String,
+    trace: Trace,
+    reply_to: Option<Uuid>,
+    public_key: Option<PublicKey>,
+    ttl_minutes: Option<i64>,
+) -> Result<Result<(), SendMessageError>, ServerFnError> {
+    let _timer = METRICS.server_fn("send_message");
     let tx_in = use_context::<Sender<ChatMessageIn>>().expect("couldn't get sender context");
-    let response = expect_context::<ResponseOptions>();
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let limiter = use_context::<Arc<Mutex<Limiter>>>().expect("couldn't get limiter context");
+    let moderator = use_context::<Arc<dyn Moderator>>().expect("couldn't get moderator context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in send handler");
+        return Err(ServerFnError::ServerError(
+            "couldn't get user id".to_string(),
+        ));
+    };
 
-    tokio::time::sleep(Duration::from_millis(200)).await;
+    if text.trim().is_empty() {
+        return Ok(Ok(()));
+    }
+    if ttl_minutes
+        .is_some_and(|ttl_minutes| !CONFIG.message_ttl_options_minutes.contains(&ttl_minutes))
+    {
+        return Ok(Err(SendMessageError::InvalidTtl));
+    }
 
-    let user_id = match use_context::<Uuid>() {
-        Some(user_id) => user_id,
-        None => Uuid::new_v4(),
+    let checked_trace = plane
+        .call(move |plane| {
+            if plane.is_banned(user_id) {
+                return Err(SendMessageError::Banned);
+            }
+            if plane.is_muted(user_id) {
+                return Err(SendMessageError::Muted);
+            }
+            plane
+                .check_trace(Some(user_id), &trace)
+                .map_err(SendMessageError::InvalidTrace)?;
+            if let Some(reply_to) = reply_to {
+                if !plane.is_visible(reply_to, user_id, &trace) {
+                    return Err(SendMessageError::InvalidReply);
+                }
+            }
+            Ok(trace)
+        })
+        .await;
+    let trace = match checked_trace {
+        Ok(Ok(trace)) => trace,
+        Ok(Err(err)) => {
+            log::warn!("rejected message of user {}, error: {}", user_id, err);
+            return Ok(Err(err));
+        }
+        Err(err) => {
+            log::error!("couldn't check trace in send handler, error: {}", err);
+            return Err(err.into());
+        }
     };
 
-    response.insert_header(
-        http::header::SET_COOKIE,
-        http::HeaderValue::from_str(&format!("user={}; Max-Age={}", user_id, 3600 * 12))
-            .expect("couldn't set user cookie"),
-    );
-
-    if text.trim().is_empty() {
-        return Ok(());
+    let checked_message = match limiter.lock() {
+        Ok(mut limiter) => limiter.check(user_id, use_context::<IpAddr>(), &text),
+        Err(_) => {
+            log::warn!("couldn't lock limiter mutex in send handler");
+            METRICS.lock_failures.inc("limiter");
+            return Err(ServerFnError::ServerError(
+                "couldn't check rate limit".to_string(),
+            ));
+        }
+    };
+    if let Err(err) = checked_message {
+        log::info!("rejected message of user {}, error: {}", user_id, err);
+        return Ok(Err(err));
     }
 
-    let msg_in = ChatMessageIn::new(user_id, text, trace);
+    let mut msg_in = ChatMessageIn::new(user_id, text, trace);
+    msg_in.reply_to = reply_to;
+    msg_in.public_key = public_key;
+    msg_in.ttl_minutes = ttl_minutes;
+    let Some(msg_in) = moderation::moderate_message(moderator.as_ref(), msg_in) else {
+        return Ok(Err(SendMessageError::Rejected));
+    };
 
     if let Err(err) = tx_in.send(msg_in.clone()).await {
         log::error!("couldn't send chat message, error: {}", err);
-        return Ok(());
+        METRICS.messages_dropped.inc("channel_closed");
+        return Err(ServerFnError::ServerError(
+            "couldn't send chat message".to_string(),
+        ));
     }
 
-    Ok(())
+    Ok(Ok(()))
 }
 
 #[server(ListMessages, "/api")]
-pub async fn list_messages(trace: Trace) -> Result<Vec<ChatMessageOut>, ServerFnError> {
-    let tx = use_context::<Arc<Mutex<Plane>>>().expect("couldn't get plane context");
+pub async fn list_messages(
+    trace: Trace,
+) -> Result<Result<Vec<ChatMessageOut>, TraceError>, ServerFnError> {
+    let _timer = METRICS.server_fn("list_messages");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
     let user_id = use_context::<Uuid>();
 
-    let Ok(mut plane) = tx.lock() else {
-        log::warn!("couldn't lock plane mutex in list handler");
-        return Ok(vec![]);
-    };
-
-    Ok(plane.get_messages(user_id, trace))
+    let messages = plane
+        .call(move |plane| {
+            plane.check_trace(user_id, &trace)?;
+            Ok(plane.get_messages(user_id, trace))
+        })
+        .await;
+    match messages {
+        Ok(Ok(messages)) => Ok(Ok(messages)),
+        Ok(Err(err)) => {
+            log::warn!("rejected trace of user {:?}, error: {}", user_id, err);
+            Ok(Err(err))
+        }
+        Err(err) => {
+            log::error!("couldn't get messages in list handler, error: {}", err);
+            Ok(Ok(vec![]))
+        }
+    }
 }
 
 #[server(VoteMessage, "/api")]
 pub async fn vote_message(id: Uuid, up: bool) -> Result<(), ServerFnError> {
+    let _timer = METRICS.server_fn("vote_message");
     log::info!("vote_message with id {:?}, upvote: {}", id, up);
-    let tx = use_context::<Arc<Mutex<Plane>>>().expect("couldn't get plane context");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let tx_out = use_context::<broadcast::Sender<ChatMessage>>()
+        .expect("couldn't get broadcast sender context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
     let Some(user_id) = use_context::<Uuid>() else {
         log::warn!("couldn't get user id in vote handler");
         return Ok(());
     };
 
-    let Ok(mut plane) = tx.try_lock() else {
-        log::warn!("couldn't lock plane mutex in vote handler");
+    match plane.vote_message(id, user_id, up).await {
+        Ok(Some(msg)) => {
+            fanout.publish(PlaneEvent::Voted {
+                id,
+                user_id,
+                vote: msg.vote(user_id),
+            });
+            // no receivers just means no websocket is connected right now
+            let _ = tx_out.send(msg);
+        }
+        Ok(None) => {}
+        Err(err) => log::error!("couldn't vote in vote handler, error: {}", err),
+    }
+
+    Ok(())
+}
+
+// new text for a message of the user, moderated like new messages
+#[server(EditMessage, "/api")]
+pub async fn edit_message(
+    id: Uuid,
+    text: String,
+) -> Result<Result<(), SendMessageError>, ServerFnError> {
+    let _timer = METRICS.server_fn("edit_message");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let limiter = use_context::<Arc<Mutex<Limiter>>>().expect("couldn't get limiter context");
+    let moderator = use_context::<Arc<dyn Moderator>>().expect("couldn't get moderator context");
+    let tx_out = use_context::<broadcast::Sender<ChatMessage>>()
+        .expect("couldn't get broadcast sender context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in edit handler");
+        return Ok(Err(SendMessageError::InvalidChange(
+            ChangeMessageError::NotAuthor,
+        )));
+    };
+
+    let previous = plane
+        .call(move |plane| {
+            if plane.is_banned(user_id) {
+                return Err(SendMessageError::Banned);
+            }
+            if plane.is_muted(user_id) {
+                return Err(SendMessageError::Muted);
+            }
+            plane
+                .own_message(id, user_id)
+                .map(|msg| msg.text)
+                .map_err(SendMessageError::InvalidChange)
+        })
+        .await?;
+    let previous = match previous {
+        Ok(previous) => previous,
+        Err(err) => {
+            log::warn!("rejected edit of user {}, error: {}", user_id, err);
+            return Ok(Err(err));
+        }
+    };
+
+    let checked_edit = match limiter.lock() {
+        Ok(mut limiter) => limiter.check_edit(user_id, use_context::<IpAddr>(), &previous, &text),
+        Err(_) => {
+            log::warn!("couldn't lock limiter mutex in edit handler");
+            METRICS.lock_failures.inc("limiter");
+            return Err(ServerFnError::ServerError(
+                "couldn't check rate limit".to_string(),
+            ));
+        }
+    };
+    if let Err(err) = checked_edit {
+        log::info!("rejected edit of user {}, error: {}", user_id, err);
+        return Ok(Err(err));
+    }
+
+    let Some(text) = moderation::moderate_text(moderator.as_ref(), id, user_id, text) else {
+        return Ok(Err(SendMessageError::Rejected));
+    };
+
+    match plane.edit_message(id, user_id, text).await? {
+        Ok(msg) => {
+            fanout.publish(PlaneEvent::Edited {
+                id,
+                text: msg.text.clone(),
+                edited_at: msg.edited_at.unwrap_or(msg.timestamp),
+            });
+            // no receivers just means no websocket is connected right now
+            let _ = tx_out.send(msg);
+            Ok(Ok(()))
+        }
+        Err(err) => Ok(Err(SendMessageError::InvalidChange(err))),
+    }
+}
+
+// deletes a message of the user, clients remove it when they get its tombstone
+#[server(DeleteMessage, "/api")]
+pub async fn delete_message(id: Uuid) -> Result<Result<(), ChangeMessageError>, ServerFnError> {
+    let _timer = METRICS.server_fn("delete_message");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let tx_out = use_context::<broadcast::Sender<ChatMessage>>()
+        .expect("couldn't get broadcast sender context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in delete handler");
+        return Ok(Err(ChangeMessageError::NotAuthor));
+    };
+
+    match plane.delete_own_message(id, user_id).await? {
+        Ok(tombstone) => {
+            fanout.publish(PlaneEvent::MessageDeleted { id });
+            // no receivers just means no websocket is connected right now
+            let _ = tx_out.send(tombstone);
+            log::info!("user {} deleted message {}", user_id, id);
+            Ok(Ok(()))
+        }
+        Err(err) => Ok(Err(err)),
+    }
+}
+
+#[server(GetUsername, "/api")]
+pub async fn get_username() -> Result<String, ServerFnError> {
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in username handler");
+        return Ok("".to_string());
+    };
+
+    Ok(plane.username(user_id).await?)
+}
+
+// username chosen by the user, moderated like messages
+#[server(SetUsername, "/api")]
+pub async fn set_username(
+    username: String,
+) -> Result<Result<String, UsernameError>, ServerFnError> {
+    let _timer = METRICS.server_fn("set_username");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let moderator = use_context::<Arc<dyn Moderator>>().expect("couldn't get moderator context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in username handler");
+        return Ok(Err(UsernameError::Invalid));
+    };
+
+    if moderator.moderate(&username) != Verdict::Allow {
+        log::info!("rejected username of user {}", user_id);
+        return Ok(Err(UsernameError::NotAllowed));
+    }
+
+    let username = plane.set_username(user_id, username).await?;
+    if let Ok(username) = &username {
+        fanout.publish(PlaneEvent::Renamed {
+            author: user_id,
+            username: username.clone(),
+        });
+    }
+
+    Ok(username)
+}
+
+// replaces the user's username with a new random one
+#[server(RerollUsername, "/api")]
+pub async fn reroll_username() -> Result<String, ServerFnError> {
+    let _timer = METRICS.server_fn("reroll_username");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in username handler");
+        return Ok("".to_string());
+    };
+
+    let username = plane.reroll_username(user_id).await?;
+    fanout.publish(PlaneEvent::Renamed {
+        author: user_id,
+        username: username.clone(),
+    });
+
+    Ok(username)
+}
+
+// opens a conversation with the author of a message the user can see, returns its id
+#[server(OpenConversation, "/api")]
+pub async fn open_conversation(
+    message_id: Uuid,
+    trace: Trace,
+    public_key: PublicKey,
+) -> Result<Result<Uuid, ConversationError>, ServerFnError> {
+    let _timer = METRICS.server_fn("open_conversation");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in open conversation handler");
+        return Ok(Err(ConversationError::NotVisible));
+    };
+
+    let opened = plane
+        .call(move |plane| {
+            if let Err(err) = plane.check_trace(Some(user_id), &trace) {
+                log::warn!("rejected trace of user {}, error: {}", user_id, err);
+                return Err(ConversationError::NotVisible);
+            }
+            let (id, author) = plane.open_conversation(user_id, message_id, &trace, public_key)?;
+            Ok((id, author, plane.public_key(author)))
+        })
+        .await?;
+
+    match opened {
+        Ok((id, author, author_key)) => {
+            fanout.publish(PlaneEvent::ConversationOpened {
+                id,
+                user_ids: (user_id, author),
+            });
+            fanout.publish(PlaneEvent::KeyPublished {
+                user_id,
+                public_key,
+            });
+            // the author's key may have just been taken from their message
+            if let Some(public_key) = author_key {
+                fanout.publish(PlaneEvent::KeyPublished {
+                    user_id: author,
+                    public_key,
+                });
+            }
+            Ok(Ok(id))
+        }
+        Err(err) => Ok(Err(err)),
+    }
+}
+
+// the user's conversations with only their newest message, most recently active first
+#[server(ListConversations, "/api")]
+pub async fn list_conversations() -> Result<Vec<ConversationOut>, ServerFnError> {
+    let _timer = METRICS.server_fn("list_conversations");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in list conversations handler");
+        return Ok(vec![]);
+    };
+
+    Ok(plane.conversations(user_id).await?)
+}
+
+// the conversation with the other user's key, also publishes the key of the user's session if
+// there is one, pages only send it when they are opened, so open tabs don't keep replacing it
+#[server(GetConversation, "/api")]
+pub async fn get_conversation(
+    id: Uuid,
+    public_key: Option<PublicKey>,
+) -> Result<Result<ConversationOut, ConversationError>, ServerFnError> {
+    let _timer = METRICS.server_fn("get_conversation");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in conversation handler");
+        return Ok(Err(ConversationError::NotFound));
+    };
+
+    let checked = plane
+        .call(move |plane| {
+            let conversation = plane.conversation(id, user_id)?;
+            let changed =
+                public_key.is_some_and(|public_key| plane.publish_key(user_id, public_key));
+            Ok((conversation, changed))
+        })
+        .await?;
+
+    match checked {
+        Ok((conversation, changed)) => {
+            if let Some(public_key) = public_key.filter(|_| changed) {
+                fanout.publish(PlaneEvent::KeyPublished {
+                    user_id,
+                    public_key,
+                });
+            }
+            Ok(Ok(conversation))
+        }
+        Err(err) => Ok(Err(err)),
+    }
+}
+
+// private message to the other user of a conversation, encrypted in the browser, so only
+// limited and not moderated, pushed to both users via websocket
+#[server(SendDirectMessage, "/api")]
+pub async fn send_direct_message(
+    id: Uuid,
+    sealed: Sealed,
+) -> Result<Result<(), SendMessageError>, ServerFnError> {
+    let _timer = METRICS.server_fn("send_direct_message");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let limiter = use_context::<Arc<Mutex<Limiter>>>().expect("couldn't get limiter context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let tx_direct = use_context::<broadcast::Sender<(Uuid, Uuid)>>()
+        .expect("couldn't get direct message sender context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in direct message handler");
+        return Ok(Ok(()));
+    };
+
+    let (banned, muted) = plane
+        .call(move |plane| (plane.is_banned(user_id), plane.is_muted(user_id)))
+        .await?;
+    if banned {
+        return Ok(Err(SendMessageError::Banned));
+    }
+    if muted {
+        return Ok(Err(SendMessageError::Muted));
+    }
+
+    let checked_message = match limiter.lock() {
+        Ok(mut limiter) => limiter.check_rate(user_id, use_context::<IpAddr>()),
+        Err(_) => {
+            log::warn!("couldn't lock limiter mutex in direct message handler");
+            METRICS.lock_failures.inc("limiter");
+            return Err(ServerFnError::ServerError(
+                "couldn't check rate limit".to_string(),
+            ));
+        }
+    };
+    if let Err(err) = checked_message {
+        log::info!(
+            "rejected direct message of user {}, error: {}",
+            user_id,
+            err
+        );
+        return Ok(Err(err));
+    }
+
+    match plane.send_direct_message(id, user_id, sealed).await? {
+        Ok((msg, user_ids)) => {
+            fanout.publish(PlaneEvent::DirectMessageAdded {
+                id,
+                msg: Box::new(msg),
+            });
+            // no receivers just means no websocket is connected right now
+            let _ = tx_direct.send(user_ids);
+            Ok(Ok(()))
+        }
+        Err(err) => Ok(Err(SendMessageError::InvalidConversation(err))),
+    }
+}
+
+// blocks or unblocks the other user of a conversation, neither can send messages while blocked
+#[server(BlockConversation, "/api")]
+pub async fn block_conversation(
+    id: Uuid,
+    blocked: bool,
+) -> Result<Result<(), ConversationError>, ServerFnError> {
+    let _timer = METRICS.server_fn("block_conversation");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in block handler");
+        return Ok(Err(ConversationError::NotFound));
+    };
+
+    match plane.block_conversation(id, user_id, blocked).await? {
+        Ok(other) => {
+            fanout.publish(PlaneEvent::UserBlocked {
+                user_id,
+                other,
+                blocked,
+            });
+            log::info!("user {} set blocked of {} to {}", user_id, other, blocked);
+            Ok(Ok(()))
+        }
+        Err(err) => Ok(Err(err)),
+    }
+}
+
+// toggles a reaction with one of the emojis in `REACTIONS`
+#[server(ReactMessage, "/api")]
+pub async fn react_message(id: Uuid, emoji: String) -> Result<(), ServerFnError> {
+    let _timer = METRICS.server_fn("react_message");
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let tx_out = use_context::<broadcast::Sender<ChatMessage>>()
+        .expect("couldn't get broadcast sender context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in react handler");
+        return Ok(());
+    };
+
+    match plane.react_message(id, user_id, emoji.clone()).await {
+        Ok(Some(msg)) => {
+            fanout.publish(PlaneEvent::Reacted {
+                id,
+                user_id,
+                reacted: msg.reacted(user_id, &emoji),
+                emoji,
+            });
+            // no receivers just means no websocket is connected right now
+            let _ = tx_out.send(msg);
+        }
+        Ok(None) => {}
+        Err(err) => log::error!("couldn't react in react handler, error: {}", err),
+    }
+
+    Ok(())
+}
+
+#[server(ReportMessage, "/api")]
+pub async fn report_message(id: Uuid, reason: String) -> Result<(), ServerFnError> {
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let limiter = use_context::<Arc<Mutex<Limiter>>>().expect("couldn't get limiter context");
+    let Some(user_id) = use_context::<Uuid>() else {
+        log::warn!("couldn't get user id in report handler");
         return Ok(());
     };
 
-    plane.vote_message(id, user_id, up);
+    // reports count as messages, so nobody can flood the admins with them
+    let checked_report = match limiter.lock() {
+        Ok(mut limiter) => limiter.check_rate(user_id, use_context::<IpAddr>()),
+        Err(_) => {
+            log::warn!("couldn't lock limiter mutex in report handler");
+            METRICS.lock_failures.inc("limiter");
+            return Err(ServerFnError::ServerError(
+                "couldn't check rate limit".to_string(),
+            ));
+        }
+    };
+    if let Err(err) = checked_report {
+        log::info!("rejected report of user {}, error: {}", user_id, err);
+        return Ok(());
+    }
+
+    match plane.report_message(id, user_id, reason).await {
+        Ok(true) => log::info!("user {} reported message {}", user_id, id),
+        Ok(false) => log::info!("rejected report of user {} on message {}", user_id, id),
+        Err(err) => log::error!("couldn't report message in report handler, error: {}", err),
+    }
+
+    Ok(())
+}
+
+#[server(ListReports, "/api")]
+pub async fn list_reports(token: String) -> Result<Vec<Report>, ServerFnError> {
+    check_admin_token(&token)?;
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+
+    Ok(plane.reports().await?)
+}
+
+#[server(GetStats, "/api")]
+pub async fn get_stats(token: String) -> Result<PlaneStats, ServerFnError> {
+    check_admin_token(&token)?;
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+
+    Ok(plane.stats().await?)
+}
+
+#[server(DeleteReportedMessage, "/api")]
+pub async fn delete_reported_message(token: String, id: Uuid) -> Result<(), ServerFnError> {
+    check_admin_token(&token)?;
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let tx_out = use_context::<broadcast::Sender<ChatMessage>>()
+        .expect("couldn't get broadcast sender context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+
+    if let Some(tombstone) = plane.delete_message(id).await? {
+        // no receivers just means no websocket is connected right now
+        let _ = tx_out.send(tombstone);
+    }
+    fanout.publish(PlaneEvent::MessageDeleted { id });
+    log::info!("admin deleted message {}", id);
+
+    Ok(())
+}
+
+#[server(BanAuthor, "/api")]
+pub async fn ban_author(token: String, author: Uuid) -> Result<(), ServerFnError> {
+    check_admin_token(&token)?;
+    let plane = use_context::<PlaneHandle>().expect("couldn't get plane context");
+    let fanout = use_context::<Arc<dyn Fanout>>().expect("couldn't get fanout context");
+
+    plane.ban_author(author).await?;
+    fanout.publish(PlaneEvent::AuthorBanned { author });
+    log::info!("admin banned user {}", author);
+
+    Ok(())
+}
+
+// admin server functions are disabled without a configured token
+#[cfg(feature = "ssr")]
+fn check_admin_token(token: &str) -> Result<(), ServerFnError> {
+    let admin_token = CONFIG.admin_token.0.as_bytes();
+    // compares every byte, so the time taken doesn't tell how much of the token was right
+    let is_valid = !admin_token.is_empty()
+        && token.len() == admin_token.len()
+        && token
+            .bytes()
+            .zip(admin_token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+
+    if !is_valid {
+        log::warn!("rejected admin request with invalid token");
+        return Err(ServerFnError::ServerError(
+            "invalid admin token".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// why the client has no trace, for metrics
+#[server(ReportNoTrace, "/api")]
+pub async fn report_no_trace(kind: String) -> Result<(), ServerFnError> {
+    // only known kinds, so clients can't add arbitrary labels
+    if NoTrace::KINDS.contains(&kind.as_str()) {
+        METRICS.client_trace_states.inc(&kind);
+    }
 
     Ok(())
 }
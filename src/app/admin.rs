@@ -0,0 +1,191 @@
+use super::Titlebar;
+use crate::api::*;
+use crate::state::{PlaneStats, Report};
+use leptos::*;
+
+#[component]
+pub fn View() -> impl IntoView {
+    let (token_input, set_token_input) = create_signal("".to_string());
+    let (token, set_token) = create_signal("".to_string());
+    // changed after every action, to load reports and statistics again
+    let (reload, set_reload) = create_signal(0);
+
+    let reports = create_local_resource(
+        move || (token.get(), reload.get()),
+        |(token, _)| async move {
+            if token.is_empty() {
+                return None;
+            }
+            Some(list_reports(token).await)
+        },
+    );
+
+    let stats = create_local_resource(
+        move || (token.get(), reload.get()),
+        |(token, _)| async move {
+            if token.is_empty() {
+                return None;
+            }
+            Some(get_stats(token).await)
+        },
+    );
+
+    let stats_content = move || match stats.get().flatten() {
+        None => view! { <p>"Enter the admin token to see statistics."</p> }.into_view(),
+        Some(Err(err)) => view! {
+            <p class="admin-error">{format!("Couldn't load statistics: {}", err)}</p>
+        }
+        .into_view(),
+        Some(Ok(stats)) => view! { <Stats stats/> }.into_view(),
+    };
+
+    let content = move || match reports.get().flatten() {
+        None => view! { <p>"Enter the admin token to review reported messages."</p> }.into_view(),
+        Some(Err(err)) => view! {
+            <p class="admin-error">{format!("Couldn't load reports: {}", err)}</p>
+        }
+        .into_view(),
+        Some(Ok(reports)) if reports.is_empty() => view! { <p>"No reports."</p> }.into_view(),
+        Some(Ok(reports)) => reports
+            .into_iter()
+            .map(|report| view! { <ReportItem report token set_reload/> })
+            .collect_view(),
+    };
+
+    view! {
+        <Titlebar current_page="admin"/>
+        <div class="main-container mg">
+            <div class="main">
+                <div class="text-content admin">
+                    <h1>Admin</h1>
+                    <div class="token-form">
+                        <input
+                            type="password"
+                            placeholder="Admin token"
+                            on:input=move |ev| set_token_input(event_target_value(&ev))
+                            prop:value=token_input
+                        />
+                        <button
+                            class="clickable"
+                            on:click=move |_| {
+                                set_token(token_input.get_untracked());
+                                set_reload.update(|reload| *reload += 1);
+                            }
+                        >
+                            "Load"
+                        </button>
+                        <button
+                            class="clickable"
+                            on:click=move |_| set_reload.update(|reload| *reload += 1)
+                        >
+                            "Refresh"
+                        </button>
+                    </div>
+                    <h2>Statistics</h2>
+                    {stats_content}
+                    <h2>Reports</h2>
+                    {content}
+                </div>
+            </div>
+        </div>
+    }
+}
+
+#[component]
+fn Stats(stats: PlaneStats) -> impl IntoView {
+    let rows = vec![
+        ("Messages", stats.messages.to_string()),
+        ("Authors", stats.authors.to_string()),
+        (
+            "Messages per minute",
+            format!("{:.1}", stats.messages_per_minute),
+        ),
+        ("Active users", stats.users.to_string()),
+        ("Rooms", stats.rooms.to_string()),
+        ("Rooms with several users", stats.shared_rooms.to_string()),
+        ("Open reports", stats.reports.to_string()),
+        ("Muted authors", stats.muted_authors.to_string()),
+        ("Banned authors", stats.banned_authors.to_string()),
+        ("Conversations", stats.conversations.to_string()),
+        (
+            "Estimated memory",
+            format!(
+                "{:.1} MB",
+                stats.estimated_memory_bytes as f64 / 1_000_000.0
+            ),
+        ),
+    ];
+
+    view! {
+        <table class="stats">
+            {rows
+                .into_iter()
+                .map(|(name, value)| view! { <tr><td>{name}</td><td>{value}</td></tr> })
+                .collect_view()}
+        </table>
+        <h3>Config</h3>
+        <pre class="config">{stats.config}</pre>
+    }
+}
+
+#[component]
+fn ReportItem(
+    report: Report,
+    token: ReadSignal<String>,
+    set_reload: WriteSignal<i32>,
+) -> impl IntoView {
+    let msg = report.message;
+    let (id, author) = (msg.id, msg.author);
+    let (lon, lat) = msg.trace.location;
+    let trace = format!(
+        "{:.5}, {:.5} at {:.1} m/s heading {:.0}°",
+        lat, lon, msg.trace.speed, msg.trace.bearing
+    );
+    let reported_at = report.reported_at.format("%H:%M").to_string();
+
+    view! {
+        <div class="report">
+            <p class="report-text">{msg.text}</p>
+            <p>
+                {format!("{} ({})", msg.username, author)}<br/>
+                {trace}<br/>
+                {format!("sent {}, reported {}", msg.timestamp.format("%H:%M"), reported_at)}
+            </p>
+            <ul>
+                {report
+                    .reasons
+                    .into_iter()
+                    .map(|(_, reason)| view! { <li>{reason}</li> })
+                    .collect_view()}
+            </ul>
+            <div class="report-actions">
+                <button
+                    class="clickable"
+                    on:click=move |_| {
+                        spawn_local(async move {
+                            if let Err(err) = delete_reported_message(token.get_untracked(), id).await {
+                                log::error!("couldn't delete message, error: {}", err);
+                            }
+                            set_reload.update(|reload| *reload += 1);
+                        });
+                    }
+                >
+                    "Delete message"
+                </button>
+                <button
+                    class="clickable"
+                    on:click=move |_| {
+                        spawn_local(async move {
+                            if let Err(err) = ban_author(token.get_untracked(), author).await {
+                                log::error!("couldn't ban author, error: {}", err);
+                            }
+                            set_reload.update(|reload| *reload += 1);
+                        });
+                    }
+                >
+                    "Ban author"
+                </button>
+            </div>
+        </div>
+    }
+}
@@ -1,19 +1,33 @@
 use super::{Inbox, Titlebar};
 use crate::api::*;
+use crate::crypto::KeyPair;
 use crate::state::*;
+use chrono::{DateTime, Utc};
 use geo::Point;
 use leptos::*;
 use leptos_dom::helpers::IntervalHandle;
-use leptos_router::A;
-use leptos_use::{use_geolocation_with_options, UseGeolocationOptions, UseGeolocationReturn};
+use leptos_router::{use_navigate, A};
+use leptos_use::core::ConnectionReadyState;
+use leptos_use::{
+    use_geolocation_with_options, use_websocket_with_options, UseGeolocationOptions,
+    UseGeolocationReturn, UseWebSocketOptions, UseWebsocketReturn,
+};
+use std::rc::Rc;
 use std::time::Duration;
 
+// max. characters of a quoted message
+const QUOTE_LENGTH: usize = 60;
+// seconds before their expiry in which messages fade out
+const FADE_SECONDS: i64 = 60;
+
 #[component]
 pub fn View() -> impl IntoView {
     let (load_config, set_load_config) = create_signal(true);
-    let (location, set_location) = create_signal(None as Option<Point<f64>>);
+    // location and its accuracy radius in meters
+    let (location, set_location) = create_signal(None as Option<(Point<f64>, f64)>);
     let (location_history, set_location_history) = create_signal(None as Option<LocationHistory>);
     let (trace, set_trace) = create_signal(Result::<Trace, NoTrace>::Err(NoTrace::LoadingConfig));
+    let (config, set_config) = create_signal(None as Option<Config>);
 
     // get config
 
@@ -22,6 +36,7 @@ pub fn View() -> impl IntoView {
             log::info!("loading config");
             set_load_config(false);
             let config = get_config().await.expect("couldn't get configuration");
+            set_config(Some(config.clone()));
             set_location_history(Some(LocationHistory::new(config)));
         }
         "".to_owned()
@@ -30,19 +45,35 @@ pub fn View() -> impl IntoView {
     // add new geolocation and set trace
 
     create_effect(move |_| {
-        let Some(location) = location.get() else {
+        let Some((location, accuracy_meters)) = location.get() else {
             return;
         };
         let Some(mut location_history) = location_history.get_untracked() else {
             log::info!("no location history yet");
             return;
         };
-        location_history.add_location(location);
+        location_history.add_location(location, accuracy_meters);
         let new_trace = location_history.trace();
         set_location_history(Some(location_history));
         set_trace(new_trace);
     });
 
+    // report why there is no trace whenever that changes, for the server's metrics
+
+    create_effect(move |prev_kind: Option<Option<&'static str>>| {
+        let kind = trace.with(|trace| trace.as_ref().err().map(|no_trace| no_trace.kind()));
+        if let Some(kind) = kind {
+            if prev_kind.flatten() != Some(kind) {
+                spawn_local(async move {
+                    if let Err(err) = report_no_trace(kind.to_string()).await {
+                        log::warn!("couldn't report missing trace, error: {}", err);
+                    }
+                });
+            }
+        }
+        kind
+    });
+
     // get geolocations
 
     create_effect(move |prev_handle: Option<IntervalHandle>| {
@@ -60,7 +91,10 @@ pub fn View() -> impl IntoView {
                 return;
             }
             if let Some(coords) = coords.get() {
-                set_location(Some(Point::new(coords.longitude(), coords.latitude())));
+                set_location(Some((
+                    Point::new(coords.longitude(), coords.latitude()),
+                    coords.accuracy(),
+                )));
                 return;
             }
             if let Some(error) = error.get() {
@@ -78,7 +112,7 @@ pub fn View() -> impl IntoView {
         <div class="main-container">
             <div class="main fullscreen">
                 <NoTrace trace/>
-                <Chat trace/>
+                <Chat trace config/>
                 {move || loader.get()}
             </div>
         </div>
@@ -150,87 +184,123 @@ fn NoTrace(trace: ReadSignal<Result<Trace, NoTrace>>) -> impl IntoView {
                     </p>
                 </div>
             }.into_view(),
-            Ok(_) => view! { <div></div> }.into_view(),
+            Err(NoTrace::Stopped { .. }) | Ok(_) => view! { <div></div> }.into_view(),
+        }
+    };
+
+    let stopped_text = move || match trace() {
+        Err(NoTrace::Stopped { seconds_left, .. }) => {
+            format!("Stopped at a station? You stay in this chat for {seconds_left} more seconds without moving.")
         }
+        _ => "".to_string(),
     };
 
     view! {
         <Show
-            when=move || trace.get().is_err()
+            when=move || usable_trace(&trace.get()).is_none()
             fallback=move || view! {}
         >
             <div class="no-trace">
                 {text()}
             </div>
         </Show>
+        <Show
+            when=move || matches!(trace.get(), Err(NoTrace::Stopped { .. }))
+            fallback=move || view! {}
+        >
+            <div class="stopped">
+                <p>{stopped_text}</p>
+            </div>
+        </Show>
     }
     .into_view()
 }
 
 #[component]
-fn Chat(trace: ReadSignal<Result<Trace, NoTrace>>) -> impl IntoView {
+fn Chat(
+    trace: ReadSignal<Result<Trace, NoTrace>>,
+    config: ReadSignal<Option<Config>>,
+) -> impl IntoView {
     let inbox = use_context::<RwSignal<Inbox>>().expect("no inbox context");
-    let (load_messages, set_load_messages) = create_signal(false);
 
-    let loader = create_resource(load_messages, move |load_messages| async move {
-        if load_messages {
-            set_load_messages(false);
-            let Ok(trace) = trace.get_untracked() else {
-                return "".to_owned();
-            };
-            log::info!("loading messages");
-            list_messages(trace)
-                .await
-                .expect("couldn't list message")
-                .into_iter()
-                .for_each(|msg| {
-                    let mut inbox_updated = inbox.get_untracked();
-                    inbox_updated.push(msg.clone());
-                    inbox.set(inbox_updated);
-                });
+    // current time for the countdowns of messages, expired ones are removed
+
+    let (now, set_now) = create_signal(Utc::now());
+    create_effect(move |_| {
+        let tick = move || {
+            let now = Utc::now();
+            set_now(now);
+            // only notify the messages if one of them is gone
+            let expired = inbox
+                .with_untracked(|inbox| inbox.messages.iter().any(|msg| msg.expires_at <= now));
+            if expired {
+                inbox.update(|inbox| inbox.remove_expired(now));
+            }
+        };
+        match set_interval_with_handle(tick, Duration::from_millis(1000)) {
+            Ok(handle) => on_cleanup(move || handle.clear()),
+            Err(err) => log::error!("couldn't start countdown, error: {:?}", err),
         }
-        "".to_owned()
     });
 
-    create_effect(move |prev_handle: Option<IntervalHandle>| {
-        if let Some(prev_handle) = prev_handle {
-            prev_handle.clear();
-        };
+    // receive new and changed messages
 
-        set_interval_with_handle(
-            move || {
-                if load_messages.try_get_untracked().is_none() {
-                    return;
-                }
-                spawn_local(async move {
-                    set_load_messages(true);
-                });
-            },
-            Duration::from_millis(500),
-        )
-        .expect("could not create interval")
+    let UseWebsocketReturn {
+        ready_state, send, ..
+    } = use_websocket_with_options(
+        "/ws",
+        UseWebSocketOptions::default().on_message(move |text: String| {
+            match serde_json::from_str::<Push>(&text) {
+                Ok(Push::Messages(messages)) => inbox.update(|inbox| {
+                    messages.into_iter().for_each(|msg| inbox.push(msg));
+                }),
+                // shown on the messages page
+                Ok(Push::DirectMessage) => {}
+                Err(err) => log::warn!("couldn't parse messages from websocket, error: {}", err),
+            }
+        }),
+    );
+
+    // send current trace
+
+    create_effect(move |_| {
+        if ready_state.get() != ConnectionReadyState::Open {
+            return;
+        }
+        let Some(trace) = usable_trace(&trace.get()) else {
+            return;
+        };
+        match serde_json::to_string(&trace) {
+            Ok(json) => send(&json),
+            Err(err) => log::error!("couldn't serialize trace, error: {}", err),
+        }
     });
 
+    // message the user is replying to
+    let (reply_to, set_reply_to) = create_signal(None as Option<ChatMessageOut>);
+
     view! {
-        <Transition fallback=move || view! {
-            <div class="loading-container">
-                <span class="loader"></span>
-            </div>
-        }>
-            {move || loader.get()}
-            <Messages inbox set_load_messages trace/>
-            <SendForm set_load_messages trace />
-        </Transition>
+        <Messages inbox trace now set_reply_to/>
+        <SendForm trace config reply_to set_reply_to/>
     }
 }
 
 #[component]
 fn SendForm(
-    set_load_messages: WriteSignal<bool>,
     trace: ReadSignal<Result<Trace, NoTrace>>,
+    config: ReadSignal<Option<Config>>,
+    reply_to: ReadSignal<Option<ChatMessageOut>>,
+    set_reply_to: WriteSignal<Option<ChatMessageOut>>,
 ) -> impl IntoView {
     let (msg, set_msg) = create_signal("".to_string());
     let (sending, set_sending) = create_signal(false);
+    let (error, set_error) = create_signal(None as Option<SendMessageError>);
+    // minutes until the message is deleted, `None` for the max. message age
+    let (ttl_minutes, set_ttl_minutes) = create_signal(None as Option<i64>);
+    // sent along, so others can message the author of this message privately
+    let public_key = use_context::<Rc<KeyPair>>()
+        .expect("couldn't get key pair context")
+        .public_key();
 
     let send_button_props = move || match (sending.get(), msg.get().is_empty()) {
         (true, _) => ("Sending", "clickable disabled"),
@@ -240,9 +310,25 @@ fn SendForm(
 
     view! {
         <Show
-            when=move || trace.get().is_ok()
+            when=move || usable_trace(&trace.get()).is_some()
             fallback=move || view! {<div></div>}
         >
+            {move || error.get().map(|err| view! { <p class="send-error">{err.to_string()}</p> })}
+            {move || {
+                reply_to
+                    .get()
+                    .map(|parent| {
+                        view! {
+                            <div class="reply-to">
+                                <Quote parent/>
+                                <p class="clickable" on:click=move |_| set_reply_to(None)>
+                                    "✕"
+                                </p>
+                            </div>
+                        }
+                    })
+            }}
+            <NameBar/>
             <div class="send-form">
                 <textarea
                     placeholder="Type a message..."
@@ -253,23 +339,65 @@ fn SendForm(
                     }
                     prop:value={msg}
                 />
+                <select
+                    class="ttl-select"
+                    title="Delete the message after"
+                    on:change=move |ev| set_ttl_minutes(event_target_value(&ev).parse().ok())
+                >
+                    {move || config.get().map(|config| view! {
+                        <option value="" selected>
+                            {format!("{} min", config.max_message_age_minutes)}
+                        </option>
+                        {config
+                            .message_ttl_options_minutes
+                            .into_iter()
+                            .map(|minutes| view! {
+                                <option value=minutes>{format!("{} min", minutes)}</option>
+                            })
+                            .collect_view()}
+                    })}
+                </select>
                 <button class={move || send_button_props().1}
                     on:click=move |_| {
                         if sending.get() {
                             return;
                         }
                         spawn_local(async move {
-                            let Ok(trace) = trace.get_untracked() else {
+                            let Some(trace) = usable_trace(&trace.get_untracked()) else {
                                 log::error!("no trace for sending message, this shouldn't happen");
                                 return;
                             };
                             set_sending(true);
                             let msg_text = msg.get_untracked();
+                            let parent = reply_to.get_untracked();
                             set_msg("".to_string());
-                            send_message(msg_text, trace)
-                                .await
-                                .expect("couldn't send message");
-                            set_load_messages(true);
+                            set_reply_to(None);
+                            set_error(None);
+                            let parent_id = parent.as_ref().map(|parent| parent.id);
+                            let result = send_message(
+                                msg_text.clone(),
+                                trace,
+                                parent_id,
+                                Some(public_key),
+                                ttl_minutes.get_untracked(),
+                            )
+                            .await;
+                            match result {
+                                Ok(Ok(())) => {}
+                                Ok(Err(err)) => {
+                                    // keep the text and the reply, so it can be sent again
+                                    set_msg(msg_text);
+                                    if err != SendMessageError::InvalidReply {
+                                        set_reply_to(parent);
+                                    }
+                                    set_error(Some(err));
+                                }
+                                Err(err) => {
+                                    set_msg(msg_text);
+                                    set_reply_to(parent);
+                                    log::error!("couldn't send message, error: {}", err);
+                                }
+                            }
                             set_sending(false);
                         });
                     }
@@ -281,24 +409,83 @@ fn SendForm(
     }
 }
 
+// the user's username, with actions to choose one or get a new random one
+#[component]
+fn NameBar() -> impl IntoView {
+    // changed after renaming, to load the username again
+    let (reload, set_reload) = create_signal(0);
+    let username = create_local_resource(
+        move || reload.get(),
+        |_| async move {
+            match get_username().await {
+                Ok(username) => username,
+                Err(err) => {
+                    log::error!("couldn't get username, error: {}", err);
+                    "".to_string()
+                }
+            }
+        },
+    );
+
+    let choose = move |_| {
+        let current = username.get_untracked().unwrap_or_default();
+        let Ok(Some(new_username)) =
+            window().prompt_with_message_and_default("Choose a username", &current)
+        else {
+            return;
+        };
+        spawn_local(async move {
+            match set_username(new_username).await {
+                Ok(Ok(_)) => set_reload.update(|reload| *reload += 1),
+                Ok(Err(err)) => alert(&err.to_string()),
+                Err(err) => log::error!("couldn't set username, error: {}", err),
+            }
+        });
+    };
+
+    let reroll = move |_| {
+        spawn_local(async move {
+            match reroll_username().await {
+                Ok(_) => set_reload.update(|reload| *reload += 1),
+                Err(err) => log::error!("couldn't get a new username, error: {}", err),
+            }
+        });
+    };
+
+    view! {
+        <div class="name-bar">
+            <p>"You are " <b>{move || username.get().unwrap_or_default()}</b></p>
+            <p class="clickable" on:click=choose>"choose name"</p>
+            <p class="clickable" on:click=reroll>"random name"</p>
+        </div>
+    }
+}
+
 #[component]
 fn Messages(
     inbox: RwSignal<Inbox>,
-    set_load_messages: WriteSignal<bool>,
     trace: ReadSignal<Result<Trace, NoTrace>>,
+    now: ReadSignal<DateTime<Utc>>,
+    set_reply_to: WriteSignal<Option<ChatMessageOut>>,
 ) -> impl IntoView {
     view! {
         <Show
-            when=move || trace.get().is_ok()
+            when=move || usable_trace(&trace.get()).is_some()
             fallback=move || view! {<div></div>}
         >
             <div class="messages">
                 <For
                     each={move || inbox.get().messages}
-                    key=|message| format!("{}-{:?}-{}-{}", message.id, message.vote, message.upvoters, message.downvoters)
+                    key=|message| format!("{}-{:?}-{}-{}-{:?}-{:?}-{:?}", message.id, message.vote, message.upvoters, message.downvoters, message.reactions, message.own_reactions, message.edited_at)
                     children=move |msg| {
+                        // only messages still in the inbox can be quoted
+                        let parent = msg.reply_to.and_then(|parent_id| {
+                            inbox.with_untracked(|inbox| {
+                                inbox.messages.iter().find(|msg| msg.id == parent_id).cloned()
+                            })
+                        });
                         view! {
-                            <Message msg set_load_messages/>
+                            <Message msg parent trace now set_reply_to/>
                         }
                     }
                 />
@@ -308,9 +495,134 @@ fn Messages(
 }
 
 #[component]
-fn Message(msg: ChatMessageOut, set_load_messages: WriteSignal<bool>) -> impl IntoView {
+fn Message(
+    msg: ChatMessageOut,
+    parent: Option<ChatMessageOut>,
+    trace: ReadSignal<Result<Trace, NoTrace>>,
+    now: ReadSignal<DateTime<Utc>>,
+    set_reply_to: WriteSignal<Option<ChatMessageOut>>,
+) -> impl IntoView {
+    let quote = match (msg.reply_to, parent) {
+        (_, Some(parent)) => Some(view! { <Quote parent/> }.into_view()),
+        (Some(_), None) => {
+            Some(view! { <p class="quote">"Reply to an older message"</p> }.into_view())
+        }
+        (None, None) => None,
+    };
+    let reply_msg = msg.clone();
+    let id = msg.id;
+    let edited = msg.edited_at.is_some();
+    let text = msg.text.clone();
+    let own_links = msg.own.then(|| {
+        view! {
+            <p class="own-links">
+                <span on:click=move |_| {
+                    let Ok(Some(new_text)) = window()
+                        .prompt_with_message_and_default("Edit your message", &text)
+                    else {
+                        return;
+                    };
+                    spawn_local(async move {
+                        match edit_message(id, new_text).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => alert(&err.to_string()),
+                            Err(err) => log::error!("couldn't edit message, error: {}", err),
+                        }
+                    });
+                }>"edit"</span>
+                <span on:click=move |_| {
+                    if !window()
+                        .confirm_with_message("Delete this message for everyone?")
+                        .unwrap_or(false)
+                    {
+                        return;
+                    }
+                    spawn_local(async move {
+                        match delete_message(id).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => alert(&err.to_string()),
+                            Err(err) => log::error!("couldn't delete message, error: {}", err),
+                        }
+                    });
+                }>"delete"</span>
+            </p>
+        }
+    });
+    // only authors of messages the user can see can be messaged
+    let message_link = (!msg.own).then(|| {
+        let navigate = use_navigate();
+        let public_key = use_context::<Rc<KeyPair>>()
+            .expect("couldn't get key pair context")
+            .public_key();
+        view! {
+            <p
+                class="message-link"
+                on:click=move |_| {
+                    let Some(trace) = usable_trace(&trace.get_untracked()) else {
+                        return;
+                    };
+                    let navigate = navigate.clone();
+                    spawn_local(async move {
+                        match open_conversation(id, trace, public_key).await {
+                            Ok(Ok(conversation_id)) => navigate(
+                                &format!("/conversations/{}", conversation_id),
+                                Default::default(),
+                            ),
+                            Ok(Err(err)) => alert(&err.to_string()),
+                            Err(err) => log::error!("couldn't open conversation, error: {}", err),
+                        }
+                    });
+                }
+            >
+                "message"
+            </p>
+        }
+    });
+    let reactions = REACTIONS
+        .into_iter()
+        .map(|emoji| {
+            let count = msg.reactions.get(emoji).copied().unwrap_or(0);
+            let class = if msg.own_reactions.contains(emoji) {
+                "reaction on"
+            } else {
+                "reaction"
+            };
+            view! {
+                <span
+                    class=class
+                    on:click=move |_| {
+                        spawn_local(async move {
+                            if let Err(err) = react_message(id, emoji.to_string()).await {
+                                log::error!("couldn't react to message, error: {}", err);
+                            }
+                        });
+                    }
+                >
+                    {emoji}
+                    {(count > 0).then(|| view! { <span class="count">{count}</span> })}
+                </span>
+            }
+        })
+        .collect_view();
     let timestamp = msg.timestamp.format("%H:%M").to_string();
 
+    // seconds until the server deletes the message
+    let expires_at = msg.expires_at;
+    let remaining_seconds = move || (expires_at - now.get()).num_seconds().max(0);
+    let countdown = move || {
+        let seconds = remaining_seconds();
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    };
+    let fade_style = move || {
+        let seconds = remaining_seconds();
+        if seconds >= FADE_SECONDS {
+            "".to_owned()
+        } else {
+            let opacity = 0.2 + 0.8 * seconds as f64 / FADE_SECONDS as f64;
+            format!("opacity: {:.2};", opacity)
+        }
+    };
+
     let bubble_style = if msg.downvoters == 0 {
         "opacity: 1.0;".to_owned()
     } else if msg.downvoters >= 8 {
@@ -327,13 +639,49 @@ fn Message(msg: ChatMessageOut, set_load_messages: WriteSignal<bool>) -> impl In
         format!("text scale-{}", msg.upvoters)
     };
 
+    let (reported, set_reported) = create_signal(false);
+
     view! {
-        <div class="message message-in">
+        <div class="message message-in" style=fade_style>
             <p class="author">{msg.username}</p>
             <div class="content">
                 <div class="bubble" style={bubble_style}>
+                    {quote}
                     <p class=text_classes>{msg.text}</p>
-                    <p class="time">{timestamp}</p>
+                    <p class="time">
+                        {timestamp}
+                        {edited.then_some(" (edited)")}
+                        <span class="countdown" title="until this message is deleted">
+                            {countdown}
+                        </span>
+                    </p>
+                    <div class="reactions">{reactions}</div>
+                    {own_links}
+                    <p class="reply-link" on:click=move |_| set_reply_to(Some(reply_msg.clone()))>
+                        "reply"
+                    </p>
+                    {message_link}
+                    <p
+                        class="report-link"
+                        on:click=move |_| {
+                            if reported.get_untracked() {
+                                return;
+                            }
+                            let Ok(Some(reason)) = window()
+                                .prompt_with_message("Which rule does this message break?")
+                            else {
+                                return;
+                            };
+                            set_reported(true);
+                            spawn_local(async move {
+                                if let Err(err) = report_message(msg.id, reason).await {
+                                    log::error!("couldn't report message, error: {}", err);
+                                }
+                            });
+                        }
+                    >
+                        {move || if reported.get() { "reported" } else { "report" }}
+                    </p>
                 </div>
                 <div class="votes">
                     <img
@@ -349,7 +697,6 @@ fn Message(msg: ChatMessageOut, set_load_messages: WriteSignal<bool>) -> impl In
                         on:click=move |_| {
                             spawn_local(async move {
                                 vote_message(msg.id, true).await.expect("couldn't send message");
-                                set_load_messages(true);
                             });
                         }
                     />
@@ -366,7 +713,6 @@ fn Message(msg: ChatMessageOut, set_load_messages: WriteSignal<bool>) -> impl In
                         on:click=move |_| {
                             spawn_local(async move {
                                 vote_message(msg.id, false).await.expect("couldn't send message");
-                                set_load_messages(true);
                             });
                         }
                     />
@@ -375,3 +721,25 @@ fn Message(msg: ChatMessageOut, set_load_messages: WriteSignal<bool>) -> impl In
         </div>
     }
 }
+
+// author and beginning of the message replied to
+#[component]
+fn Quote(parent: ChatMessageOut) -> impl IntoView {
+    let mut text: String = parent.text.chars().take(QUOTE_LENGTH).collect();
+    if parent.text.chars().count() > QUOTE_LENGTH {
+        text.push('…');
+    }
+
+    view! {
+        <div class="quote">
+            <p class="quote-author">{parent.username}</p>
+            <p>{text}</p>
+        </div>
+    }
+}
+
+fn alert(text: &str) {
+    if let Err(err) = window().alert_with_message(text) {
+        log::error!("couldn't show alert, error: {:?}", err);
+    }
+}
@@ -0,0 +1,349 @@
+use super::Titlebar;
+use crate::api::*;
+use crate::conversation::{ConversationError, ConversationOut, DirectMessageOut};
+use crate::crypto::{KeyPair, PublicKey};
+use crate::state::Push;
+use leptos::*;
+use leptos_router::{use_params_map, A};
+use leptos_use::{use_websocket_with_options, UseWebSocketOptions};
+use std::rc::Rc;
+use uuid::Uuid;
+
+// max. characters of the newest message in the list of conversations
+const PREVIEW_LENGTH: usize = 60;
+// shown instead of messages encrypted for the keys of another page load
+const UNREADABLE_TEXT: &str = "Sent during an earlier visit, can't be decrypted anymore.";
+
+#[component]
+pub fn View() -> impl IntoView {
+    let (reload, set_reload) = create_signal(0);
+    reload_on_push(set_reload);
+
+    let conversations = create_local_resource(
+        move || reload.get(),
+        |_| async move {
+            match list_conversations().await {
+                Ok(conversations) => conversations,
+                Err(err) => {
+                    log::error!("couldn't list conversations, error: {}", err);
+                    vec![]
+                }
+            }
+        },
+    );
+
+    let content = move || match conversations.get() {
+        None => view! { <p>"Loading..."</p> }.into_view(),
+        Some(conversations) if conversations.is_empty() => view! {
+            <p>
+                "No conversations yet. Tap \"message\" below a message in the "
+                <A href="/chat">"chat"</A>
+                " to write to its author privately."
+            </p>
+        }
+        .into_view(),
+        Some(conversations) => conversations
+            .into_iter()
+            .map(|conversation| view! { <ConversationItem conversation/> })
+            .collect_view(),
+    };
+
+    view! {
+        <Titlebar current_page="messages"/>
+        <div class="main-container mg">
+            <div class="main">
+                <div class="text-content conversations">
+                    <h1>Messages</h1>
+                    {content}
+                </div>
+            </div>
+        </div>
+    }
+}
+
+#[component]
+fn ConversationItem(conversation: ConversationOut) -> impl IntoView {
+    let keys = use_context::<Rc<KeyPair>>().expect("couldn't get key pair context");
+    let preview = match conversation.messages.last() {
+        Some(msg) => {
+            let full_text = open_text(&keys, msg);
+            let mut text: String = full_text.chars().take(PREVIEW_LENGTH).collect();
+            if full_text.chars().count() > PREVIEW_LENGTH {
+                text.push('…');
+            }
+            if msg.own {
+                format!("You: {}", text)
+            } else {
+                text
+            }
+        }
+        None => "No messages yet".to_string(),
+    };
+
+    view! {
+        <A href=format!("/conversations/{}", conversation.id) class="conversation-item">
+            <p class="conversation-author">{conversation.username}</p>
+            <p>{preview}</p>
+        </A>
+    }
+}
+
+// a single conversation, reached from a message in the chat or the list of conversations
+#[component]
+pub fn Conversation() -> impl IntoView {
+    let params = use_params_map();
+    let id = create_memo(move |_| {
+        params.with(|params| params.get("id").and_then(|id| Uuid::parse_str(id).ok()))
+    });
+    // changed for new messages and after every action, to load the conversation again
+    let (reload, set_reload) = create_signal(0);
+    reload_on_push(set_reload);
+    // published when the page is opened, so the other user encrypts for this tab from then on
+    let public_key = use_context::<Rc<KeyPair>>()
+        .expect("couldn't get key pair context")
+        .public_key();
+
+    let conversation = create_local_resource(
+        move || (id.get(), reload.get()),
+        move |(id, reload)| async move {
+            let Some(id) = id else {
+                return Err("This conversation doesn't exist.".to_string());
+            };
+            let public_key = (reload == 0).then_some(public_key);
+            match get_conversation(id, public_key).await {
+                Ok(conversation) => conversation.map_err(|err| err.to_string()),
+                Err(err) => {
+                    log::error!("couldn't get conversation, error: {}", err);
+                    Err("Couldn't load the conversation, please try again.".to_string())
+                }
+            }
+        },
+    );
+    let open = move || {
+        conversation
+            .get()
+            .is_some_and(|conversation| conversation.is_ok_and(|conversation| conversation.open))
+    };
+    let recipient_key = Signal::derive(move || {
+        conversation
+            .with(|conversation| {
+                conversation
+                    .as_ref()
+                    .ok()
+                    .and_then(|conversation| conversation.public_key)
+            })
+            .flatten()
+    });
+
+    let content = move || match conversation.get() {
+        None => view! { <p class="conversation-bar">"Loading..."</p> }.into_view(),
+        Some(Err(err)) => view! { <p class="send-error">{err}</p> }.into_view(),
+        Some(Ok(conversation)) => view! { <DirectMessages conversation set_reload/> }.into_view(),
+    };
+
+    view! {
+        <Titlebar current_page="messages"/>
+        <div class="main-container">
+            <div class="main fullscreen">
+                {content}
+                <Show when=open fallback=move || view! {<div></div>}>
+                    <DirectSendForm id recipient_key set_reload/>
+                </Show>
+            </div>
+        </div>
+    }
+}
+
+#[component]
+fn DirectMessages(conversation: ConversationOut, set_reload: WriteSignal<usize>) -> impl IntoView {
+    let keys = use_context::<Rc<KeyPair>>().expect("couldn't get key pair context");
+    let id = conversation.id;
+    let blocked = conversation.blocked;
+    let toggle_block = move |_| {
+        if !blocked
+            && !window()
+                .confirm_with_message("Block this user? Neither of you can write here anymore.")
+                .unwrap_or(false)
+        {
+            return;
+        }
+        spawn_local(async move {
+            match block_conversation(id, !blocked).await {
+                Ok(Ok(())) => set_reload.update(|reload| *reload += 1),
+                Ok(Err(err)) => log::warn!("couldn't block conversation, error: {}", err),
+                Err(err) => log::error!("couldn't block conversation, error: {}", err),
+            }
+        });
+    };
+    let status = match (blocked, conversation.open) {
+        (true, _) => Some("You blocked this user."),
+        (false, false) => Some("This user blocked you."),
+        (false, true) => None,
+    };
+    // both users see the same two fingerprints, unless someone else swapped in their keys
+    let fingerprints = format!(
+        "Keys: yours {}, theirs {}",
+        keys.public_key().fingerprint(),
+        conversation
+            .public_key
+            .map_or("unknown".to_string(), |public_key| public_key.fingerprint())
+    );
+
+    view! {
+        <div class="conversation-bar">
+            <A href="/conversations">"←"</A>
+            <p><b>{conversation.username}</b></p>
+            <p class="clickable" on:click=toggle_block>
+                {if blocked { "unblock" } else { "block" }}
+            </p>
+        </div>
+        <p class="key-fingerprints">{fingerprints}</p>
+        {status.map(|status| view! { <p class="send-error">{status}</p> })}
+        <div class="messages">
+            {conversation
+                .messages
+                .into_iter()
+                .rev()
+                .map(|msg| view! { <DirectMessage msg/> })
+                .collect_view()}
+        </div>
+    }
+}
+
+#[component]
+fn DirectMessage(msg: DirectMessageOut) -> impl IntoView {
+    let keys = use_context::<Rc<KeyPair>>().expect("couldn't get key pair context");
+    let class = if msg.own {
+        "message direct own"
+    } else {
+        "message direct"
+    };
+    let timestamp = msg.timestamp.format("%H:%M").to_string();
+    let text = open_text(&keys, &msg);
+
+    view! {
+        <div class=class>
+            <div class="content">
+                <div class="bubble">
+                    <p class="text">{text}</p>
+                    <p class="time">{timestamp}</p>
+                </div>
+            </div>
+        </div>
+    }
+}
+
+#[component]
+fn DirectSendForm(
+    id: Memo<Option<Uuid>>,
+    // the other user's key, messages can only be sent once it is known
+    recipient_key: Signal<Option<PublicKey>>,
+    set_reload: WriteSignal<usize>,
+) -> impl IntoView {
+    let keys = use_context::<Rc<KeyPair>>().expect("couldn't get key pair context");
+    let (msg, set_msg) = create_signal("".to_string());
+    let (sending, set_sending) = create_signal(false);
+    let (error, set_error) = create_signal(None as Option<SendMessageError>);
+
+    let send_button_props = move || match (
+        sending.get(),
+        msg.get().is_empty() || recipient_key.get().is_none(),
+    ) {
+        (true, _) => ("Sending", "clickable disabled"),
+        (_, true) => ("Send", "clickable disabled"),
+        _ => ("Send", "clickable"),
+    };
+
+    view! {
+        {move || error.get().map(|err| view! { <p class="send-error">{err.to_string()}</p> })}
+        {move || {
+            recipient_key.get().is_none().then(|| {
+                view! {
+                    <p class="send-error">
+                        "Messages are encrypted for the other user's browser, you can write \
+                        once they post in the chat or open this conversation."
+                    </p>
+                }
+            })
+        }}
+        <div class="send-form">
+            <textarea
+                placeholder="Type a private message..."
+                type="text"
+                maxlength="144"
+                on:input=move |ev| {
+                    set_msg(event_target_value(&ev));
+                }
+                prop:value={msg}
+            />
+            <button class={move || send_button_props().1}
+                on:click=move |_| {
+                    let (Some(id), Some(recipient_key)) =
+                        (id.get_untracked(), recipient_key.get_untracked())
+                    else {
+                        return;
+                    };
+                    if sending.get() || msg.get_untracked().trim().is_empty() {
+                        return;
+                    }
+                    let msg_text = msg.get_untracked();
+                    let sealed = match keys.seal(recipient_key, &msg_text) {
+                        Ok(sealed) => sealed,
+                        Err(err) => {
+                            log::error!("couldn't encrypt direct message, error: {}", err);
+                            return;
+                        }
+                    };
+                    spawn_local(async move {
+                        set_sending(true);
+                        set_msg("".to_string());
+                        set_error(None);
+                        match send_direct_message(id, sealed).await {
+                            Ok(Ok(())) => set_reload.update(|reload| *reload += 1),
+                            Ok(Err(err)) => {
+                                // load the new key, the text is kept, so it can be sent again
+                                if err
+                                    == SendMessageError::InvalidConversation(
+                                        ConversationError::KeyChanged,
+                                    )
+                                {
+                                    set_reload.update(|reload| *reload += 1);
+                                }
+                                set_msg(msg_text);
+                                set_error(Some(err));
+                            }
+                            Err(err) => {
+                                set_msg(msg_text);
+                                log::error!("couldn't send direct message, error: {}", err);
+                            }
+                        }
+                        set_sending(false);
+                    });
+                }
+            >
+                {move || send_button_props().0}
+            </button>
+        </div>
+    }
+}
+
+// the text of a direct message, if it was encrypted for this page load
+fn open_text(keys: &KeyPair, msg: &DirectMessageOut) -> String {
+    keys.open(&msg.sealed)
+        .unwrap_or_else(|_| UNREADABLE_TEXT.to_string())
+}
+
+// changes the signal whenever the server pushes a new direct message of the user
+fn reload_on_push(set_reload: WriteSignal<usize>) {
+    use_websocket_with_options(
+        "/ws",
+        UseWebSocketOptions::default().on_message(move |text: String| {
+            match serde_json::from_str::<Push>(&text) {
+                Ok(Push::DirectMessage) => set_reload.update(|reload| *reload += 1),
+                // chat messages are only pushed after sending a trace
+                Ok(Push::Messages(_)) => {}
+                Err(err) => log::warn!("couldn't parse push from websocket, error: {}", err),
+            }
+        }),
+    );
+}
@@ -16,9 +16,9 @@ pub fn View() -> impl IntoView {
             "What data does Catenary collect and store?".to_string(),
             r#"When you send a message, Catenary stores the content of the message, your location data neccessary to connect you to other users, and the time the message was sent.
 
-            Messages and their metadata are stored on the server for less than 20 minutes. Note that you and other users who got the message may have a copy of it on their devices that persists for longer.
+            Messages and their metadata are deleted from the server when the countdown below them runs out, reported messages too. When sending a message, you can choose to have it deleted sooner. To stop spam, the server remembers what you wrote in the last few minutes in any case. Note that you and other users who got the message may have a copy of it on their devices that persists for longer.
 
-            Usernames you see in the chat are randomly generated. Messages from the same user will have the same username. To achieve this, Catenary stores a cookie on your device with a randomly generated ID. This cookie has a lifetime of 12 hours.
+            To recognize you, Catenary stores a signed cookie on your device with a randomly generated ID. This cookie has a lifetime of 12 hours after your last visit.
 
             There is no need to create an account to use Catenary."#.to_string(),
         ),
@@ -40,9 +40,29 @@ pub fn View() -> impl IntoView {
             It does that by matching you with people who have a similar location, speed and direction of travel.
 
             There are already apps out there matching people solely based on location, so Catenary is designed to fill a different niche; connecting people who are travelling.
+
+            Short stops, e.g. at a station, are fine. You stay in the chat for a few minutes without moving.
             "#.to_string(),
         ),
 
+        (
+            "How do usernames work?".to_string(),
+            r#"You get a randomly generated username. Messages from the same user have the same username.
+
+            You can get a new random name or choose your own below the chat. Chosen names may have 3 to 24 letters, digits, spaces, dots, dashes or underscores, must follow the rules and can't be used by someone you are currently chatting with. Your new name shows up on the messages you send afterwards.
+
+            Depending on the server's settings, you keep your name until your cookie expires, or only for the current trip. Then you get a new random name after a break of about an hour, so people on your next trip can't tell it's you.
+            "#.to_string(),
+        ),
+        (
+            "Can I message someone privately?".to_string(),
+            r#"Yes, tap "message" below a message in the chat to write to its author. You can only do that while you can see their message.
+
+            Private messages are encrypted in your browser, only you and the other user can read them, not even we can. The keys are new for every browser tab you open Catenary in and kept while the tab is open, so messages from an earlier visit can't be read anymore. With a conversation open in several tabs, only the one opened last can read new messages. Compare the key fingerprints shown above the conversation with the other user to make sure nobody else reads along.
+
+            Private messages are deleted once they are as old as messages in the chat can get, you can't choose a shorter time for them. That you had a conversation is kept until nobody wrote in it for that long. Both of you can block the other, then nobody can write in the conversation anymore, the block is kept for a day after the conversation is deleted.
+            "#.to_string(),
+        ),
         (
             "How does the voting system work?".to_string(),
             r#"Upvotes increase a message's text size, downvotes decrease its opacity.
@@ -1,17 +1,22 @@
+mod admin;
 mod chat;
+mod conversations;
 mod faq;
 mod footer;
 mod home;
 mod rules;
 
 use crate::{
+    crypto::KeyPair,
     error_template::{AppError, ErrorTemplate},
     state::ChatMessageOut,
 };
+use chrono::{DateTime, Duration, Utc};
 use leptos::*;
 use leptos_meta::*;
 use leptos_router::*;
 use std::collections::VecDeque;
+use std::rc::Rc;
 
 #[derive(Clone, Debug)]
 pub struct Inbox {
@@ -27,7 +32,14 @@ impl Default for Inbox {
 }
 
 impl Inbox {
-    pub fn push(&mut self, m: ChatMessageOut) {
+    pub fn push(&mut self, mut m: ChatMessageOut) {
+        // on the client's clock, which may differ from the server's
+        m.expires_at = Utc::now() + Duration::seconds(m.expires_in_seconds);
+        if m.hidden || m.deleted {
+            self.messages.retain(|msg| msg.id != m.id);
+            return;
+        }
+
         for msg in self.messages.iter_mut() {
             if msg.id == m.id {
                 *msg = m;
@@ -40,12 +52,19 @@ impl Inbox {
             self.messages.pop_back();
         }
     }
+
+    // removes messages the server deleted at their expiry
+    pub fn remove_expired(&mut self, now: DateTime<Utc>) {
+        self.messages.retain(|msg| msg.expires_at > now);
+    }
 }
 
 #[component]
 pub fn App() -> impl IntoView {
     provide_meta_context();
     provide_context(create_rw_signal(Inbox::default()));
+    // keys of direct messages, kept while the tab is open, the secret key is never sent anywhere
+    provide_context(Rc::new(session_key_pair()));
 
     view! {
         <Title text="Catenary - chat far and wide!"/>
@@ -72,8 +91,11 @@ pub fn App() -> impl IntoView {
                 <Routes>
                     <Route path="" view=home::View/>
                     <Route path="/chat" view=chat::View />
+                    <Route path="/conversations" view=conversations::View/>
+                    <Route path="/conversations/:id" view=conversations::Conversation/>
                     <Route path="/faq" view=faq::View/>
                     <Route path="/rules" view=rules::View/>
+                    <Route path="/admin" view=admin::View/>
                 </Routes>
             </main>
         </Router>
@@ -85,6 +107,12 @@ pub fn Titlebar(current_page: &'static str) -> impl IntoView {
     let links = vec![
         ("Home".to_string(), "/".to_string(), false, false),
         ("Chat".to_string(), "/chat".to_string(), false, false),
+        (
+            "Messages".to_string(),
+            "/conversations".to_string(),
+            false,
+            false,
+        ),
         ("FAQ".to_string(), "/faq".to_string(), false, false),
         ("Rules".to_string(), "/rules".to_string(), false, false),
         (
@@ -198,3 +226,35 @@ pub fn Bar(open: ReadSignal<bool>, set_open: WriteSignal<bool>) -> impl IntoView
         </div>
     }
 }
+
+// the tab's key pair from its session storage, so reloading the page keeps earlier direct
+// messages readable, a new one for new tabs
+#[cfg(not(feature = "ssr"))]
+fn session_key_pair() -> KeyPair {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    const STORAGE_KEY: &str = "direct-message-secret-key";
+
+    let storage = window().session_storage().ok().flatten();
+    let stored = storage
+        .as_ref()
+        .and_then(|storage| storage.get_item(STORAGE_KEY).ok().flatten())
+        .and_then(|secret| STANDARD.decode(secret).ok())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok());
+    if let Some(bytes) = stored {
+        return KeyPair::from_secret_bytes(bytes);
+    }
+
+    let keys = KeyPair::generate();
+    let secret = STANDARD.encode(keys.secret_bytes());
+    if storage.is_none_or(|storage| storage.set_item(STORAGE_KEY, &secret).is_err()) {
+        log::warn!("couldn't keep key pair in session storage");
+    }
+    keys
+}
+
+// the server renders pages without keys of a tab, direct messages are only loaded in the browser
+#[cfg(feature = "ssr")]
+fn session_key_pair() -> KeyPair {
+    KeyPair::generate()
+}
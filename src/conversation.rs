@@ -0,0 +1,521 @@
+use crate::crypto::{PublicKey, Sealed};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use uuid::Uuid;
+
+// max. messages kept per conversation, the oldest are dropped first
+const MAX_MESSAGES_PER_CONVERSATION: usize = 200;
+// hours a block is kept after the conversation expired, so it can't be opened again right away
+const BLOCK_MAX_AGE_HOURS: i64 = 24;
+
+// a message only the two users of a conversation can read, encrypted in the browser
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub id: Uuid,
+    pub author: Uuid,
+    pub sealed: Sealed,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DirectMessage {
+    pub fn new(author: Uuid, sealed: Sealed) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            author,
+            sealed,
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirectMessageOut {
+    pub id: Uuid,
+    pub sealed: Sealed,
+    // whether the user wrote the message
+    pub own: bool,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl From<(DirectMessage, Uuid)> for DirectMessageOut {
+    fn from((msg, user_id): (DirectMessage, Uuid)) -> Self {
+        Self {
+            id: msg.id,
+            sealed: msg.sealed,
+            own: msg.author == user_id,
+            timestamp: msg.timestamp,
+        }
+    }
+}
+
+// a conversation as seen by one of its users
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConversationOut {
+    pub id: Uuid,
+    // current username of the other user
+    pub username: String,
+    // latest key of the other user to encrypt messages for them, if they published one
+    pub public_key: Option<PublicKey>,
+    // oldest first
+    pub messages: Vec<DirectMessageOut>,
+    // whether the user blocked the other one
+    pub blocked: bool,
+    // false once either user blocked the other
+    pub open: bool,
+}
+
+// why a conversation couldn't be opened, read or written to
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum ConversationError {
+    #[error("You can only message authors of messages you can see.")]
+    NotVisible,
+    #[error("You can't message yourself.")]
+    OwnMessage,
+    #[error("This conversation doesn't exist anymore.")]
+    NotFound,
+    #[error("One of you blocked the other.")]
+    Blocked,
+    #[error("A message can't be empty.")]
+    EmptyText,
+    #[error("This message is too long.")]
+    TooLong,
+    #[error("The other user started a new session, please send your message again.")]
+    KeyChanged,
+}
+
+#[derive(Debug)]
+struct Conversation {
+    id: Uuid,
+    opened_at: DateTime<Utc>,
+    // oldest first
+    messages: VecDeque<DirectMessage>,
+}
+
+impl Conversation {
+    fn last_active_at(&self) -> DateTime<Utc> {
+        self.messages
+            .back()
+            .map_or(self.opened_at, |msg| msg.timestamp)
+    }
+}
+
+// private chats between two users, keyed by both user ids in ascending order
+#[derive(Debug, Default)]
+pub struct Conversations {
+    conversations: HashMap<(Uuid, Uuid), Conversation>,
+    // user ids by conversation id, instances opening the same conversation at once may give it
+    // several ids, all of them lead to it
+    user_ids_by_id: HashMap<Uuid, (Uuid, Uuid)>,
+    // other users each user has a conversation with
+    partners_by_user: HashMap<Uuid, HashSet<Uuid>>,
+    // blocking and blocked user, and when the block started
+    blocks: HashMap<(Uuid, Uuid), DateTime<Utc>>,
+    // latest key of each user with a conversation, messages are encrypted for it
+    public_keys: HashMap<Uuid, PublicKey>,
+}
+
+impl Conversations {
+    // id of the conversation between both users, opens one if they have none yet
+    pub fn open(
+        &mut self,
+        user_id: Uuid,
+        other: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<Uuid, ConversationError> {
+        if user_id == other {
+            return Err(ConversationError::OwnMessage);
+        }
+        if self.is_blocked(user_id, other) {
+            return Err(ConversationError::Blocked);
+        }
+        if let Some(conversation) = self.conversations.get(&pair(user_id, other)) {
+            return Ok(conversation.id);
+        }
+
+        let id = Uuid::new_v4();
+        self.insert(id, (user_id, other), now);
+
+        Ok(id)
+    }
+
+    // adds a conversation, e.g. one opened on another instance
+    pub fn insert(&mut self, id: Uuid, user_ids: (Uuid, Uuid), now: DateTime<Utc>) {
+        let user_ids = pair(user_ids.0, user_ids.1);
+        self.conversations
+            .entry(user_ids)
+            .or_insert_with(|| Conversation {
+                id,
+                opened_at: now,
+                messages: VecDeque::new(),
+            });
+        self.user_ids_by_id.insert(id, user_ids);
+        self.partners_by_user
+            .entry(user_ids.0)
+            .or_default()
+            .insert(user_ids.1);
+        self.partners_by_user
+            .entry(user_ids.1)
+            .or_default()
+            .insert(user_ids.0);
+    }
+
+    // both users of the conversation, in ascending order
+    pub fn user_ids(&self, id: Uuid) -> Option<(Uuid, Uuid)> {
+        self.user_ids_by_id.get(&id).copied()
+    }
+
+    // adds a message by one of the users, unless either blocked the other or it was encrypted
+    // for an outdated key of the other user
+    pub fn add_message(
+        &mut self,
+        id: Uuid,
+        msg: DirectMessage,
+    ) -> Result<DirectMessage, ConversationError> {
+        let (user_ids, other) = self.find(id, msg.author)?;
+        if self.is_blocked(msg.author, other) {
+            return Err(ConversationError::Blocked);
+        }
+        if self.public_keys.get(&other) != Some(&msg.sealed.recipient_key) {
+            return Err(ConversationError::KeyChanged);
+        }
+        let conversation = self
+            .conversations
+            .get_mut(&user_ids)
+            .ok_or(ConversationError::NotFound)?;
+
+        conversation.messages.push_back(msg.clone());
+        if conversation.messages.len() > MAX_MESSAGES_PER_CONVERSATION {
+            conversation.messages.pop_front();
+        }
+
+        Ok(msg)
+    }
+
+    // blocks or unblocks the other user of a conversation, returns their id
+    pub fn block(
+        &mut self,
+        id: Uuid,
+        user_id: Uuid,
+        blocked: bool,
+    ) -> Result<Uuid, ConversationError> {
+        let (_, other) = self.find(id, user_id)?;
+        self.set_blocked(user_id, other, blocked);
+
+        Ok(other)
+    }
+
+    pub fn set_blocked(&mut self, user_id: Uuid, other: Uuid, blocked: bool) {
+        if blocked {
+            self.blocks.insert((user_id, other), Utc::now());
+        } else {
+            self.blocks.remove(&(user_id, other));
+        }
+    }
+
+    // replaces the user's key, returns whether it changed
+    pub fn publish_key(&mut self, user_id: Uuid, public_key: PublicKey) -> bool {
+        self.public_keys.insert(user_id, public_key) != Some(public_key)
+    }
+
+    pub fn public_key(&self, user_id: Uuid) -> Option<PublicKey> {
+        self.public_keys.get(&user_id).copied()
+    }
+
+    // whether either user blocked the other
+    pub fn is_blocked(&self, user_id: Uuid, other: Uuid) -> bool {
+        self.blocks.contains_key(&(user_id, other)) || self.blocks.contains_key(&(other, user_id))
+    }
+
+    // the conversation as seen by one of its users
+    pub fn conversation(
+        &self,
+        id: Uuid,
+        user_id: Uuid,
+        username: impl Fn(Uuid) -> String,
+    ) -> Result<ConversationOut, ConversationError> {
+        let (user_ids, other) = self.find(id, user_id)?;
+        let conversation = self
+            .conversations
+            .get(&user_ids)
+            .ok_or(ConversationError::NotFound)?;
+
+        Ok(self.out(conversation, user_id, other, username(other), usize::MAX))
+    }
+
+    // the user's conversations with only their newest message, most recently active first
+    pub fn conversations(
+        &self,
+        user_id: Uuid,
+        username: impl Fn(Uuid) -> String,
+    ) -> Vec<ConversationOut> {
+        let Some(partners) = self.partners_by_user.get(&user_id) else {
+            return vec![];
+        };
+
+        let mut conversations: Vec<(DateTime<Utc>, ConversationOut)> = partners
+            .iter()
+            .filter_map(|other| {
+                let conversation = self.conversations.get(&pair(user_id, *other))?;
+                Some((
+                    conversation.last_active_at(),
+                    self.out(conversation, user_id, *other, username(*other), 1),
+                ))
+            })
+            .collect();
+        conversations.sort_by_key(|(last_active_at, _)| std::cmp::Reverse(*last_active_at));
+
+        conversations
+            .into_iter()
+            .map(|(_, conversation)| conversation)
+            .collect()
+    }
+
+    // deletes messages sent before the given time and conversations without newer messages
+    pub fn delete_messages_before(&mut self, oldest: DateTime<Utc>) {
+        let mut expired = HashSet::new();
+        for (user_ids, conversation) in self.conversations.iter_mut() {
+            while conversation
+                .messages
+                .front()
+                .is_some_and(|msg| msg.timestamp < oldest)
+            {
+                conversation.messages.pop_front();
+            }
+            if conversation.last_active_at() < oldest {
+                expired.insert(*user_ids);
+            }
+        }
+
+        self.conversations
+            .retain(|user_ids, _| !expired.contains(user_ids));
+        self.user_ids_by_id
+            .retain(|_, user_ids| !expired.contains(user_ids));
+        for (a, b) in expired {
+            for (user_id, other) in [(a, b), (b, a)] {
+                if let Some(partners) = self.partners_by_user.get_mut(&user_id) {
+                    partners.remove(&other);
+                    if partners.is_empty() {
+                        self.partners_by_user.remove(&user_id);
+                    }
+                }
+            }
+        }
+        // only users with conversations need keys
+        let partners_by_user = &self.partners_by_user;
+        self.public_keys
+            .retain(|user_id, _| partners_by_user.contains_key(user_id));
+        let oldest_block = oldest - Duration::hours(BLOCK_MAX_AGE_HOURS);
+        let conversations = &self.conversations;
+        self.blocks.retain(|(user_id, other), blocked_at| {
+            *blocked_at >= oldest_block || conversations.contains_key(&pair(*user_id, *other))
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.conversations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conversations.is_empty()
+    }
+
+    // user ids of a conversation and the id of the user's partner in it,
+    // users not in the conversation can't tell it exists
+    fn find(&self, id: Uuid, user_id: Uuid) -> Result<((Uuid, Uuid), Uuid), ConversationError> {
+        let user_ids = *self
+            .user_ids_by_id
+            .get(&id)
+            .ok_or(ConversationError::NotFound)?;
+
+        match user_ids {
+            (a, b) if a == user_id => Ok((user_ids, b)),
+            (a, b) if b == user_id => Ok((user_ids, a)),
+            _ => {
+                log::warn!(
+                    "user {} tried to access conversation {} of other users",
+                    user_id,
+                    id
+                );
+                Err(ConversationError::NotFound)
+            }
+        }
+    }
+
+    // newest `max_messages` messages of the conversation as seen by the user
+    fn out(
+        &self,
+        conversation: &Conversation,
+        user_id: Uuid,
+        other: Uuid,
+        username: String,
+        max_messages: usize,
+    ) -> ConversationOut {
+        let skip = conversation.messages.len().saturating_sub(max_messages);
+
+        ConversationOut {
+            id: conversation.id,
+            username,
+            public_key: self.public_key(other),
+            messages: conversation
+                .messages
+                .iter()
+                .skip(skip)
+                .map(|msg| DirectMessageOut::from((msg.clone(), user_id)))
+                .collect(),
+            blocked: self.blocks.contains_key(&(user_id, other)),
+            open: !self.is_blocked(user_id, other),
+        }
+    }
+}
+
+fn pair(a: Uuid, b: Uuid) -> (Uuid, Uuid) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    #[test]
+    fn conversations() {
+        let mut conversations = Conversations::default();
+        let (alice, bob, eve) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (alice_keys, bob_keys) = (KeyPair::generate(), KeyPair::generate());
+        let now = Utc::now();
+        let username = |_| "someone".to_string();
+
+        assert_eq!(
+            conversations.open(alice, alice, now),
+            Err(ConversationError::OwnMessage)
+        );
+        let id = conversations
+            .open(alice, bob, now)
+            .expect("couldn't open conversation");
+        assert_eq!(
+            conversations.open(bob, alice, now),
+            Ok(id),
+            "same conversation"
+        );
+        let send = |conversations: &mut Conversations, author, keys: &KeyPair, to, text| {
+            let sealed = keys.seal(to, text).expect("couldn't seal message");
+            conversations
+                .add_message(id, DirectMessage::new(author, sealed))
+                .map(|msg| msg.id)
+        };
+        assert!(conversations.publish_key(alice, alice_keys.public_key()));
+        assert!(conversations.publish_key(bob, bob_keys.public_key()));
+        assert!(!conversations.publish_key(bob, bob_keys.public_key()));
+
+        send(
+            &mut conversations,
+            alice,
+            &alice_keys,
+            bob_keys.public_key(),
+            "hi bob",
+        )
+        .expect("couldn't add message");
+        send(
+            &mut conversations,
+            bob,
+            &bob_keys,
+            alice_keys.public_key(),
+            "hi alice",
+        )
+        .expect("couldn't add message");
+        let to_eve = alice_keys
+            .seal(bob_keys.public_key(), "hi eve")
+            .expect("couldn't seal message");
+        assert_eq!(
+            conversations
+                .add_message(id, DirectMessage::new(eve, to_eve))
+                .map(|msg| msg.id),
+            Err(ConversationError::NotFound),
+            "eve is not part of the conversation"
+        );
+        assert!(conversations.conversation(id, eve, username).is_err());
+
+        // only the users can read their messages, the conversation just holds ciphertext
+        let conversation = conversations
+            .conversation(id, bob, username)
+            .expect("couldn't get conversation");
+        assert_eq!(conversation.public_key, Some(alice_keys.public_key()));
+        assert_eq!(
+            conversation
+                .messages
+                .iter()
+                .map(|msg| (bob_keys.open(&msg.sealed).unwrap_or_default(), msg.own))
+                .collect::<Vec<_>>(),
+            vec![
+                ("hi bob".to_string(), false),
+                ("hi alice".to_string(), true)
+            ]
+        );
+        let listed = conversations.conversations(alice, username);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].messages.len(), 1, "only the newest message");
+
+        // messages for an earlier key of the other user are rejected
+        let new_bob_keys = KeyPair::generate();
+        conversations.publish_key(bob, new_bob_keys.public_key());
+        assert_eq!(
+            send(
+                &mut conversations,
+                alice,
+                &alice_keys,
+                bob_keys.public_key(),
+                "still there?"
+            ),
+            Err(ConversationError::KeyChanged)
+        );
+
+        // either user can block the other
+        assert_eq!(conversations.block(id, bob, true), Ok(alice));
+        assert_eq!(
+            send(
+                &mut conversations,
+                alice,
+                &alice_keys,
+                new_bob_keys.public_key(),
+                "hello?"
+            ),
+            Err(ConversationError::Blocked)
+        );
+        assert_eq!(
+            conversations.open(alice, bob, now),
+            Err(ConversationError::Blocked)
+        );
+        let conversation = conversations
+            .conversation(id, alice, username)
+            .expect("couldn't get conversation");
+        assert!(!conversation.blocked && !conversation.open);
+        conversations
+            .block(id, bob, false)
+            .expect("couldn't unblock");
+        assert!(conversations.open(alice, bob, now).is_ok());
+
+        // same retention as public messages
+        conversations.delete_messages_before(Utc::now() + Duration::seconds(1));
+        assert!(conversations.is_empty());
+        assert!(conversations.conversations(alice, username).is_empty());
+        assert_eq!(conversations.public_key(alice), None);
+        assert_eq!(
+            conversations
+                .conversation(id, alice, username)
+                .map(|c| c.id),
+            Err(ConversationError::NotFound)
+        );
+
+        // blocks outlive the conversation for a while
+        conversations.set_blocked(alice, bob, true);
+        conversations.delete_messages_before(Utc::now() + Duration::seconds(1));
+        assert!(conversations.is_blocked(bob, alice));
+        conversations.delete_messages_before(Utc::now() + Duration::hours(BLOCK_MAX_AGE_HOURS + 1));
+        assert!(!conversations.is_blocked(bob, alice));
+    }
+}
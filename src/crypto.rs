@@ -0,0 +1,234 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::StaticSecret;
+
+// binds derived keys to direct messages, so they are useless for anything else
+const KEY_INFO: &[u8] = b"catenary direct message";
+// bytes the AEAD adds to every message
+pub const TAG_LENGTH: usize = 16;
+
+// X25519 public key, base64 encoded in urls and json
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PublicKey(#[serde(with = "base64_bytes")] pub [u8; 32]);
+
+impl PublicKey {
+    // start of the key's hash, short enough for both users to compare, e.g. in person
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(self.0)[..8]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// a direct message encrypted for the two users of a conversation, all the server gets to see
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sealed {
+    // keys of both users when it was sent, either user can open it with their secret key
+    pub sender_key: PublicKey,
+    pub recipient_key: PublicKey,
+    #[serde(with = "base64_bytes")]
+    pub nonce: [u8; 12],
+    #[serde(with = "base64_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    // length of the text in bytes, without opening it
+    pub fn text_length(&self) -> usize {
+        self.ciphertext.len().saturating_sub(TAG_LENGTH)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum CryptoError {
+    #[error("the message was sent to another key, e.g. of an earlier session")]
+    WrongKey,
+    #[error("the other key is invalid")]
+    InvalidKey,
+    #[error("couldn't encrypt the message")]
+    Encrypt,
+    #[error("couldn't decrypt the message, it may have been changed")]
+    Decrypt,
+    #[error("the decrypted message is not valid text")]
+    InvalidText,
+}
+
+// X25519 key pair of a browser session, the secret key never leaves the client
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random())
+    }
+
+    // key pair kept by the browser, e.g. across page loads
+    pub fn from_secret_bytes(bytes: [u8; 32]) -> Self {
+        Self::from_secret(StaticSecret::from(bytes))
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey(x25519_dalek::PublicKey::from(&secret).to_bytes());
+
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn seal(&self, recipient_key: PublicKey, text: &str) -> Result<Sealed, CryptoError> {
+        let cipher = self.cipher(recipient_key, self.public, recipient_key)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, text.as_bytes())
+            .map_err(|_| CryptoError::Encrypt)?;
+
+        Ok(Sealed {
+            sender_key: self.public,
+            recipient_key,
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    // opens messages sent by or to this key pair
+    pub fn open(&self, sealed: &Sealed) -> Result<String, CryptoError> {
+        let other_key = if sealed.sender_key == self.public {
+            sealed.recipient_key
+        } else if sealed.recipient_key == self.public {
+            sealed.sender_key
+        } else {
+            return Err(CryptoError::WrongKey);
+        };
+        let cipher = self.cipher(other_key, sealed.sender_key, sealed.recipient_key)?;
+        let text = cipher
+            .decrypt(
+                Nonce::from_slice(&sealed.nonce),
+                sealed.ciphertext.as_slice(),
+            )
+            .map_err(|_| CryptoError::Decrypt)?;
+
+        String::from_utf8(text).map_err(|_| CryptoError::InvalidText)
+    }
+
+    // the same key for both users, derived from their shared secret and bound to both keys
+    fn cipher(
+        &self,
+        other_key: PublicKey,
+        sender_key: PublicKey,
+        recipient_key: PublicKey,
+    ) -> Result<ChaCha20Poly1305, CryptoError> {
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(other_key.0));
+        // low order keys would make the shared secret known to everyone
+        if !shared_secret.was_contributory() {
+            return Err(CryptoError::InvalidKey);
+        }
+
+        let mut salt = [0; 64];
+        salt[..32].copy_from_slice(&sender_key.0);
+        salt[32..].copy_from_slice(&recipient_key.0);
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes())
+            .expand(KEY_INFO, &mut key)
+            .map_err(|_| CryptoError::InvalidKey)?;
+
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+// bytes as base64 strings, which stay short in urls and json
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: impl AsRef<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: TryFrom<Vec<u8>>,
+    {
+        let bytes = STANDARD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(D::Error::custom)?;
+
+        T::try_from(bytes).map_err(|_| D::Error::custom("invalid length"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let (alice, bob, eve) = (
+            KeyPair::generate(),
+            KeyPair::generate(),
+            KeyPair::generate(),
+        );
+
+        let sealed = alice
+            .seal(bob.public_key(), "meet me in the dining car")
+            .expect("couldn't seal message");
+        assert_eq!(sealed.text_length(), "meet me in the dining car".len());
+        assert_eq!(
+            bob.open(&sealed),
+            Ok("meet me in the dining car".to_string())
+        );
+        assert_eq!(
+            alice.open(&sealed),
+            Ok("meet me in the dining car".to_string()),
+            "senders can read their own messages"
+        );
+        assert_eq!(eve.open(&sealed), Err(CryptoError::WrongKey));
+        let restored_bob = KeyPair::from_secret_bytes(bob.secret_bytes());
+        assert_eq!(restored_bob.public_key(), bob.public_key());
+        assert_eq!(
+            restored_bob.open(&sealed),
+            Ok("meet me in the dining car".to_string()),
+            "after reloading the page"
+        );
+        assert_eq!(bob.public_key().fingerprint().len(), 19);
+        assert_ne!(
+            bob.public_key().fingerprint(),
+            eve.public_key().fingerprint()
+        );
+
+        let mut changed = sealed.clone();
+        changed.ciphertext[0] ^= 1;
+        assert_eq!(bob.open(&changed), Err(CryptoError::Decrypt));
+
+        let json = serde_json::to_string(&sealed).expect("couldn't serialize sealed message");
+        let parsed: Sealed = serde_json::from_str(&json).expect("couldn't parse sealed message");
+        assert_eq!(parsed, sealed);
+
+        assert_eq!(
+            alice
+                .seal(PublicKey([0; 32]), "hi")
+                .map(|sealed| sealed.nonce),
+            Err(CryptoError::InvalidKey)
+        );
+    }
+}
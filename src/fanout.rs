@@ -0,0 +1,437 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::state::{PlaneEvent, CONFIG};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+    // max. amount of events queued for a peer before new ones are dropped
+    const PEER_QUEUE_SIZE: usize = 1000;
+    // seconds to wait before reconnecting to a peer
+    const PEER_RECONNECT_SECONDS: u64 = 2;
+    // seconds to wait for the challenge of a peer after connecting
+    const HANDSHAKE_TIMEOUT_SECONDS: u64 = 10;
+    // max. bytes of a line, longer ones end the connection
+    const MAX_FRAME_BYTES: usize = 64 * 1024;
+    // min. bytes of the secret shared by all instances
+    const MIN_SECRET_BYTES: usize = 32;
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    // shares plane events with other instances, events received from them are sent to the
+    // `Sender<PlaneEvent>` the fanout was created with
+    pub trait Fanout: std::fmt::Debug + Send + Sync {
+        // sends an event to all other instances, never blocks
+        fn publish(&self, event: PlaneEvent);
+    }
+
+    // fanout from the config, `NoFanout` if no listen address is set
+    pub async fn from_config(incoming: Sender<PlaneEvent>) -> anyhow::Result<Arc<dyn Fanout>> {
+        if CONFIG.fanout_listen_addr.is_empty() {
+            return Ok(Arc::new(NoFanout));
+        }
+        let secret = CONFIG.fanout_secret.0.as_bytes();
+        if secret.len() < MIN_SECRET_BYTES {
+            anyhow::bail!(
+                "FANOUT_SECRET must be at least {} bytes if FANOUT_LISTEN_ADDR is set",
+                MIN_SECRET_BYTES
+            );
+        }
+
+        let peer_addrs = CONFIG
+            .fanout_peers
+            .split(',')
+            .map(|addr| addr.trim().to_string())
+            .filter(|addr| !addr.is_empty())
+            .collect();
+        let fanout =
+            TcpFanout::start(&CONFIG.fanout_listen_addr, peer_addrs, secret, incoming).await?;
+
+        Ok(Arc::new(fanout))
+    }
+
+    // for a single instance
+    #[derive(Debug, Default)]
+    pub struct NoFanout;
+
+    impl Fanout for NoFanout {
+        fn publish(&self, _event: PlaneEvent) {}
+    }
+
+    // connects fanouts within the same process, e.g. for tests
+    #[derive(Debug, Clone, Default)]
+    pub struct LoopbackHub {
+        peers: Arc<Mutex<Vec<Sender<PlaneEvent>>>>,
+    }
+
+    impl LoopbackHub {
+        pub fn connect(&self, incoming: Sender<PlaneEvent>) -> LoopbackFanout {
+            let mut peers = self.peers.lock().expect("couldn't lock loopback peers");
+            peers.push(incoming);
+
+            LoopbackFanout {
+                id: peers.len() - 1,
+                hub: self.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct LoopbackFanout {
+        id: usize,
+        hub: LoopbackHub,
+    }
+
+    impl Fanout for LoopbackFanout {
+        fn publish(&self, event: PlaneEvent) {
+            let Ok(peers) = self.hub.peers.lock() else {
+                log::warn!("couldn't lock loopback peers");
+                return;
+            };
+            for (id, peer) in peers.iter().enumerate() {
+                if id == self.id {
+                    continue;
+                }
+                if let Err(err) = peer.try_send(event.clone()) {
+                    log::warn!("couldn't send event to loopback peer {}, error: {}", id, err);
+                }
+            }
+        }
+    }
+
+    // sends events as json lines over tcp to a fixed list of peers and receives theirs,
+    // every instance has to list all other instances as peers since events aren't forwarded
+    //
+    // the receiving side starts each connection with a random challenge, every line after that is
+    // the base64 hmac of the challenge, the line's number and its json, followed by a space and the
+    // json, so only instances with the same secret can send events and lines can't be replayed
+    #[derive(Debug)]
+    pub struct TcpFanout {
+        local_addr: SocketAddr,
+        peers: Vec<(String, Sender<PlaneEvent>)>,
+    }
+
+    impl TcpFanout {
+        pub async fn start(
+            listen_addr: &str,
+            peer_addrs: Vec<String>,
+            secret: &[u8],
+            incoming: Sender<PlaneEvent>,
+        ) -> anyhow::Result<Self> {
+            let secret: Arc<[u8]> = Arc::from(secret);
+            let listener = TcpListener::bind(listen_addr).await?;
+            let local_addr = listener.local_addr()?;
+            log::info!("receiving events from peers on {}", local_addr);
+            tokio::spawn(accept_peers(listener, secret.clone(), incoming));
+
+            let peers = peer_addrs
+                .into_iter()
+                .map(|addr| {
+                    let (tx, rx) = channel(PEER_QUEUE_SIZE);
+                    tokio::spawn(send_to_peer(addr.clone(), secret.clone(), rx));
+                    (addr, tx)
+                })
+                .collect();
+
+            Ok(Self { local_addr, peers })
+        }
+
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+    }
+
+    impl Fanout for TcpFanout {
+        fn publish(&self, event: PlaneEvent) {
+            for (addr, peer) in self.peers.iter() {
+                if let Err(err) = peer.try_send(event.clone()) {
+                    log::warn!("couldn't queue event for peer {}, error: {}", addr, err);
+                }
+            }
+        }
+    }
+
+    async fn accept_peers(listener: TcpListener, secret: Arc<[u8]>, incoming: Sender<PlaneEvent>) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    log::warn!("couldn't accept peer connection, error: {}", err);
+                    continue;
+                }
+            };
+            log::info!("peer {} connected", addr);
+            tokio::spawn(receive_from_peer(stream, addr, secret.clone(), incoming.clone()));
+        }
+    }
+
+    async fn receive_from_peer(
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        secret: Arc<[u8]>,
+        incoming: Sender<PlaneEvent>,
+    ) {
+        let mut challenge = [0; 32];
+        if let Err(err) = getrandom::getrandom(&mut challenge) {
+            log::error!("couldn't create challenge for peer {}, error: {}", addr, err);
+            return;
+        }
+        let line = format!("{}\n", STANDARD.encode(challenge));
+        if let Err(err) = stream.write_all(line.as_bytes()).await {
+            log::warn!("couldn't send challenge to peer {}, error: {}", addr, err);
+            return;
+        }
+
+        let mut reader = BufReader::new(stream);
+        let mut counter: u64 = 0;
+        loop {
+            let line = match read_frame(&mut reader).await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(err) => {
+                    log::warn!("couldn't read from peer {}, error: {}", addr, err);
+                    break;
+                }
+            };
+            let Some(json) = verify_frame(&secret, &challenge, counter, &line) else {
+                log::warn!("peer {} sent an invalid signature, disconnecting", addr);
+                break;
+            };
+            counter += 1;
+            let event = match serde_json::from_str::<PlaneEvent>(json) {
+                Ok(event) => event,
+                Err(err) => {
+                    log::warn!("couldn't parse event from peer {}, error: {}", addr, err);
+                    continue;
+                }
+            };
+            if incoming.send(event).await.is_err() {
+                break;
+            }
+        }
+        log::info!("peer {} disconnected", addr);
+    }
+
+    async fn send_to_peer(addr: String, secret: Arc<[u8]>, mut events: Receiver<PlaneEvent>) {
+        // event that couldn't be written before the connection broke, sent again after reconnecting
+        let mut pending: Option<String> = None;
+
+        loop {
+            let (mut reader, challenge) = match connect(&addr).await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    log::warn!("couldn't connect to peer {}, error: {}", addr, err);
+                    tokio::time::sleep(Duration::from_secs(PEER_RECONNECT_SECONDS)).await;
+                    continue;
+                }
+            };
+            log::info!("connected to peer {}", addr);
+
+            let mut counter: u64 = 0;
+            loop {
+                let json = match pending.take() {
+                    Some(json) => json,
+                    None => {
+                        let Some(event) = events.recv().await else {
+                            return;
+                        };
+                        match serde_json::to_string(&event) {
+                            Ok(json) => json,
+                            Err(err) => {
+                                log::error!("couldn't serialize event, error: {}", err);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                let signature = frame_mac(&secret, &challenge, counter, &json)
+                    .finalize()
+                    .into_bytes();
+                let line = format!("{} {}\n", STANDARD.encode(signature), json);
+                if let Err(err) = reader.get_mut().write_all(line.as_bytes()).await {
+                    log::warn!("couldn't write to peer {}, error: {}", addr, err);
+                    pending = Some(json);
+                    break;
+                }
+                counter += 1;
+            }
+        }
+    }
+
+    // connects to a peer and reads its challenge
+    async fn connect(addr: &str) -> anyhow::Result<(BufReader<TcpStream>, Vec<u8>)> {
+        let mut reader = BufReader::new(TcpStream::connect(addr).await?);
+        let line = tokio::time::timeout(
+            Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS),
+            read_frame(&mut reader),
+        )
+        .await??
+        .ok_or_else(|| anyhow::anyhow!("connection closed before the challenge"))?;
+
+        Ok((reader, STANDARD.decode(line)?))
+    }
+
+    // reads a line without its newline, `None` once the connection is closed
+    async fn read_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<String>> {
+        let mut bytes = vec![];
+        let read = reader
+            .take(MAX_FRAME_BYTES as u64 + 1)
+            .read_until(b'\n', &mut bytes)
+            .await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if bytes.pop() != Some(b'\n') {
+            anyhow::bail!("line is longer than {} bytes or incomplete", MAX_FRAME_BYTES);
+        }
+
+        Ok(Some(String::from_utf8(bytes)?))
+    }
+
+    fn frame_mac(secret: &[u8], challenge: &[u8], counter: u64, json: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("hmac takes keys of any size");
+        mac.update(challenge);
+        mac.update(&counter.to_be_bytes());
+        mac.update(json.as_bytes());
+        mac
+    }
+
+    // the json of a line if its signature is valid
+    fn verify_frame<'a>(
+        secret: &[u8],
+        challenge: &[u8],
+        counter: u64,
+        line: &'a str,
+    ) -> Option<&'a str> {
+        let (signature, json) = line.split_once(' ')?;
+        let signature = STANDARD.decode(signature).ok()?;
+        frame_mac(secret, challenge, counter, json)
+            .verify_slice(&signature)
+            .ok()?;
+
+        Some(json)
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use crate::state::{ChatMessageIn, Plane, Trace, Vote};
+    use uuid::Uuid;
+
+    fn sync(plane: &mut Plane, incoming: &mut Receiver<PlaneEvent>) {
+        while let Ok(event) = incoming.try_recv() {
+            plane.apply_event(event);
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let hub = LoopbackHub::default();
+        let (tx_a, mut rx_a) = channel(100);
+        let (tx_b, mut rx_b) = channel(100);
+        let fanout_a = hub.connect(tx_a);
+        let fanout_b = hub.connect(tx_b);
+        let mut plane_a = Plane::new();
+        let mut plane_b = Plane::new();
+
+        let trace = Trace::new((9.99, 53.55), 10.0, 0.0);
+        let author = Uuid::new_v4();
+        let voter = Uuid::new_v4();
+
+        let msg = plane_a.add_message(ChatMessageIn::new(author, "hi".to_string(), trace.clone()));
+        fanout_a.publish(PlaneEvent::MessageAdded(Box::new(msg.clone())));
+        sync(&mut plane_b, &mut rx_b);
+
+        let voted = plane_b
+            .vote_message(msg.id, voter, true)
+            .expect("message should be on plane b");
+        fanout_b.publish(PlaneEvent::Voted {
+            id: msg.id,
+            user_id: voter,
+            vote: voted.vote(voter),
+        });
+        sync(&mut plane_a, &mut rx_a);
+
+        for plane in [&mut plane_a, &mut plane_b] {
+            let messages = plane.get_messages(Some(voter), trace.clone());
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].username, msg.username);
+            assert_eq!(messages[0].upvoters, 1);
+            assert_eq!(messages[0].vote, Some(Vote::Up));
+        }
+    }
+
+    const SECRET: &[u8] = b"a secret shared by both instances";
+
+    #[tokio::test]
+    async fn tcp() {
+        let (tx_a, _rx_a) = channel(100);
+        let (tx_b, mut rx_b) = channel(100);
+        let fanout_b = TcpFanout::start("127.0.0.1:0", vec![], SECRET, tx_b)
+            .await
+            .expect("couldn't start fanout b");
+        let fanout_a = TcpFanout::start(
+            "127.0.0.1:0",
+            vec![fanout_b.local_addr().to_string()],
+            SECRET,
+            tx_a,
+        )
+        .await
+        .expect("couldn't start fanout a");
+
+        let id = Uuid::new_v4();
+        fanout_a.publish(PlaneEvent::Voted {
+            id,
+            user_id: id,
+            vote: Some(Vote::Down),
+        });
+
+        let event = tokio::time::timeout(Duration::from_secs(5), rx_b.recv())
+            .await
+            .expect("no event within 5 seconds");
+        assert!(matches!(
+            event,
+            Some(PlaneEvent::Voted {
+                vote: Some(Vote::Down),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn tcp_rejects_other_secrets() {
+        let (tx_a, _rx_a) = channel(100);
+        let (tx_b, mut rx_b) = channel(100);
+        let fanout_b = TcpFanout::start("127.0.0.1:0", vec![], SECRET, tx_b)
+            .await
+            .expect("couldn't start fanout b");
+        let fanout_a = TcpFanout::start(
+            "127.0.0.1:0",
+            vec![fanout_b.local_addr().to_string()],
+            b"not the secret of the other instance",
+            tx_a,
+        )
+        .await
+        .expect("couldn't start fanout a");
+
+        fanout_a.publish(PlaneEvent::Voted {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            vote: None,
+        });
+
+        let event = tokio::time::timeout(Duration::from_secs(1), rx_b.recv()).await;
+        assert!(
+            event.is_err(),
+            "events with an invalid signature are dropped"
+        );
+    }
+}
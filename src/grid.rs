@@ -0,0 +1,158 @@
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+// size of a grid cell in degrees, roughly 5.5 km north-south
+const CELL_SIZE_DEGREES: f64 = 0.05;
+// smallest radius of curvature of the WGS84 ellipsoid (meridional, at the equator) in meters,
+// using it for the bounding box makes the box slightly too big rather than too small
+const MIN_EARTH_RADIUS_METERS: f64 = 6_335_439.0;
+// extra room for the difference between the sphere used here and the geodesic distance
+const BOUNDING_BOX_MARGIN: f64 = 1.01;
+
+type Cell = (i32, i32);
+
+// buckets message ids by the grid cell of their location, so lookups around a location only
+// have to visit the cells within the given radius instead of every message
+//
+// locations are (x, y) = (lon, lat) pairs, the same way `Trace::overlaps_with` reads them
+#[derive(Debug, Clone, Default)]
+pub struct Grid {
+    cells: HashMap<Cell, HashSet<Uuid>>,
+    // ids of messages with a location that can't be put into a cell, always returned as candidates
+    unindexed: HashSet<Uuid>,
+}
+
+impl Grid {
+    pub fn insert(&mut self, id: Uuid, location: (f64, f64)) {
+        match cell(location) {
+            Some(cell) => {
+                self.cells.entry(cell).or_default().insert(id);
+            }
+            None => {
+                self.unindexed.insert(id);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &Uuid, location: (f64, f64)) {
+        let Some(cell) = cell(location) else {
+            self.unindexed.remove(id);
+            return;
+        };
+        if let Some(ids) = self.cells.get_mut(&cell) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    // ids of all messages that may be within `radius_meters` of `location`,
+    // returns `None` if the area is too big to be worth it and everything has to be checked
+    pub fn candidates(&self, location: (f64, f64), radius_meters: f64) -> Option<Vec<Uuid>> {
+        let bounding_box = BoundingBox::around(location, radius_meters)?;
+
+        let lat_cells = cell_index(bounding_box.lat_min)..=cell_index(bounding_box.lat_max);
+        let lon_cell_min = cell_index(bounding_box.lon_min);
+        let lon_cell_max = cell_index(bounding_box.lon_max);
+        let lon_cell_count = lon_cell_count();
+        if lon_cell_max - lon_cell_min + 1 >= lon_cell_count {
+            return None;
+        }
+
+        let mut ids: Vec<Uuid> = self.unindexed.iter().copied().collect();
+        for lat_cell in lat_cells {
+            for lon_cell in lon_cell_min..=lon_cell_max {
+                // wrap around the antimeridian
+                let lon_cell = wrap_lon_cell(lon_cell, lon_cell_count);
+                if let Some(cell_ids) = self.cells.get(&(lon_cell, lat_cell)) {
+                    ids.extend(cell_ids.iter().copied());
+                }
+            }
+        }
+
+        Some(ids)
+    }
+}
+
+// area in degrees containing every point within a given distance of a location,
+// `lon_min` and `lon_max` may lie beyond ±180° if the area crosses the antimeridian
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundingBox {
+    pub lon_min: f64,
+    pub lon_max: f64,
+    pub lat_min: f64,
+    pub lat_max: f64,
+}
+
+impl BoundingBox {
+    // returns `None` if the area includes a pole or spans all longitudes
+    pub fn around(location: (f64, f64), radius_meters: f64) -> Option<Self> {
+        let (lon, lat) = location;
+        if !is_valid(location) || !radius_meters.is_finite() {
+            return None;
+        }
+        let radius_meters = radius_meters.max(0.0);
+
+        // bounding coordinates on a sphere, see http://janmatuschek.de/LatitudeLongitudeBoundingCoordinates
+        let angular_radius = radius_meters / MIN_EARTH_RADIUS_METERS * BOUNDING_BOX_MARGIN;
+        let lat_min = lat.to_radians() - angular_radius;
+        let lat_max = lat.to_radians() + angular_radius;
+        if lat_min <= -std::f64::consts::FRAC_PI_2 || lat_max >= std::f64::consts::FRAC_PI_2 {
+            return None;
+        }
+        let lon_delta_sin = angular_radius.sin() / lat.to_radians().cos();
+        if angular_radius >= std::f64::consts::FRAC_PI_2 || lon_delta_sin >= 1.0 {
+            return None;
+        }
+        let lon_delta = lon_delta_sin.asin().to_degrees();
+
+        Some(Self {
+            lon_min: lon - lon_delta,
+            lon_max: lon + lon_delta,
+            lat_min: lat_min.to_degrees(),
+            lat_max: lat_max.to_degrees(),
+        })
+    }
+
+    // longitude ranges within ±180°, two of them if the area crosses the antimeridian
+    pub fn lon_ranges(&self) -> Vec<(f64, f64)> {
+        if self.lon_min < -180.0 {
+            vec![(self.lon_min + 360.0, 180.0), (-180.0, self.lon_max)]
+        } else if self.lon_max > 180.0 {
+            vec![(self.lon_min, 180.0), (-180.0, self.lon_max - 360.0)]
+        } else {
+            vec![(self.lon_min, self.lon_max)]
+        }
+    }
+}
+
+fn is_valid((lon, lat): (f64, f64)) -> bool {
+    (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat)
+}
+
+fn cell(location: (f64, f64)) -> Option<Cell> {
+    if !is_valid(location) {
+        return None;
+    }
+    let (lon, lat) = location;
+
+    Some((
+        wrap_lon_cell(cell_index(lon), lon_cell_count()),
+        cell_index(lat),
+    ))
+}
+
+fn cell_index(degrees: f64) -> i32 {
+    (degrees / CELL_SIZE_DEGREES).floor() as i32
+}
+
+fn lon_cell_count() -> i32 {
+    (360.0 / CELL_SIZE_DEGREES).round() as i32
+}
+
+fn wrap_lon_cell(lon_cell: i32, lon_cell_count: i32) -> i32 {
+    // cells are counted from -180°, so shift by half a turn before wrapping and back afterwards
+    let half = lon_cell_count / 2;
+    (lon_cell + half).rem_euclid(lon_cell_count) - half
+}
@@ -0,0 +1,213 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::state::CONFIG;
+    use axum::{
+        http::{header, HeaderMap, HeaderValue, Request},
+        middleware::Next,
+        response::Response,
+    };
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use cookie::{Cookie, CookieJar, Key, SameSite};
+    use uuid::Uuid;
+
+    const USER_COOKIE: &str = "user";
+
+    lazy_static::lazy_static! {
+        static ref COOKIE_KEYS: CookieKeys = CookieKeys::from_config();
+    }
+
+    // finds out who sent the request and makes the user id available to handlers as request
+    // extension, sets a new cookie if the user had none or it has to be signed again
+    pub async fn identify_user<B>(mut request: Request<B>, next: Next<B>) -> Response {
+        let (user_id, cookie) = COOKIE_KEYS.identify(request.headers(), Utc::now());
+        request.extensions_mut().insert(user_id);
+
+        let mut response = next.run(request).await;
+        if let Some(cookie) = cookie {
+            match HeaderValue::from_str(&cookie.to_string()) {
+                Ok(value) => {
+                    response.headers_mut().append(header::SET_COOKIE, value);
+                }
+                Err(err) => log::error!("couldn't set user cookie, error: {}", err),
+            }
+        }
+
+        response
+    }
+
+    // keys to sign user cookies, the first one signs new cookies, the others are only accepted,
+    // so keys can be rotated without logging out everyone
+    pub struct CookieKeys {
+        keys: Vec<Key>,
+    }
+
+    impl CookieKeys {
+        pub fn new(keys: Vec<Key>) -> Self {
+            assert!(!keys.is_empty(), "at least one cookie key is required");
+
+            Self { keys }
+        }
+
+        fn from_config() -> Self {
+            let keys: Vec<Key> = CONFIG
+                .cookie_keys
+                .0
+                .split(',')
+                .map(|key| key.trim())
+                .filter(|key| !key.is_empty())
+                .filter_map(|key| match Key::try_from(key.as_bytes()) {
+                    Ok(key) => Some(key),
+                    Err(err) => {
+                        log::error!("couldn't use cookie key, error: {}", err);
+                        None
+                    }
+                })
+                .collect();
+
+            if keys.is_empty() {
+                log::warn!("no cookie keys configured, users get a new identity after a restart");
+                return Self::new(vec![Key::generate()]);
+            }
+
+            Self::new(keys)
+        }
+
+        // the user id from a valid cookie, or a new one, with the cookie to set if any
+        pub fn identify(
+            &self,
+            headers: &HeaderMap,
+            now: DateTime<Utc>,
+        ) -> (Uuid, Option<Cookie<'static>>) {
+            let Some(cookie) = user_cookie(headers) else {
+                let user_id = Uuid::new_v4();
+                return (user_id, Some(self.sign(user_id, now)));
+            };
+
+            let Some((index, user_id, issued_at)) = self.verify(cookie) else {
+                log::warn!("user cookie is not signed with any cookie key, issuing a new one");
+                let user_id = Uuid::new_v4();
+                return (user_id, Some(self.sign(user_id, now)));
+            };
+
+            let max_age = Duration::hours(CONFIG.user_cookie_max_age_hours);
+            if now - issued_at > max_age {
+                log::info!("user cookie of {} expired, issuing a new one", user_id);
+                let user_id = Uuid::new_v4();
+                return (user_id, Some(self.sign(user_id, now)));
+            }
+
+            // signed with an old key or about to expire
+            if index > 0 || now - issued_at > max_age / 2 {
+                return (user_id, Some(self.sign(user_id, now)));
+            }
+
+            (user_id, None)
+        }
+
+        // cookie with the user id and when it was issued, so it expires even if the client keeps it
+        fn sign(&self, user_id: Uuid, now: DateTime<Utc>) -> Cookie<'static> {
+            let cookie = Cookie::build((
+                USER_COOKIE,
+                format!("{}.{}", user_id, now.timestamp()),
+            ))
+            .path("/")
+            .max_age(time::Duration::hours(CONFIG.user_cookie_max_age_hours))
+            .http_only(true)
+            .secure(CONFIG.secure_cookies)
+            .same_site(SameSite::Lax)
+            .build();
+
+            let mut jar = CookieJar::new();
+            jar.signed_mut(&self.keys[0]).add(cookie);
+            jar.get(USER_COOKIE)
+                .cloned()
+                .expect("couldn't get signed user cookie")
+        }
+
+        // index of the key the cookie was signed with, user id and when it was issued
+        fn verify(&self, cookie: Cookie<'static>) -> Option<(usize, Uuid, DateTime<Utc>)> {
+            let jar = CookieJar::new();
+            let (index, cookie) = self
+                .keys
+                .iter()
+                .enumerate()
+                .find_map(|(index, key)| {
+                    Some((index, jar.signed(key).verify(cookie.clone())?))
+                })?;
+
+            let (user_id, issued_at) = cookie.value().split_once('.')?;
+            let user_id = Uuid::parse_str(user_id).ok()?;
+            let issued_at = Utc.timestamp_opt(issued_at.parse().ok()?, 0).single()?;
+
+            Some((index, user_id, issued_at))
+        }
+    }
+
+    fn user_cookie(headers: &HeaderMap) -> Option<Cookie<'static>> {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(Cookie::split_parse)
+            .filter_map(|cookie| cookie.ok())
+            .find(|cookie| cookie.name() == USER_COOKIE)
+            .map(|cookie| cookie.into_owned())
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    fn headers(cookie: &Cookie) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("theme=dark; {}", cookie.stripped()))
+                .expect("couldn't create cookie header"),
+        );
+
+        headers
+    }
+
+    #[test]
+    fn identify() {
+        let (old_key, new_key) = (Key::generate(), Key::generate());
+        let old_keys = CookieKeys::new(vec![old_key.clone()]);
+        let keys = CookieKeys::new(vec![new_key, old_key]);
+        let now = Utc::now();
+
+        let (user_id, cookie) = keys.identify(&HeaderMap::new(), now);
+        let cookie = cookie.expect("no cookie for a new user");
+        assert!(cookie.http_only().unwrap_or(false));
+        assert_eq!(cookie.secure(), Some(CONFIG.secure_cookies));
+        assert_eq!(keys.identify(&headers(&cookie), now), (user_id, None));
+
+        let mut tampered = cookie.clone();
+        tampered.set_value(cookie.value().replacen(
+            &user_id.to_string(),
+            &Uuid::new_v4().to_string(),
+            1,
+        ));
+        let (tampered_user_id, new_cookie) = keys.identify(&headers(&tampered), now);
+        assert_ne!(tampered_user_id, user_id);
+        assert!(new_cookie.is_some(), "cookie for the new identity");
+
+        let (_, old_cookie) = old_keys.identify(&HeaderMap::new(), now);
+        let old_cookie = old_cookie.expect("no cookie for a new user");
+        let (old_user_id, _) = old_keys.identify(&headers(&old_cookie), now);
+        let (rotated_user_id, rotated_cookie) = keys.identify(&headers(&old_cookie), now);
+        assert_eq!(rotated_user_id, old_user_id);
+        let rotated_cookie = rotated_cookie.expect("no cookie signed with the new key");
+        assert_eq!(
+            keys.identify(&headers(&rotated_cookie), now),
+            (old_user_id, None)
+        );
+
+        let later = now + Duration::hours(CONFIG.user_cookie_max_age_hours) + Duration::minutes(1);
+        let (expired_user_id, expired_cookie) = keys.identify(&headers(&cookie), later);
+        assert_ne!(expired_user_id, user_id);
+        assert!(expired_cookie.is_some());
+    }
+}
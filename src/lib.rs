@@ -1,10 +1,22 @@
 use cfg_if::cfg_if;
 
+pub mod actor;
 pub mod api;
 pub mod app;
+pub mod conversation;
+pub mod crypto;
 pub mod error_template;
+pub mod fanout;
 pub mod fileserv;
+pub mod grid;
+pub mod identity;
+pub mod limit;
+pub mod metrics;
+pub mod moderation;
+pub mod room;
 pub mod state;
+pub mod store;
+pub mod ws;
 
 cfg_if! {
     if #[cfg(feature = "hydrate")] {
@@ -0,0 +1,368 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::api::SendMessageError;
+    use crate::state::CONFIG;
+    use axum::http::HeaderMap;
+    use std::collections::{HashMap, VecDeque};
+    use std::net::{IpAddr, SocketAddr};
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    // min. similarity of two normalized messages from the same author to count as duplicates,
+    // 1.0 means equal
+    const DUPLICATE_SIMILARITY: f64 = 0.85;
+    // max. amount of recent messages per author to compare new messages with
+    const MAX_RECENT_MESSAGES: usize = 10;
+    // seconds between removing buckets and messages that don't matter anymore
+    const PRUNE_INTERVAL_SECONDS: u64 = 60;
+
+    // ip address of the client, taken from the header set in the config if there is one,
+    // e.g. `x-forwarded-for` behind a proxy, otherwise the address of the connection
+    pub fn client_ip(headers: &HeaderMap, addr: SocketAddr) -> IpAddr {
+        if CONFIG.client_ip_header.is_empty() {
+            return addr.ip();
+        }
+
+        headers
+            .get(CONFIG.client_ip_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or_else(|| addr.ip())
+    }
+
+    #[derive(Debug, Clone)]
+    struct TokenBucket {
+        tokens: f64,
+        updated_at: Instant,
+    }
+
+    impl TokenBucket {
+        fn full(burst: f64, now: Instant) -> Self {
+            Self {
+                tokens: burst,
+                updated_at: now,
+            }
+        }
+
+        fn refill(&mut self, per_minute: f64, burst: f64, now: Instant) {
+            let seconds = now.saturating_duration_since(self.updated_at).as_secs_f64();
+            self.tokens = (self.tokens + seconds * per_minute / 60.0).min(burst);
+            self.updated_at = now;
+        }
+
+        // time until the next token is available, zero if there is one
+        fn wait(&self, per_minute: f64) -> Duration {
+            if self.tokens >= 1.0 {
+                return Duration::ZERO;
+            }
+            if per_minute <= 0.0 {
+                return Duration::MAX;
+            }
+            let seconds = (1.0 - self.tokens) * 60.0 / per_minute;
+            Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+        }
+    }
+
+    // limits how many messages a user and an ip address may send and rejects messages an author
+    // has sent shortly before, also with small changes
+    #[derive(Debug)]
+    pub struct Limiter {
+        users: HashMap<Uuid, TokenBucket>,
+        ips: HashMap<IpAddr, TokenBucket>,
+        // normalized texts of the latest messages of each author, newest last
+        recent_messages: HashMap<Uuid, VecDeque<(Instant, String)>>,
+        pruned_at: Instant,
+    }
+
+    impl Default for Limiter {
+        fn default() -> Self {
+            Self {
+                users: HashMap::new(),
+                ips: HashMap::new(),
+                recent_messages: HashMap::new(),
+                pruned_at: Instant::now(),
+            }
+        }
+    }
+
+    impl Limiter {
+        // takes a token from the user's and the ip's bucket if the message may be sent
+        pub fn check(&mut self, user_id: Uuid, ip: Option<IpAddr>, text: &str) -> Result<(), SendMessageError> {
+            self.check_at(user_id, ip, text, Instant::now())
+        }
+
+        // same as `check`, but without looking for duplicates, e.g. for encrypted messages
+        pub fn check_rate(&mut self, user_id: Uuid, ip: Option<IpAddr>) -> Result<(), SendMessageError> {
+            let now = Instant::now();
+            if now.saturating_duration_since(self.pruned_at) > Duration::from_secs(PRUNE_INTERVAL_SECONDS) {
+                self.prune(now);
+            }
+
+            self.take_tokens(user_id, ip, now)
+        }
+
+        // same as `check` for the new text of an edited message, which may be similar to its
+        // previous text, but not to the other recent messages of the author
+        pub fn check_edit(
+            &mut self,
+            user_id: Uuid,
+            ip: Option<IpAddr>,
+            previous: &str,
+            text: &str,
+        ) -> Result<(), SendMessageError> {
+            self.check_text_at(user_id, ip, text, Some(previous), Instant::now())
+        }
+
+        fn check_at(
+            &mut self,
+            user_id: Uuid,
+            ip: Option<IpAddr>,
+            text: &str,
+            now: Instant,
+        ) -> Result<(), SendMessageError> {
+            self.check_text_at(user_id, ip, text, None, now)
+        }
+
+        fn check_text_at(
+            &mut self,
+            user_id: Uuid,
+            ip: Option<IpAddr>,
+            text: &str,
+            previous: Option<&str>,
+            now: Instant,
+        ) -> Result<(), SendMessageError> {
+            if now.saturating_duration_since(self.pruned_at) > Duration::from_secs(PRUNE_INTERVAL_SECONDS) {
+                self.prune(now);
+            }
+
+            let normalized = normalize(text);
+            let previous = previous.map(normalize);
+            let window = Duration::from_secs(CONFIG.duplicate_message_window_seconds);
+            if let Some(recent_messages) = self.recent_messages.get(&user_id) {
+                let is_duplicate = recent_messages.iter().any(|(sent_at, recent)| {
+                    now.saturating_duration_since(*sent_at) < window
+                        && previous
+                            .as_ref()
+                            .is_none_or(|previous| similarity(recent, previous) < DUPLICATE_SIMILARITY)
+                        && similarity(recent, &normalized) >= DUPLICATE_SIMILARITY
+                });
+                if is_duplicate {
+                    return Err(SendMessageError::Duplicate);
+                }
+            }
+
+            self.take_tokens(user_id, ip, now)?;
+
+            let recent_messages = self.recent_messages.entry(user_id).or_default();
+            recent_messages.push_back((now, normalized));
+            if recent_messages.len() > MAX_RECENT_MESSAGES {
+                recent_messages.pop_front();
+            }
+
+            Ok(())
+        }
+
+        // takes a token from the user's and the ip's bucket, only if both have one
+        fn take_tokens(&mut self, user_id: Uuid, ip: Option<IpAddr>, now: Instant) -> Result<(), SendMessageError> {
+            let user_bucket = self
+                .users
+                .entry(user_id)
+                .or_insert_with(|| TokenBucket::full(CONFIG.user_message_burst, now));
+            user_bucket.refill(CONFIG.user_messages_per_minute, CONFIG.user_message_burst, now);
+            let mut wait = user_bucket.wait(CONFIG.user_messages_per_minute);
+
+            if let Some(ip) = ip {
+                let ip_bucket = self
+                    .ips
+                    .entry(ip)
+                    .or_insert_with(|| TokenBucket::full(CONFIG.ip_message_burst, now));
+                ip_bucket.refill(CONFIG.ip_messages_per_minute, CONFIG.ip_message_burst, now);
+                wait = wait.max(ip_bucket.wait(CONFIG.ip_messages_per_minute));
+            }
+
+            if !wait.is_zero() {
+                return Err(SendMessageError::RateLimited {
+                    retry_after_seconds: wait.as_secs_f64().ceil().min(u32::MAX as f64) as u32,
+                });
+            }
+
+            // only take tokens if both buckets have one
+            if let Some(bucket) = self.users.get_mut(&user_id) {
+                bucket.tokens -= 1.0;
+            }
+            if let Some(bucket) = ip.and_then(|ip| self.ips.get_mut(&ip)) {
+                bucket.tokens -= 1.0;
+            }
+
+            Ok(())
+        }
+
+        // removes full buckets and messages outside of the duplicate window
+        fn prune(&mut self, now: Instant) {
+            let window = Duration::from_secs(CONFIG.duplicate_message_window_seconds);
+            self.recent_messages.retain(|_, recent_messages| {
+                recent_messages.retain(|(sent_at, _)| now.saturating_duration_since(*sent_at) < window);
+                !recent_messages.is_empty()
+            });
+            self.users.retain(|_, bucket| {
+                bucket.refill(CONFIG.user_messages_per_minute, CONFIG.user_message_burst, now);
+                bucket.tokens < CONFIG.user_message_burst
+            });
+            self.ips.retain(|_, bucket| {
+                bucket.refill(CONFIG.ip_messages_per_minute, CONFIG.ip_message_burst, now);
+                bucket.tokens < CONFIG.ip_message_burst
+            });
+            self.pruned_at = now;
+        }
+    }
+
+    // lowercase words of letters and digits, so changed case, punctuation and spacing don't count,
+    // texts without any letters or digits, e.g. only emojis, are kept as they are
+    fn normalize(text: &str) -> String {
+        let text = text.trim().to_lowercase();
+        let normalized = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        if normalized.is_empty() {
+            text
+        } else {
+            normalized
+        }
+    }
+
+    // 1.0 for equal texts, 0.0 for completely different ones, based on the levenshtein distance
+    fn similarity(a: &str, b: &str) -> f64 {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        let max_len = a.len().max(b.len());
+        if max_len == 0 {
+            return 1.0;
+        }
+
+        let mut previous: Vec<usize> = (0..=b.len()).collect();
+        let mut current = vec![0; b.len() + 1];
+        for (i, char_a) in a.iter().enumerate() {
+            current[0] = i + 1;
+            for (j, char_b) in b.iter().enumerate() {
+                let substitution = previous[j] + usize::from(char_a != char_b);
+                current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+
+        1.0 - previous[b.len()] as f64 / max_len as f64
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit() {
+        let mut limiter = Limiter::default();
+        let now = Instant::now();
+        let user_id = Uuid::new_v4();
+        let ip: IpAddr = "127.0.0.1".parse().expect("couldn't parse ip");
+
+        let texts = [
+            "hello",
+            "where are you going?",
+            "nice weather today",
+            "see you",
+        ];
+        for text in texts.iter().take(CONFIG.user_message_burst as usize) {
+            assert_eq!(limiter.check_at(user_id, Some(ip), text, now), Ok(()));
+        }
+        assert!(matches!(
+            limiter.check_at(user_id, Some(ip), "one more", now),
+            Err(SendMessageError::RateLimited { .. })
+        ));
+
+        let later = now + Duration::from_secs_f64(60.0 / CONFIG.user_messages_per_minute + 0.1);
+        assert_eq!(
+            limiter.check_at(user_id, Some(ip), "one more", later),
+            Ok(())
+        );
+
+        // many users behind the same ip
+        let other_ip: IpAddr = "127.0.0.2".parse().expect("couldn't parse ip");
+        for _ in 0..CONFIG.ip_message_burst as usize {
+            assert_eq!(
+                limiter.check_at(Uuid::new_v4(), Some(other_ip), "hi", now),
+                Ok(())
+            );
+        }
+        assert!(matches!(
+            limiter.check_at(Uuid::new_v4(), Some(other_ip), "hi", now),
+            Err(SendMessageError::RateLimited { .. })
+        ));
+        assert_eq!(
+            limiter.check_at(Uuid::new_v4(), None, "hi", now),
+            Ok(()),
+            "no ip"
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut limiter = Limiter::default();
+        let now = Instant::now();
+        let user_id = Uuid::new_v4();
+
+        let text = "Is anyone else going to the concert tonight?";
+        assert_eq!(limiter.check_at(user_id, None, text, now), Ok(()));
+        for near_duplicate in [
+            text,
+            "is anyone else going to the concert tonight",
+            "Is anyone else going to the concert tonight??!",
+            "Is anyone else goin to the concert tonite?",
+        ] {
+            assert_eq!(
+                limiter.check_at(user_id, None, near_duplicate, now),
+                Err(SendMessageError::Duplicate),
+                "{}",
+                near_duplicate
+            );
+        }
+        assert_eq!(
+            limiter.check_at(Uuid::new_v4(), None, text, now),
+            Ok(()),
+            "other author"
+        );
+
+        let later = now + Duration::from_secs(CONFIG.duplicate_message_window_seconds);
+        assert_eq!(
+            limiter.check_at(user_id, None, text, later),
+            Ok(()),
+            "after the window"
+        );
+    }
+
+    #[test]
+    fn edits() {
+        let mut limiter = Limiter::default();
+        let now = Instant::now();
+        let user_id = Uuid::new_v4();
+
+        let (text, other) = ("we are stuck befor hamburg", "the bistro is closed");
+        let fixed = "we are stuck before hamburg";
+        assert_eq!(limiter.check_at(user_id, None, text, now), Ok(()));
+        assert_eq!(limiter.check_at(user_id, None, other, now), Ok(()));
+        assert_eq!(
+            limiter.check_text_at(user_id, None, fixed, Some(text), now),
+            Ok(()),
+            "typo fixed"
+        );
+        assert_eq!(
+            limiter.check_text_at(user_id, None, other, Some(text), now),
+            Err(SendMessageError::Duplicate),
+            "same as another message"
+        );
+    }
+}
@@ -15,21 +15,31 @@ pub fn main() {
 async fn main() {
     use axum::{
         body::Body as AxumBody,
-        extract::{Extension, Path, RawQuery, State},
+        extract::{ConnectInfo, Extension, Path, RawQuery, State},
         http::{header::HeaderMap, Request},
+        middleware,
         response::{IntoResponse, Response},
         routing::get,
         Router,
     };
+    use catenary::actor::PlaneHandle;
     use catenary::app::App;
+    use catenary::fanout;
     use catenary::fileserv::file_and_error_handler;
+    use catenary::identity::identify_user;
+    use catenary::limit::{client_ip, Limiter};
+    use catenary::metrics::{metrics_handler, METRICS};
+    use catenary::moderation;
     use catenary::state::{AppState, Plane};
-    use catenary::state::{ChatMessage, ChatMessageIn};
+    use catenary::state::{ChatMessage, ChatMessageIn, PlaneEvent};
+    use catenary::ws::ws_handler;
     use env_logger::Builder;
     use leptos::{get_configuration, provide_context, view};
     use leptos_axum::LeptosRoutes;
     use leptos_axum::{generate_route_list, handle_server_fns_with_context};
+    use std::net::SocketAddr;
     use std::sync::{Arc, Mutex};
+    use tokio::sync::broadcast;
     use tokio::sync::mpsc::{channel, Receiver, Sender};
     use uuid::Uuid;
 
@@ -46,27 +56,19 @@ async fn main() {
     #[axum::debug_handler]
     async fn server_fn_handler(
         State(app_state): State<AppState>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
         path: Path<String>,
         headers: HeaderMap,
         raw_query: RawQuery,
         request: Request<AxumBody>,
     ) -> impl IntoResponse {
-        // TODO: this is a terrible hack and there must be a better way
-
-        let user_re = regex::Regex::new(
-            r#"(user=)([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})"#,
-        )
-        .expect("couldn't compile user regex");
-        let cookie = request.headers().get("cookie");
-        let user_id_str = cookie
-            .and_then(|cookie| {
-                user_re
-                    .captures(cookie.to_str().unwrap())
-                    .and_then(|captures| captures.get(2))
-            })
-            .map(|uuid| uuid.as_str().to_string());
-        let user_uuid =
-            Uuid::parse_str(&user_id_str.unwrap_or_default()).unwrap_or_else(|_| Uuid::new_v4());
+        // set by the identity middleware
+        let user_uuid = request
+            .extensions()
+            .get::<Uuid>()
+            .copied()
+            .unwrap_or_else(Uuid::new_v4);
+        let ip = client_ip(request.headers(), addr);
 
         handle_server_fns_with_context(
             path,
@@ -74,8 +76,14 @@ async fn main() {
             raw_query,
             move || {
                 provide_context(app_state.chat_msg_in_tx.clone());
+                provide_context(app_state.chat_msg_out_tx.clone());
+                provide_context(app_state.direct_msg_out_tx.clone());
                 provide_context(app_state.plane.clone());
+                provide_context(app_state.fanout.clone());
+                provide_context(app_state.limiter.clone());
+                provide_context(app_state.moderator.clone());
                 provide_context(user_uuid);
+                provide_context(ip);
             },
             request,
         )
@@ -111,12 +119,24 @@ async fn main() {
     let messages_clone = messages.clone();
     let (chat_msg_in_tx, mut chat_msg_in_rx): (Sender<ChatMessageIn>, Receiver<ChatMessageIn>) =
         channel(1000);
+    let (chat_msg_out_tx, _) = broadcast::channel::<ChatMessage>(1000);
+    let (direct_msg_out_tx, _) = broadcast::channel::<(Uuid, Uuid)>(1000);
+    let (plane_event_tx, mut plane_event_rx): (Sender<PlaneEvent>, Receiver<PlaneEvent>) =
+        channel(1000);
 
-    let plane = Arc::new(Mutex::new(Plane::new()));
+    let plane = PlaneHandle::spawn(Plane::from_config().expect("couldn't create message store"));
+    let fanout = fanout::from_config(plane_event_tx)
+        .await
+        .expect("couldn't start fanout");
     let state = AppState {
         leptos_options: leptos_options,
         chat_msg_in_tx,
+        chat_msg_out_tx: chat_msg_out_tx.clone(),
+        direct_msg_out_tx: direct_msg_out_tx.clone(),
         plane: plane.clone(),
+        fanout: fanout.clone(),
+        limiter: Arc::new(Mutex::new(Limiter::default())),
+        moderator: Arc::from(moderation::from_config()),
     };
 
     // compose axum router
@@ -126,11 +146,51 @@ async fn main() {
             "/api/*fn_name",
             get(server_fn_handler).post(server_fn_handler),
         )
+        .route("/ws", get(ws_handler))
+        .route("/metrics", get(metrics_handler))
         .leptos_routes_with_handler(routes, get(leptos_routes_handler))
         .layer(Extension(messages_clone))
+        .layer(middleware::from_fn(identify_user))
         .fallback(file_and_error_handler)
         .with_state(state);
 
+    // apply messages and votes from other instances
+
+    let peer_plane = plane.clone();
+    let peer_chat_msg_out_tx = chat_msg_out_tx.clone();
+    tokio::spawn(async move {
+        while let Some(event) = plane_event_rx.recv().await {
+            // direct messages are only pushed to the users of their conversation
+            if let PlaneEvent::DirectMessageAdded { id, msg } = event {
+                match peer_plane.receive_direct_message(id, *msg).await {
+                    Ok(Some(user_ids)) => {
+                        // no receivers just means no websocket is connected right now
+                        let _ = direct_msg_out_tx.send(user_ids);
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        log::error!(
+                            "couldn't add direct message from other instance, error: {}",
+                            err
+                        );
+                    }
+                }
+                continue;
+            }
+            let msg = match peer_plane.apply_event(event).await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    log::error!("couldn't apply event from other instance, error: {}", err);
+                    continue;
+                }
+            };
+            if let Some(msg) = msg {
+                // no receivers just means no websocket is connected right now
+                let _ = peer_chat_msg_out_tx.send(msg);
+            }
+        }
+    });
+
     // start message listener
 
     tokio::spawn(async move {
@@ -140,11 +200,18 @@ async fn main() {
                 log::warn!("couldn't receive message via chat_msg_in_rx");
                 continue;
             };
-            let Ok(mut plane) = plane.try_lock() else {
-                log::warn!("couldn't lock plane mutex in message listener");
-                continue;
+            // moderated in the send handler already
+            let msg = match plane.add_message(msg).await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    log::error!("couldn't add message, error: {}", err);
+                    METRICS.messages_dropped.inc("plane_stopped");
+                    continue;
+                }
             };
-            plane.add_message(msg);
+            fanout.publish(PlaneEvent::MessageAdded(Box::new(msg.clone())));
+            // no receivers just means no websocket is connected right now
+            let _ = chat_msg_out_tx.send(msg);
         }
     });
 
@@ -152,7 +219,7 @@ async fn main() {
 
     log::info!("listening on http://{}", &addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
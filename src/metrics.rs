@@ -0,0 +1,323 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::state::AppState;
+    use axum::{
+        extract::State,
+        http::header,
+        response::IntoResponse,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    // upper bounds in seconds of the latency histograms
+    const LATENCY_BUCKETS: &[f64] = &[
+        0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    ];
+
+    lazy_static::lazy_static! {
+        pub static ref METRICS: Metrics = Metrics::default();
+    }
+
+    // serves all metrics in the prometheus text format, gauges are read from the plane on each scrape
+    pub async fn metrics_handler(State(app_state): State<AppState>) -> impl IntoResponse {
+        let stats = match app_state.plane.stats().await {
+            Ok(stats) => Some(stats),
+            Err(err) => {
+                log::error!("couldn't get plane stats in metrics handler, error: {}", err);
+                None
+            }
+        };
+        let mut gauges = match stats {
+            Some(stats) => vec![
+                ("catenary_messages", "Messages stored.", stats.messages as f64),
+                ("catenary_authors", "Distinct authors of the stored messages.", stats.authors as f64),
+                ("catenary_users", "Users who sent a trace in the last minutes.", stats.users as f64),
+                ("catenary_rooms", "Rooms of users travelling together.", stats.rooms as f64),
+                ("catenary_shared_rooms", "Rooms with more than one user.", stats.shared_rooms as f64),
+                ("catenary_reports", "Reported messages waiting for review.", stats.reports as f64),
+                (
+                    "catenary_estimated_memory_bytes",
+                    "Estimated memory used by messages, traces, reports and reputations.",
+                    stats.estimated_memory_bytes as f64,
+                ),
+            ],
+            None => vec![],
+        };
+        gauges.push((
+            "catenary_plane_queue_length",
+            "Commands waiting for the plane.",
+            app_state.plane.queue_len() as f64,
+        ));
+
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            METRICS.render(&gauges),
+        )
+    }
+
+    // everything exposed on `/metrics`, besides the gauges read from the plane
+    pub struct Metrics {
+        pub server_fn_calls: Counter,
+        pub server_fn_seconds: Histogram,
+        pub get_messages_seconds: Histogram,
+        // by where the lock failed
+        pub lock_failures: Counter,
+        // by why the message was dropped
+        pub messages_dropped: Counter,
+        // why clients have no trace
+        pub client_trace_states: Counter,
+    }
+
+    impl Default for Metrics {
+        fn default() -> Self {
+            Self {
+                server_fn_calls: Counter::new(
+                    "catenary_server_fn_calls_total",
+                    "Calls of server functions.",
+                    Some("fn"),
+                ),
+                server_fn_seconds: Histogram::new(
+                    "catenary_server_fn_duration_seconds",
+                    "Time taken by server functions.",
+                    Some("fn"),
+                    LATENCY_BUCKETS,
+                ),
+                get_messages_seconds: Histogram::new(
+                    "catenary_get_messages_duration_seconds",
+                    "Time taken to find the messages matching a trace.",
+                    None,
+                    LATENCY_BUCKETS,
+                ),
+                lock_failures: Counter::new(
+                    "catenary_lock_failures_total",
+                    "Failed attempts to lock a mutex.",
+                    Some("place"),
+                ),
+                messages_dropped: Counter::new(
+                    "catenary_messages_dropped_total",
+                    "Messages that were not stored or not delivered.",
+                    Some("reason"),
+                ),
+                client_trace_states: Counter::new(
+                    "catenary_client_trace_states_total",
+                    "Reasons clients reported for not having a trace.",
+                    Some("state"),
+                ),
+            }
+        }
+    }
+
+    impl Metrics {
+        // counts a server function call and observes its duration when the timer is dropped
+        pub fn server_fn(&'static self, name: &'static str) -> Timer {
+            self.server_fn_calls.inc(name);
+
+            Timer {
+                histogram: &self.server_fn_seconds,
+                label_value: name,
+                started_at: Instant::now(),
+            }
+        }
+
+        // prometheus text format, with the given gauges as name, help and value
+        pub fn render(&self, gauges: &[(&str, &str, f64)]) -> String {
+            let mut out = String::new();
+            self.server_fn_calls.render(&mut out);
+            self.server_fn_seconds.render(&mut out);
+            self.get_messages_seconds.render(&mut out);
+            self.lock_failures.render(&mut out);
+            self.messages_dropped.render(&mut out);
+            self.client_trace_states.render(&mut out);
+            for (name, help, value) in gauges {
+                out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"));
+            }
+
+            out
+        }
+    }
+
+    pub struct Timer {
+        histogram: &'static Histogram,
+        label_value: &'static str,
+        started_at: Instant,
+    }
+
+    impl Drop for Timer {
+        fn drop(&mut self) {
+            self.histogram
+                .observe(self.label_value, self.started_at.elapsed().as_secs_f64());
+        }
+    }
+
+    // counts by label value, or a single count without a label
+    pub struct Counter {
+        name: &'static str,
+        help: &'static str,
+        label: Option<&'static str>,
+        values: Mutex<BTreeMap<String, u64>>,
+    }
+
+    impl Counter {
+        pub fn new(name: &'static str, help: &'static str, label: Option<&'static str>) -> Self {
+            Self {
+                name,
+                help,
+                label,
+                values: Mutex::new(BTreeMap::new()),
+            }
+        }
+
+        pub fn inc(&self, label_value: &str) {
+            self.inc_by(label_value, 1);
+        }
+
+        pub fn inc_by(&self, label_value: &str, n: u64) {
+            let Ok(mut values) = self.values.lock() else {
+                return;
+            };
+            *values.entry(label_value.to_string()).or_default() += n;
+        }
+
+        fn render(&self, out: &mut String) {
+            let Ok(values) = self.values.lock() else {
+                return;
+            };
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", self.name, self.help, self.name));
+            for (label_value, count) in values.iter() {
+                let labels = labels(self.label, label_value, None);
+                out.push_str(&format!("{}{} {}\n", self.name, labels, count));
+            }
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct HistogramValues {
+        // observations per bucket, not cumulative
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    }
+
+    // observations in buckets by label value, or a single histogram without a label
+    pub struct Histogram {
+        name: &'static str,
+        help: &'static str,
+        label: Option<&'static str>,
+        buckets: &'static [f64],
+        values: Mutex<BTreeMap<String, HistogramValues>>,
+    }
+
+    impl Histogram {
+        pub fn new(
+            name: &'static str,
+            help: &'static str,
+            label: Option<&'static str>,
+            buckets: &'static [f64],
+        ) -> Self {
+            Self {
+                name,
+                help,
+                label,
+                buckets,
+                values: Mutex::new(BTreeMap::new()),
+            }
+        }
+
+        pub fn observe(&self, label_value: &str, value: f64) {
+            let Ok(mut values) = self.values.lock() else {
+                return;
+            };
+            let values = values
+                .entry(label_value.to_string())
+                .or_insert_with(|| HistogramValues {
+                    counts: vec![0; self.buckets.len()],
+                    ..Default::default()
+                });
+            if let Some(index) = self.buckets.iter().position(|bound| value <= *bound) {
+                values.counts[index] += 1;
+            }
+            values.sum += value;
+            values.count += 1;
+        }
+
+        fn render(&self, out: &mut String) {
+            let Ok(values) = self.values.lock() else {
+                return;
+            };
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} histogram\n", self.name, self.help, self.name));
+            for (label_value, values) in values.iter() {
+                let mut cumulative_count = 0;
+                for (bound, count) in self.buckets.iter().zip(values.counts.iter()) {
+                    cumulative_count += count;
+                    let labels = labels(self.label, label_value, Some(&bound.to_string()));
+                    out.push_str(&format!("{}_bucket{} {}\n", self.name, labels, cumulative_count));
+                }
+                let labels_inf = labels(self.label, label_value, Some("+Inf"));
+                out.push_str(&format!("{}_bucket{} {}\n", self.name, labels_inf, values.count));
+                let labels = labels(self.label, label_value, None);
+                out.push_str(&format!("{}_sum{} {}\n", self.name, labels, values.sum));
+                out.push_str(&format!("{}_count{} {}\n", self.name, labels, values.count));
+            }
+        }
+    }
+
+    // e.g. `{fn="send_message",le="0.1"}`, empty without any labels
+    fn labels(label: Option<&str>, label_value: &str, le: Option<&str>) -> String {
+        let mut labels = vec![];
+        if let Some(label) = label {
+            let label_value = label_value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            labels.push(format!("{}=\"{}\"", label, label_value));
+        }
+        if let Some(le) = le {
+            labels.push(format!("le=\"{}\"", le));
+        }
+
+        if labels.is_empty() {
+            "".to_string()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let counter = Counter::new("test_total", "Test counter.", Some("kind"));
+        counter.inc("a");
+        counter.inc_by("b", 2);
+        counter.inc("a");
+        let histogram = Histogram::new("test_seconds", "Test histogram.", None, &[0.1, 1.0]);
+        for value in [0.05, 0.5, 0.7, 3.0] {
+            histogram.observe("", value);
+        }
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        histogram.render(&mut out);
+
+        assert_eq!(
+            out,
+            "# HELP test_total Test counter.
+# TYPE test_total counter
+test_total{kind=\"a\"} 2
+test_total{kind=\"b\"} 2
+# HELP test_seconds Test histogram.
+# TYPE test_seconds histogram
+test_seconds_bucket{le=\"0.1\"} 1
+test_seconds_bucket{le=\"1\"} 3
+test_seconds_bucket{le=\"+Inf\"} 4
+test_seconds_sum 4.25
+test_seconds_count 4
+"
+        );
+    }
+}
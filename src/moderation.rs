@@ -0,0 +1,301 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use crate::state::{ChatMessageIn, CONFIG};
+    use regex::Regex;
+    use uuid::Uuid;
+
+    // min. amount of letters in a message before checking for too many capital letters
+    const FLOOD_MIN_LETTERS: usize = 10;
+    // max. share of capital letters, above that, the message is lowercased
+    const FLOOD_MAX_CAPS_RATIO: f64 = 0.7;
+    // max. amount of emojis in a message
+    const FLOOD_MAX_EMOJIS: usize = 10;
+    // min. amount of digits in a row of digits, spaces and dashes to count as a phone number,
+    // so dates and times don't
+    const PHONE_MIN_DIGITS: usize = 9;
+
+    // what happens to a message after moderation
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Verdict {
+        Allow,
+        // the reason is only logged
+        Reject(String),
+        // the message is stored with this text instead
+        Mask(String),
+    }
+
+    // checks the text of incoming messages before they are added to the plane
+    pub trait Moderator: std::fmt::Debug + Send + Sync {
+        fn moderate(&self, text: &str) -> Verdict;
+    }
+
+    // all built-in moderators, with the blocklist from the config
+    pub fn from_config() -> Box<dyn Moderator> {
+        let blocklist: Vec<&str> = CONFIG
+            .moderation_blocklist
+            .split(',')
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        Box::new(Pipeline::new(vec![
+            Box::new(Blocklist::new(&blocklist)),
+            Box::new(ContactDetector::default()),
+            Box::new(FloodDetector),
+        ]))
+    }
+
+    // applies the moderated message text, `None` if the message was rejected
+    pub fn moderate_message(
+        moderator: &dyn Moderator,
+        mut msg: ChatMessageIn,
+    ) -> Option<ChatMessageIn> {
+        msg.text = moderate_text(moderator, msg.id, msg.author, msg.text)?;
+
+        Some(msg)
+    }
+
+    // moderated text of a new or edited message, `None` if it was rejected
+    pub fn moderate_text(
+        moderator: &dyn Moderator,
+        id: Uuid,
+        author: Uuid,
+        text: String,
+    ) -> Option<String> {
+        match moderator.moderate(&text) {
+            Verdict::Allow => Some(text),
+            Verdict::Mask(text) => {
+                log::info!("masked message {} of user {}", id, author);
+                Some(text)
+            }
+            Verdict::Reject(reason) => {
+                log::info!(
+                    "rejected message {} of user {}, reason: {}",
+                    id,
+                    author,
+                    reason
+                );
+                None
+            }
+        }
+    }
+
+    // runs moderators one after another, each one gets the text masked by the ones before,
+    // stops at the first rejection
+    #[derive(Debug)]
+    pub struct Pipeline {
+        moderators: Vec<Box<dyn Moderator>>,
+    }
+
+    impl Pipeline {
+        pub fn new(moderators: Vec<Box<dyn Moderator>>) -> Self {
+            Self { moderators }
+        }
+    }
+
+    impl Moderator for Pipeline {
+        fn moderate(&self, text: &str) -> Verdict {
+            let mut masked: Option<String> = None;
+            for moderator in &self.moderators {
+                match moderator.moderate(masked.as_deref().unwrap_or(text)) {
+                    Verdict::Allow => {}
+                    Verdict::Mask(text) => masked = Some(text),
+                    reject @ Verdict::Reject(_) => return reject,
+                }
+            }
+
+            match masked {
+                Some(text) => Verdict::Mask(text),
+                None => Verdict::Allow,
+            }
+        }
+    }
+
+    // replaces blocked words with asterisks, ignoring case
+    #[derive(Debug)]
+    pub struct Blocklist {
+        words: Option<Regex>,
+    }
+
+    impl Blocklist {
+        pub fn new(words: &[&str]) -> Self {
+            if words.is_empty() {
+                return Self { words: None };
+            }
+
+            let words = words.iter().map(|word| regex::escape(word)).collect::<Vec<String>>();
+            let words = Regex::new(&format!(r"(?i)\b({})\b", words.join("|")))
+                .map_err(|err| log::error!("couldn't compile blocklist regex, error: {}", err))
+                .ok();
+
+            Self { words }
+        }
+    }
+
+    impl Moderator for Blocklist {
+        fn moderate(&self, text: &str) -> Verdict {
+            let Some(words) = &self.words else {
+                return Verdict::Allow;
+            };
+            if !words.is_match(text) {
+                return Verdict::Allow;
+            }
+
+            let masked = words.replace_all(text, |captures: &regex::Captures| {
+                "*".repeat(captures[0].chars().count())
+            });
+            Verdict::Mask(masked.into_owned())
+        }
+    }
+
+    // masks email addresses and phone numbers and rejects links,
+    // see the "Respect Privacy" and "No Advertising" rules
+    #[derive(Debug)]
+    pub struct ContactDetector {
+        email: Regex,
+        phone: Regex,
+        url: Regex,
+    }
+
+    impl Default for ContactDetector {
+        fn default() -> Self {
+            Self {
+                email: Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b")
+                    .expect("couldn't compile email regex"),
+                phone: Regex::new(r"\+?\(?\d[\d \-/()]*\d").expect("couldn't compile phone regex"),
+                url: Regex::new(
+                    r"(?i)\b(https?://|www\.)\S+|\b[a-z0-9-]+(\.[a-z0-9-]+)*\.(com|net|org|info|biz|io|co|me|app|shop|xyz|de|eu|uk)\b",
+                )
+                .expect("couldn't compile url regex"),
+            }
+        }
+    }
+
+    impl Moderator for ContactDetector {
+        fn moderate(&self, text: &str) -> Verdict {
+            let masked = self.email.replace_all(text, "***");
+            let masked = self.phone.replace_all(&masked, |captures: &regex::Captures| {
+                let digits = captures[0].chars().filter(|c| c.is_ascii_digit()).count();
+                if digits >= PHONE_MIN_DIGITS {
+                    "***".to_string()
+                } else {
+                    captures[0].to_string()
+                }
+            });
+
+            if self.url.is_match(&masked) {
+                return Verdict::Reject("link".to_string());
+            }
+
+            if masked == text {
+                Verdict::Allow
+            } else {
+                Verdict::Mask(masked.into_owned())
+            }
+        }
+    }
+
+    // lowercases messages with too many capital letters and rejects messages with too many emojis
+    #[derive(Debug)]
+    pub struct FloodDetector;
+
+    impl Moderator for FloodDetector {
+        fn moderate(&self, text: &str) -> Verdict {
+            let emojis = text.chars().filter(|c| is_emoji(*c)).count();
+            if emojis > FLOOD_MAX_EMOJIS {
+                return Verdict::Reject(format!("{} emojis", emojis));
+            }
+
+            let letters = text.chars().filter(|c| c.is_alphabetic()).count();
+            let caps = text.chars().filter(|c| c.is_uppercase()).count();
+            if letters >= FLOOD_MIN_LETTERS && caps as f64 / letters as f64 > FLOOD_MAX_CAPS_RATIO {
+                return Verdict::Mask(text.to_lowercase());
+            }
+
+            Verdict::Allow
+        }
+    }
+
+    // pictographs, symbols and dingbats, without variation selectors and joiners
+    fn is_emoji(c: char) -> bool {
+        matches!(c as u32, 0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2B00..=0x2BFF)
+    }
+}}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocklist() {
+        let blocklist = Blocklist::new(&["darn", "heck"]);
+        assert_eq!(blocklist.moderate("what a nice view"), Verdict::Allow);
+        assert_eq!(
+            blocklist.moderate("Darn, the train is late again, heck"),
+            Verdict::Mask("****, the train is late again, ****".to_string())
+        );
+        assert_eq!(
+            blocklist.moderate("darnation"),
+            Verdict::Allow,
+            "only whole words"
+        );
+        assert_eq!(Blocklist::new(&[]).moderate("darn"), Verdict::Allow);
+    }
+
+    #[test]
+    fn contact_detector() {
+        let detector = ContactDetector::default();
+        for text in [
+            "we arrive at 12:30 on 2026-10-18",
+            "platform 3, car 12",
+            "the ICE 1234 is late by 25 minutes",
+        ] {
+            assert_eq!(detector.moderate(text), Verdict::Allow, "{}", text);
+        }
+
+        assert_eq!(
+            detector.moderate("write me at jane.doe@example.com or call +49 170 1234567"),
+            Verdict::Mask("write me at *** or call ***".to_string())
+        );
+        for text in [
+            "cheap tickets at https://example.net/deals",
+            "check out www.example.org",
+            "buy-stuff.shop has the best prices",
+        ] {
+            assert!(
+                matches!(detector.moderate(text), Verdict::Reject(_)),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        let pipeline = Pipeline::new(vec![
+            Box::new(Blocklist::new(&["darn"])),
+            Box::new(ContactDetector::default()),
+            Box::new(FloodDetector),
+        ]);
+
+        assert_eq!(pipeline.moderate("Is this seat taken?"), Verdict::Allow);
+        assert_eq!(
+            pipeline.moderate("DARN, WHY IS THIS TRAIN SO SLOW"),
+            Verdict::Mask("****, why is this train so slow".to_string())
+        );
+        assert_eq!(
+            pipeline.moderate("OK"),
+            Verdict::Allow,
+            "too short for caps"
+        );
+        assert!(matches!(
+            pipeline.moderate(&"🚃".repeat(FLOOD_MAX_EMOJIS + 1)),
+            Verdict::Reject(_)
+        ));
+        assert!(matches!(
+            pipeline.moderate("DARN, see www.example.com"),
+            Verdict::Reject(_)
+        ));
+    }
+}
@@ -0,0 +1,398 @@
+use crate::grid::Grid;
+use crate::state::{ChatMessage, Trace, CONFIG};
+use chrono::{DateTime, Duration, Utc};
+use geo::{geometry::Point, GeodesicDestination};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+// max. age of a member's trace in seconds to be matched with the traces of other users
+const MEMBER_TRACE_MAX_AGE_SECONDS: i64 = 60;
+
+// a user's place in a room
+#[derive(Clone, Debug, PartialEq)]
+pub struct Membership {
+    pub room_id: Uuid,
+    // ids of the room and of all rooms merged into it, messages may belong to any of them
+    pub room_ids: Vec<Uuid>,
+    pub joined_at: DateTime<Utc>,
+}
+
+impl Membership {
+    // whether a message was posted to the room since the user joined
+    pub fn contains(&self, msg: &ChatMessage) -> bool {
+        match msg.room_id {
+            Some(room_id) => self.room_ids.contains(&room_id) && msg.timestamp >= self.joined_at,
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Room {
+    created_at: DateTime<Utc>,
+    merged_room_ids: Vec<Uuid>,
+    members: HashMap<Uuid, Member>,
+}
+
+#[derive(Clone, Debug)]
+struct Member {
+    joined_at: DateTime<Utc>,
+    // last trace that matched the room and when it was received
+    trace: Trace,
+    seen_at: DateTime<Utc>,
+}
+
+impl Member {
+    // where the member should be by now if they kept going, e.g. through a tunnel
+    fn expected_trace(&self, now: DateTime<Utc>) -> Trace {
+        let seconds = (now - self.seen_at).num_milliseconds() as f64 / 1000.0;
+        let location = Point::new(self.trace.location.0, self.trace.location.1)
+            .geodesic_destination(self.trace.bearing, self.trace.speed * seconds.max(0.0));
+
+        Trace::new(
+            (location.x(), location.y()),
+            self.trace.speed,
+            self.trace.bearing,
+        )
+    }
+}
+
+// groups users travelling together, e.g. on the same train, into rooms
+//
+// users stay in their room as long as their traces match it, and for a grace period without
+// matching traces, e.g. while the train stops at a station or goes through a tunnel
+#[derive(Debug, Default)]
+pub struct Rooms {
+    rooms: HashMap<Uuid, Room>,
+    room_ids_by_user: HashMap<Uuid, Uuid>,
+    // user ids by the location of their last trace, to only match rooms nearby
+    grid: Grid,
+    // when users were seen and their ids, oldest first, entries are outdated if users were seen again
+    seen: VecDeque<(DateTime<Utc>, Uuid)>,
+}
+
+impl Rooms {
+    // moves a user to the room matching their current trace, returns their membership if any
+    pub fn update(
+        &mut self,
+        user_id: Uuid,
+        trace: &Trace,
+        now: DateTime<Utc>,
+    ) -> Option<Membership> {
+        self.remove_inactive_members(now);
+
+        if trace.speed < CONFIG.min_speed_meters_per_second {
+            // standing still, keep the room until the grace period is over
+            return self.membership(user_id);
+        }
+
+        let mut room_ids = self.matching_rooms(Some(user_id), trace, now);
+        if let Some(room_id) = self.room_ids_by_user.get(&user_id).copied() {
+            if !room_ids.contains(&room_id) {
+                self.leave(user_id, room_id);
+            }
+        }
+
+        let room_id = match room_ids.first().copied() {
+            Some(room_id) => {
+                for other_room_id in room_ids.split_off(1) {
+                    self.merge(other_room_id, room_id);
+                }
+                room_id
+            }
+            None => {
+                let room_id = Uuid::new_v4();
+                self.rooms.insert(
+                    room_id,
+                    Room {
+                        created_at: now,
+                        merged_room_ids: vec![],
+                        members: HashMap::new(),
+                    },
+                );
+                room_id
+            }
+        };
+
+        let room = self.rooms.get_mut(&room_id)?;
+        let member = room.members.entry(user_id).or_insert_with(|| Member {
+            joined_at: now,
+            trace: trace.clone(),
+            seen_at: now,
+        });
+        self.grid.remove(&user_id, member.trace.location);
+        member.trace = trace.clone();
+        member.seen_at = now;
+        self.grid.insert(user_id, trace.location);
+        self.room_ids_by_user.insert(user_id, room_id);
+        self.seen.push_back((now, user_id));
+
+        self.membership(user_id)
+    }
+
+    // room for a trace without joining it, e.g. for messages from other instances
+    pub fn find(&self, trace: &Trace, now: DateTime<Utc>) -> Option<Uuid> {
+        if trace.speed < CONFIG.min_speed_meters_per_second {
+            return None;
+        }
+
+        self.matching_rooms(None, trace, now).first().copied()
+    }
+
+    // amount of rooms and of rooms shared by several users, e.g. vehicles with users chatting
+    pub fn counts(&mut self, now: DateTime<Utc>) -> (usize, usize) {
+        self.remove_inactive_members(now);
+
+        let shared = self
+            .rooms
+            .values()
+            .filter(|room| room.members.len() > 1)
+            .count();
+        (self.rooms.len(), shared)
+    }
+
+    pub fn membership(&self, user_id: Uuid) -> Option<Membership> {
+        let room_id = *self.room_ids_by_user.get(&user_id)?;
+        let room = self.rooms.get(&room_id)?;
+        let member = room.members.get(&user_id)?;
+
+        let mut room_ids = vec![room_id];
+        room_ids.extend(room.merged_room_ids.iter().copied());
+
+        Some(Membership {
+            room_id,
+            room_ids,
+            joined_at: member.joined_at,
+        })
+    }
+
+    // other members of the user's room
+    pub fn room_mates(&self, user_id: Uuid) -> Vec<Uuid> {
+        self.room_ids_by_user
+            .get(&user_id)
+            .and_then(|room_id| self.rooms.get(room_id))
+            .map(|room| {
+                room.members
+                    .keys()
+                    .filter(|member_id| **member_id != user_id)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // ids of rooms matching a trace, biggest first, oldest first on ties
+    fn matching_rooms(
+        &self,
+        user_id: Option<Uuid>,
+        trace: &Trace,
+        now: DateTime<Utc>,
+    ) -> Vec<Uuid> {
+        let oldest_trace_time = now - Duration::seconds(MEMBER_TRACE_MAX_AGE_SECONDS);
+        let candidates = self
+            .grid
+            .candidates(trace.location, trace.match_radius_meters())
+            .unwrap_or_else(|| self.room_ids_by_user.keys().copied().collect());
+
+        let mut room_ids: Vec<Uuid> = vec![];
+        for other_user_id in candidates {
+            if Some(other_user_id) == user_id {
+                continue;
+            }
+            let Some(room_id) = self.room_ids_by_user.get(&other_user_id) else {
+                continue;
+            };
+            if room_ids.contains(room_id) {
+                continue;
+            }
+            let Some(other) = self
+                .rooms
+                .get(room_id)
+                .and_then(|room| room.members.get(&other_user_id))
+            else {
+                continue;
+            };
+            if other.seen_at >= oldest_trace_time && trace.overlaps_with(&other.trace) {
+                room_ids.push(*room_id);
+            }
+        }
+
+        // without recent traces of others, e.g. after a tunnel, the user's own room matches if
+        // they are where their last trace says they would be
+        if let Some(user_id) = user_id {
+            let own_room = self.room_ids_by_user.get(&user_id).and_then(|room_id| {
+                let room = self.rooms.get(room_id)?;
+                let member = room.members.get(&user_id)?;
+                let others_seen_recently = room.members.iter().any(|(other_user_id, other)| {
+                    *other_user_id != user_id && other.seen_at >= oldest_trace_time
+                });
+                let matches = trace.overlaps_with(&member.expected_trace(now));
+                (!others_seen_recently && matches).then_some(*room_id)
+            });
+            if let Some(room_id) = own_room {
+                if !room_ids.contains(&room_id) {
+                    room_ids.push(room_id);
+                }
+            }
+        }
+
+        room_ids.sort_by(|a, b| {
+            let (a, b) = (&self.rooms[a], &self.rooms[b]);
+            b.members
+                .len()
+                .cmp(&a.members.len())
+                .then(a.created_at.cmp(&b.created_at))
+        });
+
+        room_ids
+    }
+
+    // moves all members of a room to another one, keeping the time they joined
+    fn merge(&mut self, from_room_id: Uuid, to_room_id: Uuid) {
+        let Some(from) = self.rooms.remove(&from_room_id) else {
+            return;
+        };
+        let Some(to) = self.rooms.get_mut(&to_room_id) else {
+            return;
+        };
+
+        to.merged_room_ids.push(from_room_id);
+        to.merged_room_ids.extend(from.merged_room_ids);
+        for (user_id, member) in from.members {
+            self.room_ids_by_user.insert(user_id, to_room_id);
+            to.members.insert(user_id, member);
+        }
+    }
+
+    fn leave(&mut self, user_id: Uuid, room_id: Uuid) {
+        self.room_ids_by_user.remove(&user_id);
+        let Some(room) = self.rooms.get_mut(&room_id) else {
+            return;
+        };
+        if let Some(member) = room.members.remove(&user_id) {
+            self.grid.remove(&user_id, member.trace.location);
+        }
+        if room.members.is_empty() {
+            self.rooms.remove(&room_id);
+        }
+    }
+
+    fn remove_inactive_members(&mut self, now: DateTime<Utc>) {
+        let oldest_seen_at = now - Duration::seconds(CONFIG.room_membership_grace_seconds);
+        while let Some((seen_at, user_id)) = self.seen.front().copied() {
+            if seen_at >= oldest_seen_at {
+                break;
+            }
+            self.seen.pop_front();
+
+            let Some(room_id) = self.room_ids_by_user.get(&user_id).copied() else {
+                continue;
+            };
+            let last_seen_at = self
+                .rooms
+                .get(&room_id)
+                .and_then(|room| room.members.get(&user_id))
+                .map(|member| member.seen_at);
+            let inactive = match last_seen_at {
+                Some(last_seen_at) => last_seen_at < oldest_seen_at,
+                None => true,
+            };
+            if inactive {
+                self.leave(user_id, room_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn rooms() {
+        use super::*;
+
+        let now = Utc::now();
+        let seconds = |seconds: i64| now + Duration::seconds(seconds);
+        let mut rooms = Rooms::default();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        // train leaving Hamburg Hbf towards Berlin at 30 m/s, heading east
+        let train = |after_seconds: f64, speed: f64| {
+            let location =
+                Point::new(10.006, 53.553).geodesic_destination(90.0, 30.0 * after_seconds);
+            Trace::new((location.x(), location.y()), speed, 90.0)
+        };
+
+        let alice_room = rooms
+            .update(alice, &train(0.0, 30.0), now)
+            .expect("alice should be in a room");
+        let bob_room = rooms
+            .update(bob, &train(10.0, 28.0), seconds(10))
+            .expect("bob should be in a room");
+        assert_eq!(alice_room.room_id, bob_room.room_id, "same train");
+        assert_eq!(bob_room.joined_at, seconds(10));
+
+        // a bus on the same road in the opposite direction
+        let bus = Trace::new(train(20.0, 30.0).location, 12.0, 270.0);
+        let carol_room = rooms
+            .update(carol, &bus, seconds(20))
+            .expect("carol should be in a room");
+        assert_ne!(carol_room.room_id, alice_room.room_id, "different vehicle");
+
+        // the train stops at a station, still the same room
+        let stopped = Trace::new(train(60.0, 0.0).location, 0.5, 90.0);
+        assert_eq!(
+            rooms.update(alice, &stopped, seconds(120)),
+            Some(alice_room.clone()),
+            "stopped at station"
+        );
+
+        // both go through a tunnel and come out where they'd be by now
+        assert_eq!(
+            rooms.update(alice, &train(240.0, 30.0), seconds(240)),
+            Some(alice_room.clone()),
+            "after tunnel"
+        );
+        assert_eq!(
+            rooms
+                .update(bob, &train(245.0, 30.0), seconds(245))
+                .map(|membership| membership.room_id),
+            Some(alice_room.room_id),
+            "after tunnel, bob"
+        );
+
+        // without traces for longer than the grace period, the room is gone
+        let later = seconds(245 + CONFIG.room_membership_grace_seconds + 1);
+        assert_eq!(rooms.membership(alice), Some(alice_room.clone()));
+        rooms.update(carol, &bus, later);
+        assert_eq!(rooms.membership(alice), None, "alice timed out");
+        assert_eq!(rooms.membership(bob), None, "bob timed out");
+    }
+
+    #[test]
+    fn merge_rooms() {
+        use super::*;
+
+        let now = Utc::now();
+        let mut rooms = Rooms::default();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        // alice and bob are too far apart to match each other, carol sits in between
+        let alice_room = rooms
+            .update(alice, &Trace::new((10.0, 53.55), 10.0, 0.0), now)
+            .expect("alice should be in a room");
+        let bob_room = rooms
+            .update(bob, &Trace::new((10.0, 53.58), 10.0, 0.0), now)
+            .expect("bob should be in a room");
+        assert_ne!(alice_room.room_id, bob_room.room_id);
+
+        let carol_room = rooms
+            .update(carol, &Trace::new((10.0, 53.565), 14.0, 0.0), now)
+            .expect("carol should be in a room");
+        assert_eq!(carol_room.room_ids.len(), 2, "rooms merged");
+        for user_id in [alice, bob] {
+            let membership = rooms.membership(user_id).expect("should be in a room");
+            assert_eq!(membership.room_id, carol_room.room_id);
+            assert_eq!(membership.joined_at, now, "join time kept");
+        }
+    }
+}
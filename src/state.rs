@@ -1,9 +1,13 @@
+use crate::conversation::{ConversationError, ConversationOut, Conversations, DirectMessage};
+use crate::crypto::{PublicKey, Sealed};
+use crate::room::{Membership, Rooms};
+use crate::store::{Area, MemoryStore, MessageStats, MessageStore};
 use cfg_if::cfg_if;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use geo::{geometry::Point, GeodesicDistance};
 use names::Generator;
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet, VecDeque};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::env;
 use uuid::Uuid;
 use web_sys::PositionError;
@@ -12,14 +16,41 @@ cfg_if! {
     if #[cfg(feature = "ssr")] {
         use leptos::LeptosOptions;
         use axum::extract::FromRef;
-        use tokio::sync::mpsc::{Sender};
+        use tokio::sync::{broadcast, mpsc::Sender};
         use std::sync::{Arc, Mutex};
+        use crate::actor::PlaneHandle;
+        use crate::fanout::Fanout;
+        use crate::limit::Limiter;
+        use crate::moderation::Moderator;
 
         #[derive(FromRef, Debug, Clone)]
         pub struct AppState{
             pub leptos_options: LeptosOptions,
             pub chat_msg_in_tx: Sender<ChatMessageIn>,
-            pub plane: Arc<Mutex<Plane>>,
+            // every new or changed message is published here, websocket connections subscribe to it
+            pub chat_msg_out_tx: broadcast::Sender<ChatMessage>,
+            // users of every conversation with a new direct message, also for websockets
+            pub direct_msg_out_tx: broadcast::Sender<(Uuid, Uuid)>,
+            pub plane: PlaneHandle,
+            // shares new messages and votes with other instances
+            pub fanout: Arc<dyn Fanout>,
+            // rate limits and duplicate checks of sent messages
+            pub limiter: Arc<Mutex<Limiter>>,
+            // checks new and edited messages
+            pub moderator: Arc<dyn Moderator>,
+        }
+
+        impl Plane {
+            // plane with the message store selected in the config
+            pub fn from_config() -> anyhow::Result<Self> {
+                let store: Box<dyn MessageStore> = match CONFIG.message_store.as_str() {
+                    "memory" => Box::new(MemoryStore::with_capacity(CONFIG.max_messages_in_memory)),
+                    "sqlite" => Box::new(crate::store::SqliteStore::open(&CONFIG.sqlite_path)?),
+                    other => anyhow::bail!("unknown message store: {}", other),
+                };
+
+                Ok(Self::with_store(store))
+            }
         }
     }
 }
@@ -34,6 +65,54 @@ const MAX_MESSAGES_IN_MEMORY: usize = 100_000;
 const MAX_MESSAGE_LENGTH: usize = 144;
 // max. message age in minutes before removing from memory
 const MAX_MESSAGE_AGE_MINUTES: i64 = 10;
+// comma separated minutes authors can choose to keep their message shorter than the max. age
+const MESSAGE_TTL_OPTIONS_MINUTES: &str = "1,5";
+// where messages and usernames are stored, either "memory" or "sqlite"
+const MESSAGE_STORE: &str = "memory";
+// path of the sqlite database file, only used by the "sqlite" message store
+const SQLITE_PATH: &str = "catenary.sqlite";
+// address to receive messages and votes from other instances on, e.g. "0.0.0.0:3100", empty for a single instance
+const FANOUT_LISTEN_ADDR: &str = "";
+// comma separated addresses of all other instances, e.g. "10.0.0.2:3100,10.0.0.3:3100"
+const FANOUT_PEERS: &str = "";
+// secret of at least 32 bytes shared by all instances to sign events, required with a listen address
+const FANOUT_SECRET: &str = "";
+// header with the client ip address set by a proxy, e.g. "x-forwarded-for", empty to use the address of the connection
+const CLIENT_IP_HEADER: &str = "";
+// max. messages per minute a user may send on average, and at once
+const USER_MESSAGES_PER_MINUTE: f64 = 6.0;
+const USER_MESSAGE_BURST: f64 = 3.0;
+// max. messages per minute all users with the same ip address may send on average, and at once
+const IP_MESSAGES_PER_MINUTE: f64 = 30.0;
+const IP_MESSAGE_BURST: f64 = 10.0;
+// lower rates are raised to this, otherwise waiting for the next message overflows
+const MIN_MESSAGES_PER_MINUTE: f64 = 0.01;
+// seconds in which an author can't send the same or a very similar message again
+const DUPLICATE_MESSAGE_WINDOW_SECONDS: u64 = 120;
+// comma separated words that are masked in messages
+const MODERATION_BLOCKLIST: &str = "";
+// min. share of downvotes among all votes of a message to hide it from everyone
+const HIDE_MESSAGE_DOWNVOTE_RATIO: f64 = 0.7;
+// min. amount of users who voted on a message before it can be hidden
+const HIDE_MESSAGE_MIN_VOTERS: usize = 5;
+// amount of hidden messages after which an author is muted until the server restarts
+const MUTE_AUTHOR_HIDDEN_MESSAGES: usize = 3;
+// token to enter on the admin page, empty to disable it
+const ADMIN_TOKEN: &str = "";
+// seconds after sending in which authors can edit their messages
+const EDIT_MESSAGE_WINDOW_SECONDS: i64 = 300;
+// comma separated keys of at least 64 bytes to sign user cookies with, the first one signs new
+// cookies, the others are only accepted, random if empty
+const COOKIE_KEYS: &str = "";
+// hours a user keeps their identity without visiting
+const USER_COOKIE_MAX_AGE_HOURS: i64 = 12;
+// only send the user cookie over https, turn off to develop over plain http
+const SECURE_COOKIES: bool = true;
+// how long users keep their username, "user" as long as they keep their identity, "trip" until
+// they make a break, so they can't be recognized on their next trip
+const USERNAME_SCOPE: &str = "user";
+// minutes without sending or listing messages after which a trip ends
+const TRIP_BREAK_MINUTES: i64 = 60;
 
 // max. amount of locations stored in history
 const MAX_LOCATIONS_IN_HISTORY: usize = 4;
@@ -43,11 +122,71 @@ const MAX_LOCATION_AGE_SECONDS: usize = 60;
 const MIN_LOCATION_TIME_DELTA_SECONDS: f64 = 1.5;
 // min. speed in meters per second, below that, the trace is not valid
 const MIN_SPEED_METERS_PER_SECOND: f64 = 3.0;
+// seconds the last valid trace is kept while too slow, e.g. while stopping at a station
+const STOP_GRACE_SECONDS: i64 = 180;
+// max. accuracy radius of a location in meters, less accurate locations are dropped
+const MAX_LOCATION_ACCURACY_METERS: f64 = 50.0;
+// max. speed in meters per second between two locations, faster jumps are dropped
+const MAX_PLAUSIBLE_SPEED_METERS_PER_SECOND: f64 = 120.0;
+
+// distance in meters a user may move beyond the max. plausible speed between two traces,
+// to allow for inaccurate locations
+const TELEPORT_SLACK_METERS: f64 = 200.0;
+// seconds the last trace of a user is kept to check the next one against
+const LAST_TRACE_MAX_AGE_SECONDS: i64 = 600;
+
+// emojis users can react to messages with, in the order they are shown
+pub const REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🚆"];
+
+// min. and max. characters of a username chosen by a user
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 24;
+// random usernames tried when re-rolling until one is found nobody nearby uses
+const MAX_REROLL_ATTEMPTS: usize = 10;
+
+// max. amount of reported messages waiting for review, further reports are rejected
+const MAX_REPORTS: usize = 1000;
+// max. amount of waiting reports by the same user
+const MAX_REPORTS_PER_REPORTER: usize = 10;
+// minutes to average the messages per minute over on the admin page
+const STATS_RATE_MINUTES: i64 = 5;
+
+// seconds users stay in their room without a matching trace, e.g. while stopping at a station
+const ROOM_MEMBERSHIP_GRACE_SECONDS: i64 = 300;
 
 // match traces if distance covered of self in x seconds is smaller than distance diff between self and other
 const TRACE_MATCH_MAX_MOVE_SECONDS: f64 = 180.0;
-// max. slope diff between two traces in degrees
-const TRACE_MATCH_MAX_SLOPE_DIFF_DEGREES: f64 = 32.0;
+// max. bearing diff between two traces in degrees
+const TRACE_MATCH_MAX_BEARING_DIFF_DEGREES: f64 = 32.0;
+// below this speed in meters per second, the max. bearing diff grows inversely with speed,
+// because bearings measured at low speeds are less precise
+const TRACE_MATCH_BEARING_REFERENCE_SPEED_METERS_PER_SECOND: f64 = 8.0;
+// max. speed diff between two traces in meters per second, regardless of their speed
+const TRACE_MATCH_MAX_SPEED_DIFF_METERS_PER_SECOND: f64 = 8.0;
+// max. speed diff between two traces relative to the higher speed, used if more than the absolute diff
+const TRACE_MATCH_MAX_SPEED_DIFF_RATIO: f64 = 0.4;
+
+// config value that must not show up in logs
+#[derive(Clone, Default, PartialEq)]
+pub(crate) struct Secret(pub(crate) String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "\"***\"")
+        }
+    }
+}
+
+// how long users keep their username, see `USERNAME_SCOPE`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UsernameScope {
+    #[default]
+    User,
+    Trip,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Config {
@@ -56,7 +195,73 @@ pub struct Config {
     // max. amount of characters in a message
     max_message_length: usize,
     // max. message age in minutes before removing from memory
-    max_message_age_minutes: i64,
+    pub(crate) max_message_age_minutes: i64,
+    // minutes authors can choose to keep their message shorter than the max. age, ascending
+    pub(crate) message_ttl_options_minutes: Vec<i64>,
+    // where messages and usernames are stored, either "memory" or "sqlite"
+    #[serde(skip)]
+    message_store: String,
+    // path of the sqlite database file, only used by the "sqlite" message store
+    #[serde(skip)]
+    sqlite_path: String,
+    // address to receive messages and votes from other instances on, empty for a single instance
+    #[serde(skip)]
+    pub(crate) fanout_listen_addr: String,
+    // comma separated addresses of all other instances
+    #[serde(skip)]
+    pub(crate) fanout_peers: String,
+    // secret shared by all instances to sign events
+    #[serde(skip)]
+    pub(crate) fanout_secret: Secret,
+    // header with the client ip address set by a proxy, empty to use the address of the connection
+    #[serde(skip)]
+    pub(crate) client_ip_header: String,
+    // max. messages per minute a user may send on average, and at once
+    #[serde(skip)]
+    pub(crate) user_messages_per_minute: f64,
+    #[serde(skip)]
+    pub(crate) user_message_burst: f64,
+    // max. messages per minute all users with the same ip address may send on average, and at once
+    #[serde(skip)]
+    pub(crate) ip_messages_per_minute: f64,
+    #[serde(skip)]
+    pub(crate) ip_message_burst: f64,
+    // seconds in which an author can't send the same or a very similar message again
+    #[serde(skip)]
+    pub(crate) duplicate_message_window_seconds: u64,
+    // comma separated words that are masked in messages
+    #[serde(skip)]
+    pub(crate) moderation_blocklist: String,
+    // min. share of downvotes among all votes of a message to hide it from everyone
+    #[serde(skip)]
+    pub(crate) hide_message_downvote_ratio: f64,
+    // min. amount of users who voted on a message before it can be hidden
+    #[serde(skip)]
+    pub(crate) hide_message_min_voters: usize,
+    // amount of hidden messages after which an author is muted until the server restarts
+    #[serde(skip)]
+    pub(crate) mute_author_hidden_messages: usize,
+    // token to enter on the admin page, empty to disable it
+    #[serde(skip)]
+    pub(crate) admin_token: Secret,
+    // seconds after sending in which authors can edit their messages
+    #[serde(skip)]
+    pub(crate) edit_message_window_seconds: i64,
+    // comma separated keys to sign user cookies with, the first one signs new cookies
+    #[serde(skip)]
+    pub(crate) cookie_keys: Secret,
+    // hours a user keeps their identity without visiting
+    #[serde(skip)]
+    pub(crate) user_cookie_max_age_hours: i64,
+    // only send the user cookie over https
+    #[serde(skip)]
+    pub(crate) secure_cookies: bool,
+    // how long users keep their username
+    #[serde(skip)]
+    pub(crate) username_scope: UsernameScope,
+    // minutes without sending or listing messages after which a trip ends
+    #[serde(skip)]
+    pub(crate) trip_break_minutes: i64,
 
     // max. amount of locations stored in history
     max_locations_in_history: usize,
@@ -65,17 +270,32 @@ pub struct Config {
     // min. amount of seconds between first and last location in history, below that, the trace is not valid
     min_location_time_delta_seconds: f64,
     // min. speed in meters per second, below that, the trace is not valid
-    min_speed_meters_per_second: f64,
+    pub(crate) min_speed_meters_per_second: f64,
+    // seconds the last valid trace is kept while too slow, e.g. while stopping at a station
+    stop_grace_seconds: i64,
+    // max. accuracy radius of a location in meters, less accurate locations are dropped
+    max_location_accuracy_meters: f64,
+    // max. speed in meters per second between two locations, faster jumps are dropped
+    max_plausible_speed_meters_per_second: f64,
+    // seconds users stay in their room without a matching trace, e.g. while stopping at a station
+    pub(crate) room_membership_grace_seconds: i64,
 
     // match traces if distance covered of self in x seconds is smaller than distance diff between self and other
     trace_match_max_move_seconds: f64,
-    // max. slope diff between two traces in degrees
-    trace_match_max_slope_diff_degrees: f64,
+    // max. bearing diff between two traces in degrees
+    trace_match_max_bearing_diff_degrees: f64,
+    // below this speed in meters per second, the max. bearing diff grows inversely with speed,
+    // because bearings measured at low speeds are less precise
+    trace_match_bearing_reference_speed_meters_per_second: f64,
+    // max. speed diff between two traces in meters per second, regardless of their speed
+    trace_match_max_speed_diff_meters_per_second: f64,
+    // max. speed diff between two traces relative to the higher speed, used if more than the absolute diff
+    trace_match_max_speed_diff_ratio: f64,
 }
 
 impl Config {
     fn new() -> Self {
-        let config = Self {
+        let mut config = Self {
             max_messages_in_memory: env::var("MAX_MESSAGES_IN_MEMORY")
                 .unwrap_or_else(|_| MAX_MESSAGES_IN_MEMORY.to_string())
                 .parse()
@@ -88,6 +308,92 @@ impl Config {
                 .unwrap_or_else(|_| MAX_MESSAGE_AGE_MINUTES.to_string())
                 .parse()
                 .unwrap_or(10),
+            message_ttl_options_minutes: env::var("MESSAGE_TTL_OPTIONS_MINUTES")
+                .unwrap_or_else(|_| MESSAGE_TTL_OPTIONS_MINUTES.to_string())
+                .split(',')
+                .filter_map(|minutes| minutes.trim().parse().ok())
+                .collect(),
+            message_store: env::var("MESSAGE_STORE").unwrap_or_else(|_| MESSAGE_STORE.to_string()),
+            sqlite_path: env::var("SQLITE_PATH").unwrap_or_else(|_| SQLITE_PATH.to_string()),
+            fanout_listen_addr: env::var("FANOUT_LISTEN_ADDR")
+                .unwrap_or_else(|_| FANOUT_LISTEN_ADDR.to_string()),
+            fanout_peers: env::var("FANOUT_PEERS").unwrap_or_else(|_| FANOUT_PEERS.to_string()),
+            fanout_secret: Secret(
+                env::var("FANOUT_SECRET").unwrap_or_else(|_| FANOUT_SECRET.to_string()),
+            ),
+            client_ip_header: env::var("CLIENT_IP_HEADER")
+                .unwrap_or_else(|_| CLIENT_IP_HEADER.to_string()),
+            user_messages_per_minute: env::var("USER_MESSAGES_PER_MINUTE")
+                .unwrap_or_else(|_| USER_MESSAGES_PER_MINUTE.to_string())
+                .parse::<f64>()
+                .unwrap_or(6.0)
+                .max(MIN_MESSAGES_PER_MINUTE),
+            user_message_burst: env::var("USER_MESSAGE_BURST")
+                .unwrap_or_else(|_| USER_MESSAGE_BURST.to_string())
+                .parse::<f64>()
+                .unwrap_or(3.0)
+                .max(1.0),
+            ip_messages_per_minute: env::var("IP_MESSAGES_PER_MINUTE")
+                .unwrap_or_else(|_| IP_MESSAGES_PER_MINUTE.to_string())
+                .parse::<f64>()
+                .unwrap_or(30.0)
+                .max(MIN_MESSAGES_PER_MINUTE),
+            ip_message_burst: env::var("IP_MESSAGE_BURST")
+                .unwrap_or_else(|_| IP_MESSAGE_BURST.to_string())
+                .parse::<f64>()
+                .unwrap_or(10.0)
+                .max(1.0),
+            duplicate_message_window_seconds: env::var("DUPLICATE_MESSAGE_WINDOW_SECONDS")
+                .unwrap_or_else(|_| DUPLICATE_MESSAGE_WINDOW_SECONDS.to_string())
+                .parse()
+                .unwrap_or(120),
+            moderation_blocklist: env::var("MODERATION_BLOCKLIST")
+                .unwrap_or_else(|_| MODERATION_BLOCKLIST.to_string()),
+            hide_message_downvote_ratio: env::var("HIDE_MESSAGE_DOWNVOTE_RATIO")
+                .unwrap_or_else(|_| HIDE_MESSAGE_DOWNVOTE_RATIO.to_string())
+                .parse()
+                .unwrap_or(0.7),
+            hide_message_min_voters: env::var("HIDE_MESSAGE_MIN_VOTERS")
+                .unwrap_or_else(|_| HIDE_MESSAGE_MIN_VOTERS.to_string())
+                .parse()
+                .unwrap_or(5),
+            mute_author_hidden_messages: env::var("MUTE_AUTHOR_HIDDEN_MESSAGES")
+                .unwrap_or_else(|_| MUTE_AUTHOR_HIDDEN_MESSAGES.to_string())
+                .parse()
+                .unwrap_or(3),
+            admin_token: Secret(
+                env::var("ADMIN_TOKEN").unwrap_or_else(|_| ADMIN_TOKEN.to_string()),
+            ),
+            edit_message_window_seconds: env::var("EDIT_MESSAGE_WINDOW_SECONDS")
+                .unwrap_or_else(|_| EDIT_MESSAGE_WINDOW_SECONDS.to_string())
+                .parse()
+                .unwrap_or(300),
+            cookie_keys: Secret(
+                env::var("COOKIE_KEYS").unwrap_or_else(|_| COOKIE_KEYS.to_string()),
+            ),
+            user_cookie_max_age_hours: env::var("USER_COOKIE_MAX_AGE_HOURS")
+                .unwrap_or_else(|_| USER_COOKIE_MAX_AGE_HOURS.to_string())
+                .parse()
+                .unwrap_or(12),
+            secure_cookies: env::var("SECURE_COOKIES")
+                .unwrap_or_else(|_| SECURE_COOKIES.to_string())
+                .parse()
+                .unwrap_or(true),
+            username_scope: match env::var("USERNAME_SCOPE")
+                .unwrap_or_else(|_| USERNAME_SCOPE.to_string())
+                .as_str()
+            {
+                "user" => UsernameScope::User,
+                "trip" => UsernameScope::Trip,
+                other => {
+                    log::warn!("unknown username scope: {}, using \"user\"", other);
+                    UsernameScope::User
+                }
+            },
+            trip_break_minutes: env::var("TRIP_BREAK_MINUTES")
+                .unwrap_or_else(|_| TRIP_BREAK_MINUTES.to_string())
+                .parse()
+                .unwrap_or(60),
 
             max_locations_in_history: env::var("MAX_LOCATIONS_IN_HISTORY")
                 .unwrap_or_else(|_| {
@@ -108,17 +414,68 @@ impl Config {
                 .unwrap_or_else(|_| MIN_SPEED_METERS_PER_SECOND.to_string())
                 .parse()
                 .unwrap_or(3.0),
+            stop_grace_seconds: env::var("STOP_GRACE_SECONDS")
+                .unwrap_or_else(|_| STOP_GRACE_SECONDS.to_string())
+                .parse()
+                .unwrap_or(180),
+            max_location_accuracy_meters: env::var("MAX_LOCATION_ACCURACY_METERS")
+                .unwrap_or_else(|_| MAX_LOCATION_ACCURACY_METERS.to_string())
+                .parse()
+                .unwrap_or(50.0),
+            max_plausible_speed_meters_per_second: env::var(
+                "MAX_PLAUSIBLE_SPEED_METERS_PER_SECOND",
+            )
+            .unwrap_or_else(|_| MAX_PLAUSIBLE_SPEED_METERS_PER_SECOND.to_string())
+            .parse()
+            .unwrap_or(120.0),
+            room_membership_grace_seconds: env::var("ROOM_MEMBERSHIP_GRACE_SECONDS")
+                .unwrap_or_else(|_| ROOM_MEMBERSHIP_GRACE_SECONDS.to_string())
+                .parse()
+                .unwrap_or(300),
 
             trace_match_max_move_seconds: env::var("TRACE_MATCH_MAX_MOVE_SECONDS")
                 .unwrap_or_else(|_| TRACE_MATCH_MAX_MOVE_SECONDS.to_string())
                 .parse()
                 .unwrap_or(180.0),
 
-            trace_match_max_slope_diff_degrees: env::var("TRACE_MATCH_MAX_SLOPE_DIFF_DEGREES")
-                .unwrap_or_else(|_| TRACE_MATCH_MAX_SLOPE_DIFF_DEGREES.to_string())
+            trace_match_max_bearing_diff_degrees: env::var("TRACE_MATCH_MAX_BEARING_DIFF_DEGREES")
+                .or_else(|_| {
+                    // name before traces were matched by bearing, still read for old deployments
+                    let value = env::var("TRACE_MATCH_MAX_SLOPE_DIFF_DEGREES")?;
+                    log::warn!(
+                        "TRACE_MATCH_MAX_SLOPE_DIFF_DEGREES is deprecated, \
+                        use TRACE_MATCH_MAX_BEARING_DIFF_DEGREES instead"
+                    );
+                    Ok::<_, env::VarError>(value)
+                })
+                .unwrap_or_else(|_| TRACE_MATCH_MAX_BEARING_DIFF_DEGREES.to_string())
                 .parse()
                 .unwrap_or(32.0),
+            trace_match_bearing_reference_speed_meters_per_second: env::var(
+                "TRACE_MATCH_BEARING_REFERENCE_SPEED_METERS_PER_SECOND",
+            )
+            .unwrap_or_else(|_| TRACE_MATCH_BEARING_REFERENCE_SPEED_METERS_PER_SECOND.to_string())
+            .parse()
+            .unwrap_or(8.0),
+
+            trace_match_max_speed_diff_meters_per_second: env::var(
+                "TRACE_MATCH_MAX_SPEED_DIFF_METERS_PER_SECOND",
+            )
+            .unwrap_or_else(|_| TRACE_MATCH_MAX_SPEED_DIFF_METERS_PER_SECOND.to_string())
+            .parse()
+            .unwrap_or(8.0),
+            trace_match_max_speed_diff_ratio: env::var("TRACE_MATCH_MAX_SPEED_DIFF_RATIO")
+                .unwrap_or_else(|_| TRACE_MATCH_MAX_SPEED_DIFF_RATIO.to_string())
+                .parse()
+                .unwrap_or(0.4),
         };
+        // only shorter times than the max. age are options
+        let max_message_age_minutes = config.max_message_age_minutes;
+        config
+            .message_ttl_options_minutes
+            .retain(|minutes| (1..max_message_age_minutes).contains(minutes));
+        config.message_ttl_options_minutes.sort_unstable();
+        config.message_ttl_options_minutes.dedup();
 
         cfg_if! {
             if #[cfg(feature = "ssr")] {
@@ -130,67 +487,222 @@ impl Config {
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug)]
 pub struct Plane {
-    messages: VecDeque<ChatMessage>,
-    author_usernames_by_id: HashMap<Uuid, String>,
+    store: Box<dyn MessageStore>,
+    rooms: Rooms,
+    // last valid trace of each user and when it was checked
+    last_traces: HashMap<Uuid, (Trace, DateTime<Utc>)>,
+    last_traces_pruned_at: DateTime<Utc>,
+    // when each user's username was last used, to start a new one after a trip
+    usernames_used_at: HashMap<Uuid, DateTime<Utc>>,
+    // hidden messages of each author who had any
+    author_reputations_by_id: HashMap<Uuid, Reputation>,
+    // reported messages waiting for review, oldest first
+    reports: VecDeque<Report>,
+    // authors banned by an admin, until the server restarts
+    banned_author_ids: HashSet<Uuid>,
+    // private chats between users, opened from messages one of them could see
+    conversations: Conversations,
+}
+
+#[derive(Debug, Default)]
+struct Reputation {
+    // with the time they were sent, to forget them once they expired
+    hidden_message_ids: HashMap<Uuid, DateTime<Utc>>,
+    // stays muted, even if messages get visible again
+    muted: bool,
+}
+
+impl Default for Plane {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Plane {
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryStore::with_capacity(
+            CONFIG.max_messages_in_memory,
+        )))
+    }
+
+    pub fn with_store(store: Box<dyn MessageStore>) -> Self {
         Plane {
-            messages: VecDeque::with_capacity(CONFIG.max_messages_in_memory),
-            author_usernames_by_id: HashMap::new(),
+            store,
+            rooms: Rooms::default(),
+            last_traces: HashMap::new(),
+            last_traces_pruned_at: Utc::now(),
+            usernames_used_at: HashMap::new(),
+            author_reputations_by_id: HashMap::new(),
+            reports: VecDeque::new(),
+            banned_author_ids: HashSet::new(),
+            conversations: Conversations::default(),
         }
     }
 
-    pub fn add_message(&mut self, msg: ChatMessageIn) {
-        let username = match self.author_usernames_by_id.get(&msg.author) {
-            Some(username) => username.clone(),
-            None => {
-                let username = Generator::default()
-                    .next()
-                    .unwrap_or_else(|| "anonymous".to_string());
-
-                self.author_usernames_by_id
-                    .insert(msg.author, username.clone());
-
-                username
-            }
-        };
+    pub fn add_message(&mut self, msg: ChatMessageIn) -> ChatMessage {
+        let username = self.username(msg.author);
 
-        let msg = ChatMessage::from((msg, username));
-        self.messages.push_front(msg.clone());
+        let membership = self.rooms.update(msg.author, &msg.trace, Utc::now());
+        let mut msg = ChatMessage::from((msg, username));
+        msg.room_id = membership.map(|membership| membership.room_id);
+        if let Err(err) = self.store.save_message(&msg) {
+            log::error!("couldn't save message, error: {}", err);
+        }
 
         self.delete_old_messages();
 
-        if self.messages.len() >= self.messages.capacity() {
-            self.messages.pop_back();
+        if let Err(err) = self.store.truncate_messages(CONFIG.max_messages_in_memory) {
+            log::error!("couldn't truncate messages, error: {}", err);
         }
+
+        msg
     }
 
+    // messages posted to the user's room since they joined and messages overlapping with their trace
     pub fn get_messages(&mut self, user_id: Option<Uuid>, trace: Trace) -> Vec<ChatMessageOut> {
         self.delete_old_messages();
 
-        let mut messages: Vec<ChatMessageOut> = self
-            .messages
-            .iter()
-            .take(10000)
-            .filter(|&msg| trace.overlaps_with(&msg.trace))
-            .cloned()
-            .map(|msg| ChatMessageOut::from((msg, user_id)))
-            .collect();
-
-        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        #[cfg(feature = "ssr")]
+        let started_at = std::time::Instant::now();
+        let membership = user_id.and_then(|user_id| self.join_room(user_id, &trace));
+        if let Some(user_id) = user_id {
+            self.continue_trip(user_id, Utc::now());
+        }
+        let area = (trace.location, trace.match_radius_meters());
+        let messages = self.find_messages(Some(area), user_id, &trace, membership.as_ref());
+        #[cfg(feature = "ssr")]
+        crate::metrics::METRICS
+            .get_messages_seconds
+            .observe("", started_at.elapsed().as_secs_f64());
 
         messages
     }
 
-    pub fn vote_message(&mut self, id: Uuid, user_id: Uuid, up: bool) {
-        let Some(msg) = self.messages.iter_mut().find(|msg| msg.id == id) else {
-            log::warn!("couldn't find message with id: {}", id);
-            return;
+    // validates a trace sent by a client and checks it against the user's previous one
+    pub fn check_trace(&mut self, user_id: Option<Uuid>, trace: &Trace) -> Result<(), TraceError> {
+        trace.validate()?;
+
+        let Some(user_id) = user_id else {
+            return Ok(());
+        };
+        let now = Utc::now();
+        if now - self.last_traces_pruned_at > Duration::seconds(LAST_TRACE_MAX_AGE_SECONDS) {
+            let oldest = now - Duration::seconds(LAST_TRACE_MAX_AGE_SECONDS);
+            self.last_traces
+                .retain(|_, (_, checked_at)| *checked_at >= oldest);
+            let trip_break = Duration::minutes(CONFIG.trip_break_minutes);
+            self.usernames_used_at
+                .retain(|_, used_at| now - *used_at <= trip_break);
+            self.last_traces_pruned_at = now;
+        }
+
+        if let Some((last_trace, checked_at)) = self.last_traces.get(&user_id) {
+            let seconds = (now - *checked_at).num_milliseconds() as f64 / 1000.0;
+            let distance_meters = Point::new(last_trace.location.0, last_trace.location.1)
+                .geodesic_distance(&Point::new(trace.location.0, trace.location.1));
+            let max_distance_meters =
+                TELEPORT_SLACK_METERS + CONFIG.max_plausible_speed_meters_per_second * seconds;
+            if distance_meters > max_distance_meters {
+                return Err(TraceError::Teleported {
+                    distance_meters,
+                    seconds,
+                });
+            }
+        }
+        self.last_traces.insert(user_id, (trace.clone(), now));
+
+        Ok(())
+    }
+
+    // current username of the user, a new random one if they have none yet or their trip ended
+    pub fn username(&mut self, user_id: Uuid) -> String {
+        let now = Utc::now();
+        if !self.trip_ended(user_id, now) {
+            match self.store.username(user_id) {
+                Ok(Some(username)) => {
+                    self.continue_trip(user_id, now);
+                    return username;
+                }
+                Ok(None) => {}
+                Err(err) => log::error!("couldn't get username, error: {}", err),
+            }
+        }
+
+        self.reroll_username(user_id)
+    }
+
+    // replaces the user's username with a new random one, unique among the users they may chat
+    // with unless all attempts are taken
+    pub fn reroll_username(&mut self, user_id: Uuid) -> String {
+        let mut username = "anonymous".to_string();
+        for candidate in Generator::default().take(MAX_REROLL_ATTEMPTS) {
+            username = candidate;
+            if !self.is_username_taken(user_id, &username) {
+                break;
+            }
+        }
+        self.save_username(user_id, &username);
+
+        username
+    }
+
+    // username chosen by the user, must be unique among the users they may chat with
+    pub fn set_username(&mut self, user_id: Uuid, username: &str) -> Result<String, UsernameError> {
+        let username = validate_username(username)?;
+        if self.is_username_taken(user_id, &username) {
+            return Err(UsernameError::Taken);
+        }
+
+        self.save_username(user_id, &username);
+        Ok(username)
+    }
+
+    // whether anyone the user may chat with has the username, ignoring case
+    fn is_username_taken(&self, user_id: Uuid, username: &str) -> bool {
+        self.nearby_user_ids(user_id).into_iter().any(|other| {
+            matches!(
+                self.store.username(other),
+                Ok(Some(other_username)) if other_username.to_lowercase() == username.to_lowercase()
+            )
+        })
+    }
+
+    // whether the message is one of those `get_messages` returns to the user, e.g. to reply to it
+    pub fn is_visible(&mut self, id: Uuid, user_id: Uuid, trace: &Trace) -> bool {
+        let Some(msg) = self.message(id) else {
+            return false;
         };
+        if msg.is_hidden() {
+            return false;
+        }
+
+        trace.overlaps_with(&msg.trace)
+            || self
+                .join_room(user_id, trace)
+                .is_some_and(|membership| membership.contains(&msg))
+    }
+
+    // moves the user to the room matching their trace, returns their membership if any
+    pub fn join_room(&mut self, user_id: Uuid, trace: &Trace) -> Option<Membership> {
+        self.rooms.update(user_id, trace, Utc::now())
+    }
+
+    // same as `get_messages`, but checks every message instead of only the ones nearby,
+    // serves as a reference for tests and benchmarks
+    pub fn get_messages_unindexed(
+        &mut self,
+        user_id: Option<Uuid>,
+        trace: Trace,
+    ) -> Vec<ChatMessageOut> {
+        self.delete_old_messages();
+
+        self.find_messages(None, user_id, &trace, None)
+    }
+
+    pub fn vote_message(&mut self, id: Uuid, user_id: Uuid, up: bool) -> Option<ChatMessage> {
+        let mut msg = self.message(id)?;
 
         if up {
             if msg.upvoters.contains(&user_id) {
@@ -205,11 +717,576 @@ impl Plane {
             msg.downvoters.insert(user_id);
             msg.upvoters.remove(&user_id);
         }
+
+        self.update_reputation(&msg);
+        self.save_message(msg)
+    }
+
+    // toggles the user's reaction, returns `None` for unknown messages and emojis
+    pub fn react_message(&mut self, id: Uuid, user_id: Uuid, emoji: &str) -> Option<ChatMessage> {
+        if !REACTIONS.contains(&emoji) {
+            log::warn!("unknown reaction: {}", emoji);
+            return None;
+        }
+        let mut msg = self.message(id)?;
+
+        let reacted = msg.reacted(user_id, emoji);
+        msg.set_reaction(user_id, emoji, !reacted);
+        self.save_message(msg)
+    }
+
+    // adds a message to the reports waiting for review, returns false if the reporter can't see
+    // the message at their last trace, already reported it or has too many reports waiting
+    pub fn report_message(&mut self, id: Uuid, reporter: Uuid, reason: &str) -> bool {
+        let Some((trace, _)) = self.last_traces.get(&reporter).cloned() else {
+            return false;
+        };
+        if !self.is_visible(id, reporter, &trace) {
+            return false;
+        }
+        let Some(msg) = self.message(id) else {
+            return false;
+        };
+        let reports_by_reporter = self
+            .reports
+            .iter()
+            .flat_map(|report| report.reasons.iter())
+            .filter(|(user_id, _)| *user_id == reporter)
+            .count();
+        if reports_by_reporter >= MAX_REPORTS_PER_REPORTER {
+            return false;
+        }
+        let reason: String = reason
+            .trim()
+            .chars()
+            .take(CONFIG.max_message_length)
+            .collect();
+        let full = self.reports.len() >= MAX_REPORTS;
+
+        match self
+            .reports
+            .iter_mut()
+            .find(|report| report.message.id == id)
+        {
+            Some(report) => {
+                if report
+                    .reasons
+                    .iter()
+                    .any(|(user_id, _)| *user_id == reporter)
+                {
+                    return false;
+                }
+                report.reasons.push((reporter, reason));
+                report.message = msg;
+            }
+            // waiting reports are kept until they are reviewed or their message expires
+            None if full => return false,
+            None => self.reports.push_back(Report {
+                message: msg,
+                reasons: vec![(reporter, reason)],
+                reported_at: Utc::now(),
+            }),
+        }
+
+        true
+    }
+
+    // reports waiting for review, oldest first
+    pub fn reports(&self) -> Vec<Report> {
+        self.reports.iter().cloned().collect()
+    }
+
+    // deletes a message and its report
+    // returns a tombstone of the message to remove it from clients, `None` if there was none
+    pub fn delete_message(&mut self, id: Uuid) -> Option<ChatMessage> {
+        let msg = self.message(id);
+        if let Err(err) = self.store.delete_message(id) {
+            log::error!("couldn't delete message with id: {}, error: {}", id, err);
+        }
+        self.reports.retain(|report| report.message.id != id);
+
+        msg.map(ChatMessage::into_tombstone)
+    }
+
+    // changes the text of a message, only by its author and only shortly after sending it
+    pub fn edit_message(
+        &mut self,
+        id: Uuid,
+        user_id: Uuid,
+        text: String,
+    ) -> Result<ChatMessage, ChangeMessageError> {
+        let mut msg = self.own_message(id, user_id)?;
+        let text = clean_text(text);
+        if text.is_empty() {
+            return Err(ChangeMessageError::EmptyText);
+        }
+        let edited_at = Utc::now();
+        if edited_at - msg.timestamp > Duration::seconds(CONFIG.edit_message_window_seconds) {
+            return Err(ChangeMessageError::TooLate);
+        }
+
+        msg.text = text;
+        msg.edited_at = Some(edited_at);
+        self.save_message(msg).ok_or(ChangeMessageError::NotFound)
+    }
+
+    // deletes a message by its author, returns its tombstone
+    pub fn delete_own_message(
+        &mut self,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<ChatMessage, ChangeMessageError> {
+        self.own_message(id, user_id)?;
+
+        self.delete_message(id).ok_or(ChangeMessageError::NotFound)
+    }
+
+    // opens a conversation with the author of a message the user can see,
+    // returns its id and the author's id
+    pub fn open_conversation(
+        &mut self,
+        user_id: Uuid,
+        message_id: Uuid,
+        trace: &Trace,
+        public_key: PublicKey,
+    ) -> Result<(Uuid, Uuid), ConversationError> {
+        if !self.is_visible(message_id, user_id, trace) {
+            return Err(ConversationError::NotVisible);
+        }
+        let msg = self
+            .message(message_id)
+            .ok_or(ConversationError::NotVisible)?;
+        let id = self.conversations.open(user_id, msg.author, Utc::now())?;
+        self.conversations.publish_key(user_id, public_key);
+        // the author's key from their message, unless they published a newer one
+        if let Some(author_key) = msg.public_key {
+            if self.conversations.public_key(msg.author).is_none() {
+                self.conversations.publish_key(msg.author, author_key);
+            }
+        }
+        // the author sees the user's username, even if the user never sent a message
+        self.username(user_id);
+
+        Ok((id, msg.author))
+    }
+
+    // adds a message encrypted in the user's browser, only its length can be checked
+    // returns the message and both users of the conversation
+    pub fn send_direct_message(
+        &mut self,
+        id: Uuid,
+        user_id: Uuid,
+        sealed: Sealed,
+    ) -> Result<(DirectMessage, (Uuid, Uuid)), ConversationError> {
+        if sealed.text_length() == 0 {
+            return Err(ConversationError::EmptyText);
+        }
+        if sealed.text_length() > CONFIG.max_message_length {
+            return Err(ConversationError::TooLong);
+        }
+
+        let msg = self
+            .conversations
+            .add_message(id, DirectMessage::new(user_id, sealed))?;
+        let user_ids = self
+            .conversations
+            .user_ids(id)
+            .ok_or(ConversationError::NotFound)?;
+
+        Ok((msg, user_ids))
+    }
+
+    // direct message sent on another instance, returns both users of the conversation
+    pub fn receive_direct_message(&mut self, id: Uuid, msg: DirectMessage) -> Option<(Uuid, Uuid)> {
+        if let Err(err) = self.conversations.add_message(id, msg) {
+            log::warn!(
+                "couldn't add direct message to conversation {}, error: {}",
+                id,
+                err
+            );
+            return None;
+        }
+
+        self.conversations.user_ids(id)
+    }
+
+    // key of the user's browser session for their conversations, returns whether it changed
+    pub fn publish_key(&mut self, user_id: Uuid, public_key: PublicKey) -> bool {
+        self.conversations.publish_key(user_id, public_key)
+    }
+
+    pub fn public_key(&self, user_id: Uuid) -> Option<PublicKey> {
+        self.conversations.public_key(user_id)
+    }
+
+    pub fn conversation(
+        &mut self,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<ConversationOut, ConversationError> {
+        self.delete_old_messages();
+
+        self.conversations
+            .conversation(id, user_id, |other| self.stored_username(other))
+    }
+
+    // the user's conversations with only their newest message, most recently active first
+    pub fn conversations(&mut self, user_id: Uuid) -> Vec<ConversationOut> {
+        self.delete_old_messages();
+
+        self.conversations
+            .conversations(user_id, |other| self.stored_username(other))
+    }
+
+    // blocks or unblocks the other user of a conversation, returns their id
+    pub fn block_conversation(
+        &mut self,
+        id: Uuid,
+        user_id: Uuid,
+        blocked: bool,
+    ) -> Result<Uuid, ConversationError> {
+        self.conversations.block(id, user_id, blocked)
+    }
+
+    // stops an author from sending messages until the server restarts, their reports are done then
+    pub fn ban_author(&mut self, author: Uuid) {
+        self.banned_author_ids.insert(author);
+        self.reports
+            .retain(|report| report.message.author != author);
+    }
+
+    pub fn is_banned(&self, user_id: Uuid) -> bool {
+        self.banned_author_ids.contains(&user_id)
+    }
+
+    // numbers and config for the admin page
+    pub fn stats(&mut self) -> PlaneStats {
+        self.delete_old_messages();
+
+        let now = Utc::now();
+        let message_stats = match self
+            .store
+            .stats(now - Duration::minutes(STATS_RATE_MINUTES))
+        {
+            Ok(message_stats) => message_stats,
+            Err(err) => {
+                log::error!("couldn't get message stats, error: {}", err);
+                MessageStats::default()
+            }
+        };
+        let (rooms, shared_rooms) = self.rooms.counts(now);
+        let oldest = now - Duration::seconds(LAST_TRACE_MAX_AGE_SECONDS);
+        let users = self
+            .last_traces
+            .values()
+            .filter(|(_, checked_at)| *checked_at >= oldest)
+            .count();
+        let estimated_memory_bytes = message_stats.estimated_bytes
+            + self.last_traces.len() * std::mem::size_of::<(Uuid, (Trace, DateTime<Utc>))>()
+            + self.reports.len() * std::mem::size_of::<Report>()
+            + self.author_reputations_by_id.len() * std::mem::size_of::<(Uuid, Reputation)>();
+
+        PlaneStats {
+            messages: message_stats.messages,
+            authors: message_stats.authors,
+            messages_per_minute: message_stats.recent_messages as f64 / STATS_RATE_MINUTES as f64,
+            rooms,
+            shared_rooms,
+            users,
+            reports: self.reports.len(),
+            muted_authors: self
+                .author_reputations_by_id
+                .values()
+                .filter(|reputation| reputation.muted)
+                .count(),
+            banned_authors: self.banned_author_ids.len(),
+            conversations: self.conversations.len(),
+            estimated_memory_bytes,
+            config: format!("{:#?}", *CONFIG),
+        }
+    }
+
+    // authors are muted after too many of their messages got hidden
+    pub fn is_muted(&self, user_id: Uuid) -> bool {
+        self.author_reputations_by_id
+            .get(&user_id)
+            .is_some_and(|reputation| reputation.muted)
+    }
+
+    // applies a change made on another instance, returns the changed message
+    pub fn apply_event(&mut self, event: PlaneEvent) -> Option<ChatMessage> {
+        match event {
+            PlaneEvent::MessageAdded(mut msg) => {
+                // rooms are local to each instance
+                msg.room_id = self.rooms.find(&msg.trace, Utc::now());
+
+                // instances may have generated different usernames for the same author before
+                // they saw each other's messages, the alphabetically first one wins everywhere,
+                // unless the author started a new trip
+                let trip_ended = self.trip_ended(msg.author, Utc::now());
+                match self.store.username(msg.author) {
+                    Ok(Some(username)) if username <= msg.username && !trip_ended => {}
+                    Ok(_) => self.save_username(msg.author, &msg.username),
+                    Err(err) => log::error!("couldn't get username, error: {}", err),
+                }
+
+                self.save_message(*msg)
+            }
+            PlaneEvent::Voted { id, user_id, vote } => {
+                let mut msg = self.message(id)?;
+
+                msg.upvoters.remove(&user_id);
+                msg.downvoters.remove(&user_id);
+                match vote {
+                    Some(Vote::Up) => {
+                        msg.upvoters.insert(user_id);
+                    }
+                    Some(Vote::Down) => {
+                        msg.downvoters.insert(user_id);
+                    }
+                    None => {}
+                }
+
+                self.update_reputation(&msg);
+                self.save_message(msg)
+            }
+            PlaneEvent::Reacted {
+                id,
+                user_id,
+                emoji,
+                reacted,
+            } => {
+                // peers run the same code, but may be outdated or compromised
+                if !REACTIONS.contains(&emoji.as_str()) {
+                    log::warn!("unknown reaction from peer: {}", emoji);
+                    return None;
+                }
+                let mut msg = self.message(id)?;
+                msg.set_reaction(user_id, &emoji, reacted);
+
+                self.save_message(msg)
+            }
+            PlaneEvent::Edited {
+                id,
+                text,
+                edited_at,
+            } => {
+                let mut msg = self.message(id)?;
+                msg.text = text;
+                msg.edited_at = Some(edited_at);
+
+                self.save_message(msg)
+            }
+            PlaneEvent::Renamed { author, username } => {
+                // checked against the users nearby on the other instance, names chosen on two
+                // instances at once may still collide
+                self.save_username(author, &username);
+                None
+            }
+            PlaneEvent::MessageDeleted { id } => self.delete_message(id),
+            PlaneEvent::AuthorBanned { author } => {
+                self.ban_author(author);
+                None
+            }
+            PlaneEvent::ConversationOpened { id, user_ids } => {
+                self.conversations.insert(id, user_ids, Utc::now());
+                None
+            }
+            PlaneEvent::DirectMessageAdded { id, msg } => {
+                self.receive_direct_message(id, *msg);
+                None
+            }
+            PlaneEvent::KeyPublished {
+                user_id,
+                public_key,
+            } => {
+                self.conversations.publish_key(user_id, public_key);
+                None
+            }
+            PlaneEvent::UserBlocked {
+                user_id,
+                other,
+                blocked,
+            } => {
+                self.conversations.set_blocked(user_id, other, blocked);
+                None
+            }
+        }
+    }
+
+    fn save_username(&mut self, user_id: Uuid, username: &str) {
+        if let Err(err) = self.store.save_username(user_id, username) {
+            log::error!("couldn't save username, error: {}", err);
+        }
+        self.usernames_used_at.insert(user_id, Utc::now());
+    }
+
+    // with usernames scoped per trip, whether the user made a break since using their username
+    fn trip_ended(&self, user_id: Uuid, now: DateTime<Utc>) -> bool {
+        CONFIG.username_scope == UsernameScope::Trip
+            && self
+                .usernames_used_at
+                .get(&user_id)
+                .is_none_or(|used_at| now - *used_at > Duration::minutes(CONFIG.trip_break_minutes))
+    }
+
+    // users keep their username while they read messages, even without sending any
+    fn continue_trip(&mut self, user_id: Uuid, now: DateTime<Utc>) {
+        if !self.trip_ended(user_id, now) {
+            self.usernames_used_at.insert(user_id, now);
+        }
+    }
+
+    // username of another user, without continuing their trip
+    fn stored_username(&self, user_id: Uuid) -> String {
+        match self.store.username(user_id) {
+            Ok(Some(username)) => username,
+            Ok(None) => "anonymous".to_string(),
+            Err(err) => {
+                log::error!("couldn't get username, error: {}", err);
+                "anonymous".to_string()
+            }
+        }
+    }
+
+    // other users in the user's room and authors of messages matching their last trace
+    fn nearby_user_ids(&self, user_id: Uuid) -> HashSet<Uuid> {
+        let mut user_ids: HashSet<Uuid> = self.rooms.room_mates(user_id).into_iter().collect();
+        if let Some((trace, _)) = self.last_traces.get(&user_id) {
+            let area = (trace.location, trace.match_radius_meters());
+            match self
+                .store
+                .find_messages(Some(area), &|msg| trace.overlaps_with(&msg.trace))
+            {
+                Ok(messages) => user_ids.extend(messages.iter().map(|msg| msg.author)),
+                Err(err) => log::error!("couldn't find messages, error: {}", err),
+            }
+        }
+        user_ids.remove(&user_id);
+
+        user_ids
+    }
+
+    // the user's own message, to change or delete it
+    pub fn own_message(&self, id: Uuid, user_id: Uuid) -> Result<ChatMessage, ChangeMessageError> {
+        let msg = self.message(id).ok_or(ChangeMessageError::NotFound)?;
+        if msg.author != user_id {
+            log::warn!(
+                "user {} tried to change message {} of another author",
+                user_id,
+                id
+            );
+            return Err(ChangeMessageError::NotAuthor);
+        }
+
+        Ok(msg)
+    }
+
+    fn message(&self, id: Uuid) -> Option<ChatMessage> {
+        match self.store.message(id) {
+            Ok(Some(msg)) => Some(msg),
+            Ok(None) => {
+                log::warn!("couldn't find message with id: {}", id);
+                None
+            }
+            Err(err) => {
+                log::error!("couldn't get message with id: {}, error: {}", id, err);
+                None
+            }
+        }
+    }
+
+    fn update_reputation(&mut self, msg: &ChatMessage) {
+        if msg.is_hidden() {
+            let reputation = self.author_reputations_by_id.entry(msg.author).or_default();
+            reputation.hidden_message_ids.insert(msg.id, msg.timestamp);
+            if !reputation.muted
+                && reputation.hidden_message_ids.len() >= CONFIG.mute_author_hidden_messages
+            {
+                log::info!("muted user {}", msg.author);
+                reputation.muted = true;
+            }
+        } else if let Some(reputation) = self.author_reputations_by_id.get_mut(&msg.author) {
+            reputation.hidden_message_ids.remove(&msg.id);
+        }
+    }
+
+    fn save_message(&mut self, msg: ChatMessage) -> Option<ChatMessage> {
+        if let Err(err) = self.store.save_message(&msg) {
+            log::error!("couldn't save message with id: {}, error: {}", msg.id, err);
+            return None;
+        }
+
+        Some(msg)
+    }
+
+    // newest 10000 messages overlapping with the given trace or posted to the room since joining,
+    // sorted from oldest to newest
+    fn find_messages(
+        &self,
+        area: Option<Area>,
+        user_id: Option<Uuid>,
+        trace: &Trace,
+        membership: Option<&Membership>,
+    ) -> Vec<ChatMessageOut> {
+        let mut messages = match self
+            .store
+            .find_messages(area, &|msg| trace.overlaps_with(&msg.trace))
+        {
+            Ok(messages) => messages,
+            Err(err) => {
+                log::error!("couldn't find messages, error: {}", err);
+                return vec![];
+            }
+        };
+
+        if let Some(membership) = membership {
+            match self
+                .store
+                .room_messages(&membership.room_ids, membership.joined_at)
+            {
+                Ok(room_messages) => {
+                    let ids: HashSet<Uuid> = messages.iter().map(|msg| msg.id).collect();
+                    messages.extend(
+                        room_messages
+                            .into_iter()
+                            .filter(|msg| !ids.contains(&msg.id)),
+                    );
+                }
+                Err(err) => log::error!("couldn't get room messages, error: {}", err),
+            }
+        }
+        messages.retain(|msg| !msg.is_hidden());
+
+        messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(b.id.cmp(&a.id)));
+        messages.truncate(10000);
+        messages.reverse();
+
+        messages
+            .into_iter()
+            .map(|msg| ChatMessageOut::from((msg, user_id)))
+            .collect()
     }
 
     fn delete_old_messages(&mut self) {
-        self.messages.retain(|msg| {
-            (Utc::now() - msg.timestamp).num_minutes() < CONFIG.max_message_age_minutes
+        let now = Utc::now();
+        let oldest_timestamp = now - Duration::minutes(CONFIG.max_message_age_minutes);
+        if let Err(err) = self.store.delete_messages_before(oldest_timestamp) {
+            log::error!("couldn't delete old messages, error: {}", err);
+        }
+        if let Err(err) = self.store.delete_expired_messages(now) {
+            log::error!("couldn't delete expired messages, error: {}", err);
+        }
+        self.conversations.delete_messages_before(oldest_timestamp);
+        // reports hold a copy of the message, which must not outlive it
+        self.reports
+            .retain(|report| report.message.expires_at() > now);
+        // muted authors stay muted, the others are forgotten once their hidden messages are gone
+        self.author_reputations_by_id.retain(|_, reputation| {
+            reputation
+                .hidden_message_ids
+                .retain(|_, timestamp| *timestamp >= oldest_timestamp);
+            reputation.muted || !reputation.hidden_message_ids.is_empty()
         });
     }
 }
@@ -218,32 +1295,51 @@ impl Plane {
 pub struct ChatMessageIn {
     pub id: Uuid,
     pub author: Uuid,
-    pub username: Option<String>,
     pub text: String,
     pub trace: Trace,
+    // message this one answers, must be visible to the author
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
+    // key of the author's browser session, to send them encrypted direct messages
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+    // one of `message_ttl_options_minutes`, to delete the message before the max. age
+    #[serde(default)]
+    pub ttl_minutes: Option<i64>,
     pub timestamp: DateTime<Utc>,
 }
 
 impl ChatMessageIn {
-    pub fn new(author: Uuid, mut text: String, trace: Trace) -> Self {
-        if text.len() > CONFIG.max_message_length {
-            log::warn!("message too long: {}", text.len());
-            text.truncate(CONFIG.max_message_length);
-        }
-
-        let text = text.trim().to_string();
+    pub fn new(author: Uuid, text: String, trace: Trace) -> Self {
+        let text = clean_text(text);
 
         Self {
             id: Uuid::new_v4(),
             author,
-            username: None,
             text,
             trace,
+            reply_to: None,
+            public_key: None,
+            ttl_minutes: None,
             timestamp: Utc::now(),
         }
     }
 }
 
+// cuts the text to the max. message length and trims it, without splitting a character
+fn clean_text(mut text: String) -> String {
+    if text.len() > CONFIG.max_message_length {
+        log::warn!("message too long: {}", text.len());
+        let mut end = CONFIG.max_message_length;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+
+    text.trim().to_string()
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: Uuid,
@@ -251,9 +1347,27 @@ pub struct ChatMessage {
     pub username: String,
     pub text: String,
     pub trace: Trace,
+    // room of the author when posting, if they were in one
+    #[serde(default)]
+    pub room_id: Option<Uuid>,
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
     pub upvoters: HashSet<Uuid>,
     pub downvoters: HashSet<Uuid>,
+    // users by emoji, only emojis in `REACTIONS`
+    #[serde(default)]
+    pub reactions: HashMap<String, HashSet<Uuid>>,
     pub timestamp: DateTime<Utc>,
+    // set if the author chose to delete the message before the max. age
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    // tombstones are never stored, only sent to remove deleted messages from clients
+    #[serde(default)]
+    pub deleted: bool,
 }
 
 impl From<(ChatMessageIn, String)> for ChatMessage {
@@ -264,44 +1378,177 @@ impl From<(ChatMessageIn, String)> for ChatMessage {
             username,
             text: msg.text,
             trace: msg.trace,
+            room_id: None,
+            reply_to: msg.reply_to,
+            public_key: msg.public_key,
             upvoters: HashSet::new(),
             downvoters: HashSet::new(),
+            reactions: HashMap::new(),
             timestamp: msg.timestamp,
+            expires_at: msg
+                .ttl_minutes
+                .map(|ttl_minutes| msg.timestamp + Duration::minutes(ttl_minutes)),
+            edited_at: None,
+            deleted: false,
         }
     }
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
-pub struct ChatMessageOut {
+// what the server pushes via websocket
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Push {
+    // new or changed messages matching the user's trace or room
+    Messages(Vec<ChatMessageOut>),
+    // one of the user's conversations has a new message
+    DirectMessage,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChatMessageOut {
     pub id: Uuid,
     pub username: String,
     pub text: String,
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
+    // key of the author's browser session when posting
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
     pub upvoters: usize,
     pub downvoters: usize,
     pub vote: Option<Vote>,
+    // amount of users by emoji
+    #[serde(default)]
+    pub reactions: BTreeMap<String, usize>,
+    // emojis the user reacted with
+    #[serde(default)]
+    pub own_reactions: BTreeSet<String>,
     pub timestamp: DateTime<Utc>,
+    // when the message is deleted on the server
+    #[serde(default)]
+    pub expires_at: DateTime<Utc>,
+    // seconds until then when the server sent the message, clients count down from these, as
+    // their clock may be off
+    #[serde(default)]
+    pub expires_in_seconds: i64,
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    // whether the user wrote the message
+    #[serde(default)]
+    pub own: bool,
+    // hidden and deleted messages are only sent to remove them from clients
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+impl ChatMessage {
+    pub fn vote(&self, user_id: Uuid) -> Option<Vote> {
+        if self.upvoters.contains(&user_id) {
+            Some(Vote::Up)
+        } else if self.downvoters.contains(&user_id) {
+            Some(Vote::Down)
+        } else {
+            None
+        }
+    }
+
+    // same message without its text, marked as deleted
+    pub fn into_tombstone(self) -> Self {
+        Self {
+            text: "".to_string(),
+            reply_to: None,
+            upvoters: HashSet::new(),
+            downvoters: HashSet::new(),
+            reactions: HashMap::new(),
+            edited_at: None,
+            deleted: true,
+            ..self
+        }
+    }
+
+    pub fn reacted(&self, user_id: Uuid, emoji: &str) -> bool {
+        self.reactions
+            .get(emoji)
+            .is_some_and(|users| users.contains(&user_id))
+    }
+
+    // adds or removes the user's reaction, emojis without users are removed
+    fn set_reaction(&mut self, user_id: Uuid, emoji: &str, reacted: bool) {
+        if reacted {
+            self.reactions
+                .entry(emoji.to_string())
+                .or_default()
+                .insert(user_id);
+        } else if let Some(users) = self.reactions.get_mut(emoji) {
+            users.remove(&user_id);
+            if users.is_empty() {
+                self.reactions.remove(emoji);
+            }
+        }
+    }
+
+    // deleted at its own expiry or once it reaches the max. age, whichever comes first
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        let max_expires_at = self.timestamp + Duration::minutes(CONFIG.max_message_age_minutes);
+        self.expires_at
+            .map_or(max_expires_at, |expires_at| expires_at.min(max_expires_at))
+    }
+
+    // hidden from everyone once enough users voted and most of them downvoted
+    pub fn is_hidden(&self) -> bool {
+        let voters = self.upvoters.len() + self.downvoters.len();
+        voters > 0
+            && voters >= CONFIG.hide_message_min_voters
+            && self.downvoters.len() as f64 / voters as f64 >= CONFIG.hide_message_downvote_ratio
+    }
 }
 
 impl From<(ChatMessage, Option<Uuid>)> for ChatMessageOut {
     fn from((msg, user_id): (ChatMessage, Option<Uuid>)) -> Self {
-        let vote = user_id.and_then(|user_id| {
-            if msg.upvoters.contains(&user_id) {
-                Some(Vote::Up)
-            } else if msg.downvoters.contains(&user_id) {
-                Some(Vote::Down)
-            } else {
-                None
-            }
-        });
+        // clients only remove hidden messages, so none of their content is sent
+        if msg.is_hidden() {
+            return Self {
+                id: msg.id,
+                timestamp: msg.timestamp,
+                hidden: true,
+                ..Default::default()
+            };
+        }
+
+        let vote = user_id.and_then(|user_id| msg.vote(user_id));
+        let own = user_id == Some(msg.author);
+        let expires_at = msg.expires_at();
+        let reactions = msg
+            .reactions
+            .iter()
+            .map(|(emoji, users)| (emoji.clone(), users.len()))
+            .collect();
+        let own_reactions = msg
+            .reactions
+            .iter()
+            .filter(|(_, users)| user_id.is_some_and(|user_id| users.contains(&user_id)))
+            .map(|(emoji, _)| emoji.clone())
+            .collect();
 
         Self {
             id: msg.id,
             username: msg.username,
             text: msg.text,
+            reply_to: msg.reply_to,
+            public_key: msg.public_key,
             upvoters: msg.upvoters.len(),
             downvoters: msg.downvoters.len(),
             vote,
+            reactions,
+            own_reactions,
             timestamp: msg.timestamp,
+            expires_at,
+            expires_in_seconds: (expires_at - Utc::now()).num_seconds().max(0),
+            edited_at: msg.edited_at,
+            own,
+            hidden: false,
+            deleted: msg.deleted,
         }
     }
 }
@@ -312,23 +1559,205 @@ pub enum Vote {
     Down,
 }
 
+// changes to a plane that are shared with the planes of other instances
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PlaneEvent {
+    MessageAdded(Box<ChatMessage>),
+    // the vote of a user on a message after they voted
+    Voted {
+        id: Uuid,
+        user_id: Uuid,
+        vote: Option<Vote>,
+    },
+    // whether a user reacted with an emoji after they toggled it
+    Reacted {
+        id: Uuid,
+        user_id: Uuid,
+        emoji: String,
+        reacted: bool,
+    },
+    // new text of a message edited by its author
+    Edited {
+        id: Uuid,
+        text: String,
+        edited_at: DateTime<Utc>,
+    },
+    // username chosen or re-rolled by a user
+    Renamed {
+        author: Uuid,
+        username: String,
+    },
+    // deleted by an admin or its author
+    MessageDeleted {
+        id: Uuid,
+    },
+    AuthorBanned {
+        author: Uuid,
+    },
+    ConversationOpened {
+        id: Uuid,
+        user_ids: (Uuid, Uuid),
+    },
+    DirectMessageAdded {
+        id: Uuid,
+        msg: Box<DirectMessage>,
+    },
+    KeyPublished {
+        user_id: Uuid,
+        public_key: PublicKey,
+    },
+    // whether a user blocked the other user of a conversation after they toggled it
+    UserBlocked {
+        user_id: Uuid,
+        other: Uuid,
+        blocked: bool,
+    },
+}
+
+// numbers about a plane for the admin page
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlaneStats {
+    pub messages: usize,
+    // distinct authors of the stored messages
+    pub authors: usize,
+    pub messages_per_minute: f64,
+    pub rooms: usize,
+    // rooms with more than one user, e.g. vehicles with users chatting
+    pub shared_rooms: usize,
+    // users who sent a trace in the last minutes
+    pub users: usize,
+    pub reports: usize,
+    pub muted_authors: usize,
+    pub banned_authors: usize,
+    pub conversations: usize,
+    // messages, traces, reports and reputations, without rooms and allocator overhead
+    pub estimated_memory_bytes: usize,
+    // current config values, as logged at startup
+    pub config: String,
+}
+
+// a message reported by users for breaking the rules, reviewed on the admin page
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Report {
+    // the message when it was last reported
+    pub message: ChatMessage,
+    // reporters and their reasons
+    pub reasons: Vec<(Uuid, String)>,
+    // first report
+    pub reported_at: DateTime<Utc>,
+}
+
+// why a username chosen by a user was rejected
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum UsernameError {
+    #[error("Usernames need 3 to 24 letters, digits, spaces, dots, dashes or underscores.")]
+    Invalid,
+    #[error("Someone you are chatting with already uses this name.")]
+    Taken,
+    #[error("This name is not allowed, please follow the rules.")]
+    NotAllowed,
+}
+
+// trims the username and collapses whitespace, rejects names too short, too long or with
+// characters that could be mistaken for formatting
+pub fn validate_username(username: &str) -> Result<String, UsernameError> {
+    let username = username.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let length = username.chars().count();
+    let valid = (MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length)
+        && username
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '.' | '-' | '_'));
+
+    if valid {
+        Ok(username)
+    } else {
+        Err(UsernameError::Invalid)
+    }
+}
+
+// why an author couldn't edit or delete their message
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum ChangeMessageError {
+    #[error("This message doesn't exist anymore.")]
+    NotFound,
+    #[error("You can only change your own messages.")]
+    NotAuthor,
+    #[error("Messages can only be edited in the first minutes after sending them.")]
+    TooLate,
+    #[error("A message can't be empty, delete it instead.")]
+    EmptyText,
+}
+
+// why a trace sent by a client was rejected
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum TraceError {
+    #[error("Your location is invalid.")]
+    InvalidLocation,
+    #[error("Your speed is invalid.")]
+    InvalidSpeed,
+    #[error("Your direction of travel is invalid.")]
+    InvalidBearing,
+    #[error("Your speed of {speed:.1} m/s is above the maximum of {max_speed:.1} m/s.")]
+    TooFast { speed: f64, max_speed: f64 },
+    #[error("You moved {distance_meters:.0} m in {seconds:.0} s, that's too fast.")]
+    Teleported { distance_meters: f64, seconds: f64 },
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Trace {
-    pub location: (f64, f64), // lat, lon
-    pub speed: f64,           // meters per second
-    pub slope: f64,           // degrees
+    #[serde(deserialize_with = "deserialize_location")]
+    pub location: (f64, f64), // lon, lat
+    pub speed: f64,   // meters per second
+    pub bearing: f64, // degrees clockwise from north, 0 to 360
+}
+
+// json has no NaN and writes null instead, read it back as NaN, so stored messages with an
+// invalid location can still be parsed
+fn deserialize_location<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<(f64, f64), D::Error> {
+    let (lon, lat) = <(Option<f64>, Option<f64>)>::deserialize(deserializer)?;
+
+    Ok((lon.unwrap_or(f64::NAN), lat.unwrap_or(f64::NAN)))
 }
 
 impl Trace {
-    pub fn new(location: (f64, f64), speed: f64, slope: f64) -> Self {
+    pub fn new(location: (f64, f64), speed: f64, bearing: f64) -> Self {
         Self {
             location,
             speed,
-            slope,
+            bearing,
         }
     }
 
-    fn overlaps_with(&self, other: &Self) -> bool {
+    // rejects traces no device could have sent
+    pub fn validate(&self) -> Result<(), TraceError> {
+        let (lon, lat) = self.location;
+        if !(-180.0..=180.0).contains(&lon) || !(-90.0..=90.0).contains(&lat) {
+            return Err(TraceError::InvalidLocation);
+        }
+        if !self.speed.is_finite() || self.speed < 0.0 {
+            return Err(TraceError::InvalidSpeed);
+        }
+        if self.speed > CONFIG.max_plausible_speed_meters_per_second {
+            return Err(TraceError::TooFast {
+                speed: self.speed,
+                max_speed: CONFIG.max_plausible_speed_meters_per_second,
+            });
+        }
+        if !(0.0..=360.0).contains(&self.bearing) {
+            return Err(TraceError::InvalidBearing);
+        }
+
+        Ok(())
+    }
+
+    // max. distance in meters between self and another trace to still overlap
+    pub fn match_radius_meters(&self) -> f64 {
+        self.speed * CONFIG.trace_match_max_move_seconds
+    }
+
+    pub fn overlaps_with(&self, other: &Self) -> bool {
         if self.speed < CONFIG.min_speed_meters_per_second
             || other.speed < CONFIG.min_speed_meters_per_second
         {
@@ -337,20 +1766,64 @@ impl Trace {
 
         let distance_meters = Point::new(self.location.0, self.location.1)
             .geodesic_distance(&Point::new(other.location.0, other.location.1));
-        // let speed_diff_meter_per_second = (self.speed - other.speed).abs();
-        let slope_diff = (other.slope - self.slope).abs();
+        let speed_diff_meters_per_second = (self.speed - other.speed).abs();
+        let bearing_diff = bearing_diff(self.bearing, other.bearing);
+
+        // match if distance diff is smaller than distance covered by self in x seconds
+        distance_meters < self.match_radius_meters()
+            // match if speed diff is small in absolute terms or relative to the higher speed
+            && speed_diff_meters_per_second <= self.max_speed_diff_meters_per_second(other)
+            // match if bearing diff is smaller than x degrees, more for lower speeds
+            && bearing_diff < self.max_bearing_diff_degrees(other)
+    }
+
+    fn max_speed_diff_meters_per_second(&self, other: &Self) -> f64 {
+        let relative = self.speed.max(other.speed) * CONFIG.trace_match_max_speed_diff_ratio;
+        CONFIG
+            .trace_match_max_speed_diff_meters_per_second
+            .max(relative)
+    }
 
-        // match if distance diff is smaller than distance covered by self in 2 minutes
-        distance_meters < self.speed * CONFIG.trace_match_max_move_seconds
-            // match if speed diff is smaller than 1 m/s
-            // && speed_diff_meter_per_second < 20.0 // TODO: think about that for a while
-            // match if slope diff is smaller than x degrees
-            && slope_diff < CONFIG.trace_match_max_slope_diff_degrees // TODO: maybe the allowed diff should be higher for lower speeds?
+    // the slower trace has the less precise bearing, so its speed decides
+    fn max_bearing_diff_degrees(&self, other: &Self) -> f64 {
+        // MIN_SPEED_METERS_PER_SECOND may be set to 0, don't divide by zero then
+        let speed = self.speed.min(other.speed).max(f64::EPSILON);
+        let factor =
+            (CONFIG.trace_match_bearing_reference_speed_meters_per_second / speed).max(1.0);
+        CONFIG.trace_match_max_bearing_diff_degrees * factor
     }
 }
 
+// smallest angle between two bearings in degrees, 0 to 180
+fn bearing_diff(a: f64, b: f64) -> f64 {
+    let diff = (a - b).rem_euclid(360.0);
+    diff.min(360.0 - diff)
+}
+
 #[cfg(test)]
 mod tests {
+    // empty plane and the trace of a train in hamburg heading east
+    fn plane_with_train() -> (super::Plane, super::Trace) {
+        (
+            super::Plane::new(),
+            super::Trace::new((10.006, 53.553), 30.0, 90.0),
+        )
+    }
+
+    // adds a message of the author at the trace
+    fn post(
+        plane: &mut super::Plane,
+        author: uuid::Uuid,
+        text: &str,
+        trace: &super::Trace,
+    ) -> super::ChatMessage {
+        plane.add_message(super::ChatMessageIn::new(
+            author,
+            text.to_string(),
+            trace.clone(),
+        ))
+    }
+
     #[test]
     fn overlaps_with() {
         use super::*;
@@ -367,25 +1840,109 @@ mod tests {
         let trace_b = Trace::new((0.0, 0.0), CONFIG.min_speed_meters_per_second - 1.0, 0.0);
         assert!(!trace_a.overlaps_with(&trace_b), "both have low speed");
 
-        // slope diff
+        // bearing diff, at a speed where the bearing diff isn't widened
 
-        let trace_a = Trace::new((0.0, 0.0), CONFIG.min_speed_meters_per_second + 1.0, 0.0);
-        let trace_b = Trace::new((0.0, 0.0), CONFIG.min_speed_meters_per_second + 1.0, 0.0);
-        assert!(trace_a.overlaps_with(&trace_b), "same slope");
-        let trace_a = Trace::new((0.0, 0.0), CONFIG.min_speed_meters_per_second + 1.0, 0.0);
+        let speed = CONFIG.trace_match_bearing_reference_speed_meters_per_second;
+        let trace_a = Trace::new((0.0, 0.0), speed, 0.0);
+        let trace_b = Trace::new((0.0, 0.0), speed, 0.0);
+        assert!(trace_a.overlaps_with(&trace_b), "same bearing");
+        let trace_a = Trace::new((0.0, 0.0), speed, 0.0);
         let trace_b = Trace::new(
             (0.0, 0.0),
-            CONFIG.min_speed_meters_per_second + 1.0,
-            CONFIG.trace_match_max_slope_diff_degrees - 1.0,
+            speed,
+            CONFIG.trace_match_max_bearing_diff_degrees - 1.0,
         );
-        assert!(trace_a.overlaps_with(&trace_b), "small slope diff");
-        let trace_a = Trace::new((0.0, 0.0), CONFIG.min_speed_meters_per_second + 1.0, 0.0);
+        assert!(trace_a.overlaps_with(&trace_b), "small bearing diff");
+        let trace_a = Trace::new((0.0, 0.0), speed, 0.0);
         let trace_b = Trace::new(
             (0.0, 0.0),
-            CONFIG.min_speed_meters_per_second + 1.0,
-            CONFIG.trace_match_max_slope_diff_degrees + 1.0,
+            speed,
+            CONFIG.trace_match_max_bearing_diff_degrees + 1.0,
+        );
+        assert!(!trace_a.overlaps_with(&trace_b), "big bearing diff");
+
+        let trace_a = Trace::new((0.0, 0.0), speed, 359.0);
+        let trace_b = Trace::new((0.0, 0.0), speed, 1.0);
+        assert!(
+            trace_a.overlaps_with(&trace_b),
+            "small bearing diff across north"
+        );
+        assert!(
+            trace_b.overlaps_with(&trace_a),
+            "small bearing diff across north, swapped"
+        );
+
+        let trace_a = Trace::new((0.0, 0.0), speed, 10.0);
+        let trace_b = Trace::new(
+            (0.0, 0.0),
+            speed,
+            360.0 + 10.0 - CONFIG.trace_match_max_bearing_diff_degrees - 1.0,
+        );
+        assert!(
+            !trace_a.overlaps_with(&trace_b),
+            "big bearing diff across north"
+        );
+
+        let trace_a = Trace::new((0.0, 0.0), speed, 0.0);
+        let trace_b = Trace::new((0.0, 0.0), speed, 180.0);
+        assert!(
+            !trace_a.overlaps_with(&trace_b),
+            "opposite directions, north and south"
+        );
+
+        let trace_a = Trace::new((0.0, 0.0), speed, 85.0);
+        let trace_b = Trace::new((0.0, 0.0), speed, 265.0);
+        assert!(
+            !trace_a.overlaps_with(&trace_b),
+            "opposite directions, east and west"
+        );
+
+        // bearing diff at low speed, bearings are less precise there
+
+        let slow_speed = CONFIG.trace_match_bearing_reference_speed_meters_per_second / 2.0;
+        let trace_a = Trace::new((0.0, 0.0), slow_speed, 0.0);
+        let trace_b = Trace::new(
+            (0.0, 0.0),
+            slow_speed,
+            CONFIG.trace_match_max_bearing_diff_degrees * 2.0 - 1.0,
+        );
+        assert!(
+            trace_a.overlaps_with(&trace_b),
+            "bearing diff allowed at low speed"
+        );
+        let trace_a = Trace::new((0.0, 0.0), speed, 0.0);
+        let trace_b = Trace::new(
+            (0.0, 0.0),
+            speed,
+            CONFIG.trace_match_max_bearing_diff_degrees * 2.0 - 1.0,
+        );
+        assert!(
+            !trace_a.overlaps_with(&trace_b),
+            "same bearing diff not allowed at higher speed"
+        );
+        let trace_a = Trace::new((0.0, 0.0), slow_speed, 0.0);
+        let trace_b = Trace::new(
+            (0.0, 0.0),
+            slow_speed,
+            CONFIG.trace_match_max_bearing_diff_degrees * 2.0 + 1.0,
+        );
+        assert!(
+            !trace_a.overlaps_with(&trace_b),
+            "big bearing diff at low speed"
+        );
+        let trace_a = Trace::new((0.0, 0.0), 0.0, 0.0);
+        assert!(
+            trace_a.max_bearing_diff_degrees(&trace_a).is_finite(),
+            "bearing diff tolerance at zero speed"
+        );
+
+        // same track, opposite directions, Hamburg Hbf -> Berliner Tor and back
+        let trace_a = Trace::new((9.994872, 53.552196), 12.0, 112.0);
+        let trace_b = Trace::new((9.995500, 53.552500), 12.0, 292.0);
+        assert!(
+            !trace_a.overlaps_with(&trace_b),
+            "same track, opposite directions"
         );
-        assert!(!trace_a.overlaps_with(&trace_b), "big slope diff");
 
         // distance diff
 
@@ -411,11 +1968,765 @@ mod tests {
             "bus, Europapassage -> Gurlittinsel"
         );
 
-        // TODO: add more tests
+        // speed diff
+
+        let cyclist_speed = 4.0;
+        let high_speed_train_speed = 70.0;
+        let trace_a = Trace::new((9.994872, 53.552196), cyclist_speed, 0.0);
+        let trace_b = Trace::new((9.994872, 53.552196), high_speed_train_speed, 0.0);
+        assert!(!trace_a.overlaps_with(&trace_b), "cyclist and ICE");
+        assert!(!trace_b.overlaps_with(&trace_a), "ICE and cyclist");
+
+        let bus_speed_on_highway = 25.0;
+        let trace_a = Trace::new((9.994872, 53.552196), bus_speed_on_highway, 90.0);
+        let trace_b = Trace::new((10.010000, 53.560000), bus_speed_on_highway - 4.0, 95.0);
+        assert!(trace_a.overlaps_with(&trace_b), "bus on highway, braking");
+        let trace_b = Trace::new((10.010000, 53.560000), bus_speed, 95.0);
+        assert!(
+            !trace_a.overlaps_with(&trace_b),
+            "bus on highway and bus in town"
+        );
+
+        let train_speed = 35.0;
+        let trace_a = Trace::new((9.994872, 53.552196), train_speed, 180.0);
+        let trace_b = Trace::new((9.990000, 53.540000), train_speed - 10.0, 175.0);
+        assert!(trace_a.overlaps_with(&trace_b), "train, slowing down");
+        let trace_b = Trace::new((9.990000, 53.540000), bus_speed_on_highway, 175.0);
+        assert!(
+            trace_a.overlaps_with(&trace_b),
+            "train and bus on highway alongside"
+        );
 
-        // let bus_speed_on_highway = 25.0;
-        // let train_speed = 35.0;
-        // let high_speed_train_speed = 75.0;
+        let high_speed_train_speed = 75.0;
+        let trace_a = Trace::new((13.369402, 52.525084), high_speed_train_speed, 300.0);
+        let trace_b = Trace::new((13.300000, 52.560000), high_speed_train_speed - 15.0, 305.0);
+        assert!(trace_a.overlaps_with(&trace_b), "ICE, speeds differ a bit");
+        let trace_b = Trace::new((13.300000, 52.560000), train_speed, 305.0);
+        assert!(!trace_a.overlaps_with(&trace_b), "ICE and train");
+        let trace_b = Trace::new((13.300000, 52.560000), bus_speed_on_highway, 305.0);
+        assert!(!trace_a.overlaps_with(&trace_b), "ICE and bus on highway");
+    }
+
+    #[test]
+    fn get_messages_with_and_without_grid() {
+        use super::*;
+
+        // deterministic pseudo random numbers in [0, 1)
+        let mut seed: u64 = 42;
+        let mut random = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+
+        let centers = [
+            (9.99, 53.55),   // hamburg
+            (179.99, -16.5), // fiji, close to the antimeridian
+            (-179.99, -16.5),
+            (15.6, 78.2), // svalbard
+            (0.0, 0.0),
+        ];
+
+        let mut plane = Plane::new();
+        for (i, center) in centers.iter().enumerate() {
+            for _ in 0..200 {
+                let location = (
+                    center.0 + (random() - 0.5) * 0.4,
+                    center.1 + (random() - 0.5) * 0.4,
+                );
+                let speed = CONFIG.min_speed_meters_per_second + random() * 80.0;
+                let bearing = random() * 360.0;
+                let trace = Trace::new(location, speed, bearing);
+                plane.add_message(ChatMessageIn::new(Uuid::new_v4(), i.to_string(), trace));
+            }
+        }
+
+        for center in centers.iter() {
+            for _ in 0..50 {
+                let location = (
+                    center.0 + (random() - 0.5) * 0.4,
+                    center.1 + (random() - 0.5) * 0.4,
+                );
+                let speed = CONFIG.min_speed_meters_per_second + random() * 80.0;
+                let trace = Trace::new(location, speed, 0.0);
+
+                let ids = |messages: Vec<ChatMessageOut>| {
+                    messages
+                        .into_iter()
+                        .map(|msg| msg.id)
+                        .collect::<Vec<Uuid>>()
+                };
+                assert_eq!(
+                    ids(plane.get_messages(None, trace.clone())),
+                    ids(plane.get_messages_unindexed(None, trace.clone())),
+                    "grid and full scan differ for {:?}",
+                    trace
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn get_newest_matching_messages() {
+        use super::*;
+
+        let mut plane = Plane::new();
+        let (hamburg_author, berlin_author) = (Uuid::new_v4(), Uuid::new_v4());
+        let hamburg = Trace::new((10.006, 53.553), 30.0, 90.0);
+        let berlin = Trace::new((13.377, 52.516), 30.0, 90.0);
+        let start = Utc::now() - Duration::seconds(60);
+        let add = |plane: &mut Plane, author, trace: &Trace, i: i64| {
+            let mut msg = ChatMessageIn::new(author, i.to_string(), trace.clone());
+            msg.timestamp = start + Duration::milliseconds(i);
+            plane.add_message(msg)
+        };
+
+        // the limit applies to matching messages, newer ones elsewhere don't push them out
+        let oldest = add(&mut plane, hamburg_author, &hamburg, 0);
+        for i in 1..=10000 {
+            add(&mut plane, berlin_author, &berlin, i);
+        }
+        let messages = plane.get_messages(None, hamburg.clone());
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, oldest.id);
+
+        for i in 10001..=20000 {
+            add(&mut plane, hamburg_author, &hamburg, i);
+        }
+        let messages = plane.get_messages(None, hamburg);
+        assert_eq!(messages.len(), 10000, "newest 10000 matching messages");
+        assert!(messages.iter().all(|msg| msg.id != oldest.id));
+        assert!(messages
+            .windows(2)
+            .all(|pair| pair[0].timestamp < pair[1].timestamp));
+    }
+
+    #[test]
+    fn stopped_at_station() {
+        use super::*;
+
+        let mut history = LocationHistory::new(CONFIG.clone());
+        let now = Utc::now();
+        let locations = |lons: Vec<f64>| {
+            lons.into_iter()
+                .enumerate()
+                .map(|(i, lon)| Fix {
+                    location: Point::new(lon, 53.55),
+                    accuracy_meters: 10.0,
+                    timestamp: now - Duration::seconds(i as i64 * 5),
+                })
+                .collect::<Vec<Fix>>()
+        };
+
+        // heading east at roughly 13 m/s
+        history.locations = locations(vec![10.003, 10.002, 10.001, 10.0]);
+        let trace = history.trace().expect("should be moving");
+
+        history.locations = locations(vec![10.003; 4]);
+        match history.trace() {
+            Err(NoTrace::Stopped {
+                trace: stopped_trace,
+                seconds_left,
+            }) => {
+                assert_eq!(stopped_trace.speed, trace.speed, "keeps speed");
+                assert_eq!(stopped_trace.bearing, trace.bearing, "keeps heading");
+                assert!(seconds_left > 0);
+            }
+            other => panic!("should be stopped, got {:?}", other),
+        }
+
+        history.last_trace = Some((trace, now - Duration::seconds(CONFIG.stop_grace_seconds)));
+        assert!(
+            matches!(history.trace(), Err(NoTrace::TooSlow { .. })),
+            "stopped for too long"
+        );
+    }
+
+    #[test]
+    fn location_history_filters_bad_fixes() {
+        use super::*;
+        use geo::GeodesicDestination;
+
+        let mut history = LocationHistory::new(CONFIG.clone());
+        let start = Utc::now() - Duration::seconds(20);
+        // heading north east at 20 m/s, with a few meters of noise
+        let noise = [3.0, -4.0, 2.0, -1.0, 4.0, -3.0];
+        let fix = |i: usize, accuracy_meters: f64| {
+            let location = Point::new(10.0, 53.55)
+                .geodesic_destination(45.0, 20.0 * i as f64 * 2.0 + noise[i % noise.len()]);
+            Fix {
+                location,
+                accuracy_meters,
+                timestamp: start + Duration::seconds(i as i64 * 2),
+            }
+        };
+
+        for i in 0..CONFIG.max_locations_in_history {
+            history.add_fix(fix(i, 10.0));
+        }
+        let i = CONFIG.max_locations_in_history;
+
+        history.add_fix(fix(i, CONFIG.max_location_accuracy_meters + 1.0));
+        assert_eq!(
+            history.locations[0].timestamp,
+            fix(i - 1, 10.0).timestamp,
+            "inaccurate fix dropped"
+        );
+
+        let mut jump = fix(i, 10.0);
+        jump.location = jump.location.geodesic_destination(90.0, 5000.0);
+        history.add_fix(jump);
+        assert_eq!(
+            history.locations[0].timestamp,
+            fix(i - 1, 10.0).timestamp,
+            "impossible jump dropped"
+        );
+
+        history.add_fix(fix(i, 10.0));
+        let trace = history.trace().expect("should be moving");
+        assert!((trace.speed - 20.0).abs() < 2.0, "speed: {}", trace.speed);
+        assert!(
+            bearing_diff(trace.bearing, 45.0) < 5.0,
+            "bearing: {}",
+            trace.bearing
+        );
+    }
+
+    #[test]
+    fn check_trace() {
+        use super::*;
+
+        let mut plane = Plane::new();
+        let user_id = Uuid::new_v4();
+        let hamburg = Trace::new((9.99, 53.55), 10.0, 90.0);
+
+        for (trace, expected) in [
+            (
+                Trace::new((f64::NAN, 53.55), 10.0, 90.0),
+                TraceError::InvalidLocation,
+            ),
+            (
+                Trace::new((9.99, 91.0), 10.0, 90.0),
+                TraceError::InvalidLocation,
+            ),
+            (
+                Trace::new((9.99, 53.55), -1.0, 90.0),
+                TraceError::InvalidSpeed,
+            ),
+            (
+                Trace::new((9.99, 53.55), f64::INFINITY, 90.0),
+                TraceError::InvalidSpeed,
+            ),
+            (
+                Trace::new((9.99, 53.55), 1e9, 90.0),
+                TraceError::TooFast {
+                    speed: 1e9,
+                    max_speed: CONFIG.max_plausible_speed_meters_per_second,
+                },
+            ),
+            (
+                Trace::new((9.99, 53.55), 10.0, 361.0),
+                TraceError::InvalidBearing,
+            ),
+        ] {
+            assert_eq!(plane.check_trace(Some(user_id), &trace), Err(expected));
+        }
+
+        assert_eq!(plane.check_trace(Some(user_id), &hamburg), Ok(()));
+        let nearby = Trace::new((9.991, 53.55), 10.0, 90.0);
+        assert_eq!(plane.check_trace(Some(user_id), &nearby), Ok(()));
+        let berlin = Trace::new((13.37, 52.52), 10.0, 90.0);
+        assert!(matches!(
+            plane.check_trace(Some(user_id), &berlin),
+            Err(TraceError::Teleported { .. })
+        ));
+        assert_eq!(
+            plane.check_trace(Some(Uuid::new_v4()), &berlin),
+            Ok(()),
+            "other user"
+        );
+        assert_eq!(plane.check_trace(None, &berlin), Ok(()), "anonymous user");
+    }
+
+    #[test]
+    fn get_messages_from_room() {
+        use super::*;
+
+        let mut plane = Plane::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let train = Trace::new((10.006, 53.553), 30.0, 90.0);
+        // 8 km further east, out of reach of traces at the start
+        let train_later = Trace::new((10.126, 53.553), 30.0, 90.0);
+
+        assert!(plane.get_messages(Some(alice), train.clone()).is_empty());
+        let msg = plane.add_message(ChatMessageIn::new(bob, "hi".to_string(), train.clone()));
+        assert!(msg.room_id.is_some(), "bob joined alice's room");
+
+        // travelling together, one kilometer at a time
+        for km in 1..=8 {
+            let trace = Trace::new((10.006 + km as f64 * 0.015, 53.553), 30.0, 90.0);
+            plane.join_room(bob, &trace);
+            plane.join_room(alice, &trace);
+        }
+        assert!(!train_later.overlaps_with(&msg.trace));
+        let messages = plane.get_messages(Some(alice), train_later.clone());
+        assert_eq!(
+            messages.len(),
+            1,
+            "room message, although traces don't match"
+        );
+        assert!(
+            plane.get_messages(None, train_later).is_empty(),
+            "not in the room"
+        );
+    }
+
+    #[test]
+    fn hide_messages_and_mute_authors() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (author, reader) = (Uuid::new_v4(), Uuid::new_v4());
+        let voters: Vec<Uuid> = (0..CONFIG.hide_message_min_voters)
+            .map(|_| Uuid::new_v4())
+            .collect();
+
+        for i in 0..CONFIG.mute_author_hidden_messages {
+            assert!(!plane.is_muted(author));
+            let msg = post(&mut plane, author, &format!("spam {}", i), &train);
+            for voter in voters.iter().skip(1) {
+                plane.vote_message(msg.id, *voter, false);
+            }
+            assert!(
+                plane
+                    .get_messages(Some(reader), train.clone())
+                    .iter()
+                    .any(|out| out.id == msg.id),
+                "not enough voters yet"
+            );
+
+            let msg = plane
+                .vote_message(msg.id, voters[0], false)
+                .expect("couldn't vote");
+            // also on later votes, e.g. the reader's one
+            let later = plane
+                .vote_message(msg.id, reader, true)
+                .expect("couldn't vote");
+            for msg in [msg.clone(), later] {
+                let out = ChatMessageOut::from((msg.clone(), Some(reader)));
+                assert!(out.hidden);
+                let json = serde_json::to_string(&out).expect("couldn't serialize message");
+                assert!(!json.contains("spam"), "no text");
+                assert!(!json.contains(&msg.username), "no username");
+            }
+            assert!(
+                !plane
+                    .get_messages(Some(reader), train.clone())
+                    .iter()
+                    .any(|out| out.id == msg.id),
+                "hidden"
+            );
+        }
+        assert!(plane.is_muted(author));
+        assert!(!plane.is_muted(reader));
+    }
+
+    #[test]
+    fn report_messages() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (author, reporter) = (Uuid::new_v4(), Uuid::new_v4());
+        let spam = post(&mut plane, author, "spam", &train);
+        let insult = post(&mut plane, author, "insult", &train);
+
+        let (other_reporter, far_away) = (Uuid::new_v4(), Uuid::new_v4());
+        for (user_id, trace) in [
+            (reporter, &train),
+            (other_reporter, &train),
+            (far_away, &Trace::new((13.377, 52.516), 30.0, 90.0)),
+        ] {
+            plane
+                .check_trace(Some(user_id), trace)
+                .expect("couldn't check trace");
+        }
+
+        assert!(!plane.report_message(Uuid::new_v4(), reporter, "no such message"));
+        assert!(!plane.report_message(spam.id, Uuid::new_v4(), "no trace"));
+        assert!(!plane.report_message(spam.id, far_away, "can't see it"));
+        assert!(plane.report_message(spam.id, reporter, "advertising"));
+        assert!(
+            !plane.report_message(spam.id, reporter, "advertising again"),
+            "once per reporter"
+        );
+        assert!(plane.report_message(spam.id, other_reporter, "spam"));
+        assert!(plane.report_message(insult.id, reporter, "rude"));
+        let reports = plane.reports();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].message.id, spam.id);
+        assert_eq!(reports[0].reasons.len(), 2);
+
+        for i in 2..MAX_REPORTS_PER_REPORTER {
+            let msg = post(&mut plane, author, &i.to_string(), &train);
+            assert!(plane.report_message(msg.id, reporter, "spam"));
+        }
+        let msg = post(&mut plane, author, "one too many", &train);
+        assert!(
+            !plane.report_message(msg.id, reporter, "spam"),
+            "too many reports waiting"
+        );
+        assert!(plane.report_message(msg.id, other_reporter, "spam"));
+        assert_eq!(plane.reports().len(), MAX_REPORTS_PER_REPORTER + 1);
+
+        plane.delete_message(spam.id);
+        assert_eq!(plane.reports().len(), MAX_REPORTS_PER_REPORTER);
+        assert!(plane
+            .get_messages(Some(reporter), train.clone())
+            .iter()
+            .all(|msg| msg.id != spam.id));
+
+        assert!(!plane.is_banned(author));
+        plane.ban_author(author);
+        assert!(plane.is_banned(author));
+        assert!(plane.reports().is_empty());
+    }
+
+    #[test]
+    fn plane_stats() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (author, reader, driver) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let car = Trace::new((9.935, 53.557), 15.0, 180.0);
+        for text in ["one", "two", "three"] {
+            post(&mut plane, author, text, &train);
+        }
+        for (user_id, trace) in [(reader, &train), (driver, &car)] {
+            assert!(plane.check_trace(Some(user_id), trace).is_ok());
+            plane.join_room(user_id, trace);
+        }
+        plane.ban_author(Uuid::new_v4());
+
+        let stats = plane.stats();
+        assert_eq!(stats.messages, 3);
+        assert_eq!(stats.authors, 1);
+        assert_eq!(
+            stats.messages_per_minute,
+            3.0 / STATS_RATE_MINUTES as f64,
+            "all messages are recent"
+        );
+        assert_eq!(stats.users, 2, "only users with a trace");
+        assert_eq!(stats.rooms, 2, "train and car");
+        assert_eq!(
+            stats.shared_rooms, 1,
+            "author and reader, the driver is alone"
+        );
+        assert_eq!(stats.banned_authors, 1);
+        assert!(stats.estimated_memory_bytes > 0);
+    }
+
+    #[test]
+    fn reply_to_visible_messages() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (author, replier) = (Uuid::new_v4(), Uuid::new_v4());
+        let other_train = Trace::new((13.377, 52.516), 30.0, 90.0);
+        let parent = post(
+            &mut plane,
+            author,
+            "anyone going to the dining car?",
+            &train,
+        );
+
+        assert!(plane.is_visible(parent.id, replier, &train));
+        assert!(!plane.is_visible(parent.id, replier, &other_train));
+        assert!(!plane.is_visible(Uuid::new_v4(), replier, &train));
+
+        let mut reply = ChatMessageIn::new(replier, "me".to_string(), train.clone());
+        reply.reply_to = Some(parent.id);
+        let reply = plane.add_message(reply);
+        let messages = plane.get_messages(Some(author), train.clone());
+        let reply = messages
+            .iter()
+            .find(|msg| msg.id == reply.id)
+            .expect("couldn't find reply");
+        assert_eq!(reply.reply_to, Some(parent.id));
+
+        for _ in 0..CONFIG.hide_message_min_voters {
+            plane.vote_message(parent.id, Uuid::new_v4(), false);
+        }
+        assert!(!plane.is_visible(parent.id, replier, &train), "hidden");
+    }
+
+    #[test]
+    fn cut_long_texts() {
+        use super::*;
+
+        let short = "a".repeat(CONFIG.max_message_length - 1);
+        assert_eq!(
+            clean_text(format!("{}☕ coffee", short)),
+            short,
+            "emoji at the end"
+        );
+        assert_eq!(clean_text(format!(" {} ", short)), short);
+    }
+
+    #[test]
+    fn react_to_messages() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let msg = post(&mut plane, alice, "we are crossing the elbe", &train);
+
+        assert!(
+            plane.react_message(msg.id, bob, "🦀").is_none(),
+            "unknown emoji"
+        );
+        assert!(plane.react_message(Uuid::new_v4(), bob, "👍").is_none());
+
+        plane.react_message(msg.id, alice, "👍");
+        plane.react_message(msg.id, bob, "👍");
+        let reacted = plane
+            .react_message(msg.id, bob, "🚆")
+            .expect("couldn't react");
+        let out = ChatMessageOut::from((reacted, Some(bob)));
+        assert_eq!(out.reactions.get("👍"), Some(&2));
+        assert_eq!(out.reactions.get("🚆"), Some(&1));
+        assert_eq!(
+            out.own_reactions,
+            BTreeSet::from(["👍".to_string(), "🚆".to_string()])
+        );
+
+        let toggled = plane
+            .react_message(msg.id, bob, "🚆")
+            .expect("couldn't react");
+        assert!(!toggled.reacted(bob, "🚆"));
+        assert!(!toggled.reactions.contains_key("🚆"), "no empty emojis");
+
+        let applied = plane
+            .apply_event(PlaneEvent::Reacted {
+                id: msg.id,
+                user_id: alice,
+                emoji: "👍".to_string(),
+                reacted: false,
+            })
+            .expect("couldn't apply event");
+        let out = ChatMessageOut::from((applied, Some(alice)));
+        assert_eq!(out.reactions.get("👍"), Some(&1));
+        assert!(out.own_reactions.is_empty());
+        assert!(
+            plane
+                .apply_event(PlaneEvent::Reacted {
+                    id: msg.id,
+                    user_id: alice,
+                    emoji: "🦀".to_string(),
+                    reacted: true,
+                })
+                .is_none(),
+            "unknown emoji from a peer"
+        );
+    }
+
+    #[test]
+    fn choose_usernames() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let other_train = Trace::new((13.377, 52.516), 30.0, 90.0);
+        post(&mut plane, alice, "hi", &train);
+        post(&mut plane, carol, "hello", &other_train);
+        plane
+            .check_trace(Some(bob), &train)
+            .expect("couldn't check trace");
+        plane
+            .check_trace(Some(carol), &other_train)
+            .expect("couldn't check trace");
+
+        assert_eq!(
+            validate_username("  Night   Owl "),
+            Ok("Night Owl".to_string())
+        );
+        for invalid in ["ab", "<b>owl</b>", &"o".repeat(MAX_USERNAME_LENGTH + 1)] {
+            assert_eq!(
+                validate_username(invalid),
+                Err(UsernameError::Invalid),
+                "{}",
+                invalid
+            );
+        }
+
+        let alice_username = plane.username(alice);
+        assert_eq!(
+            plane.set_username(bob, &alice_username.to_uppercase()),
+            Err(UsernameError::Taken)
+        );
+        assert_eq!(
+            plane.set_username(carol, &alice_username),
+            Ok(alice_username.clone()),
+            "alice is on another train"
+        );
+        assert_eq!(
+            plane.set_username(bob, "night owl"),
+            Ok("night owl".to_string())
+        );
+        let msg = post(&mut plane, bob, "hoo", &train);
+        assert_eq!(msg.username, "night owl");
+
+        let rerolled = plane.reroll_username(bob);
+        assert_ne!(rerolled, "night owl");
+        assert_eq!(plane.username(bob), rerolled);
+    }
+
+    #[test]
+    fn open_conversations_with_visible_authors() {
+        use super::*;
+        use crate::crypto::KeyPair;
+
+        let (mut plane, train) = plane_with_train();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let other_train = Trace::new((13.377, 52.516), 30.0, 90.0);
+        let (alice_keys, bob_keys) = (KeyPair::generate(), KeyPair::generate());
+        let mut alice_msg_in = ChatMessageIn::new(alice, "hi".to_string(), train.clone());
+        alice_msg_in.public_key = Some(alice_keys.public_key());
+        let alice_msg = plane.add_message(alice_msg_in);
+        let carol_msg = post(&mut plane, carol, "hello", &other_train);
+
+        assert_eq!(
+            plane.open_conversation(bob, carol_msg.id, &train, bob_keys.public_key()),
+            Err(ConversationError::NotVisible)
+        );
+        assert_eq!(
+            plane.open_conversation(alice, alice_msg.id, &train, alice_keys.public_key()),
+            Err(ConversationError::OwnMessage)
+        );
+        let (id, author) = plane
+            .open_conversation(bob, alice_msg.id, &train, bob_keys.public_key())
+            .expect("couldn't open conversation");
+        assert_eq!(author, alice);
+        assert_eq!(
+            plane.public_key(alice),
+            Some(alice_keys.public_key()),
+            "published with her message"
+        );
+
+        let sealed = bob_keys
+            .seal(alice_keys.public_key(), "hi alice")
+            .expect("couldn't seal message");
+        let (_, user_ids) = plane
+            .send_direct_message(id, bob, sealed)
+            .expect("couldn't send direct message");
+        assert!(user_ids == (alice, bob) || user_ids == (bob, alice));
+        let empty = bob_keys
+            .seal(alice_keys.public_key(), "")
+            .expect("couldn't seal message");
+        assert_eq!(
+            plane
+                .send_direct_message(id, bob, empty)
+                .map(|(msg, _)| msg.id),
+            Err(ConversationError::EmptyText)
+        );
+        let conversation = plane
+            .conversation(id, alice)
+            .expect("couldn't get conversation");
+        assert_eq!(conversation.username, plane.username(bob));
+        assert_eq!(conversation.public_key, Some(bob_keys.public_key()));
+        assert_eq!(
+            alice_keys.open(&conversation.messages[0].sealed),
+            Ok("hi alice".to_string())
+        );
+        assert!(plane.conversations(carol).is_empty());
+    }
+
+    #[test]
+    fn edit_and_delete_own_messages() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (author, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let msg = post(&mut plane, author, "my seat is 42 in car 7", &train);
+
+        assert_eq!(
+            plane.edit_message(msg.id, other, "mine".to_string()).err(),
+            Some(ChangeMessageError::NotAuthor)
+        );
+        assert_eq!(
+            plane.edit_message(msg.id, author, "  ".to_string()).err(),
+            Some(ChangeMessageError::EmptyText)
+        );
+        let edited = plane
+            .edit_message(msg.id, author, " my seat is in car 7 ".to_string())
+            .expect("couldn't edit message");
+        assert_eq!(edited.text, "my seat is in car 7");
+        assert!(edited.edited_at.is_some());
+
+        let mut old = ChatMessageIn::new(author, "old".to_string(), train.clone());
+        old.timestamp = Utc::now() - Duration::seconds(CONFIG.edit_message_window_seconds + 10);
+        let old = plane.add_message(old);
+        assert_eq!(
+            plane.edit_message(old.id, author, "new".to_string()).err(),
+            Some(ChangeMessageError::TooLate)
+        );
+
+        assert_eq!(
+            plane.delete_own_message(msg.id, other).err(),
+            Some(ChangeMessageError::NotAuthor)
+        );
+        let tombstone = plane
+            .delete_own_message(msg.id, author)
+            .expect("couldn't delete message");
+        assert!(tombstone.deleted);
+        assert!(tombstone.text.is_empty());
+        assert_eq!(
+            plane.delete_own_message(msg.id, author).err(),
+            Some(ChangeMessageError::NotFound)
+        );
+        let messages = plane.get_messages(Some(other), train);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, old.id);
+        assert!(!messages[0].own);
+    }
+
+    #[test]
+    fn delete_messages_at_their_expiry() {
+        use super::*;
+
+        let (mut plane, train) = plane_with_train();
+        let (author, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let kept = post(&mut plane, author, "we are running late", &train);
+        let mut short = ChatMessageIn::new(author, "the bar is open".to_string(), train.clone());
+        short.ttl_minutes = Some(1);
+        let short = plane.add_message(short);
+        let mut expired = ChatMessageIn::new(author, "door is stuck".to_string(), train.clone());
+        expired.ttl_minutes = Some(1);
+        expired.timestamp = Utc::now() - Duration::minutes(2);
+        plane.add_message(expired);
+
+        let messages = plane.get_messages(Some(other), train);
+        assert_eq!(messages.len(), 2, "expired messages are deleted");
+        let expires_at = |id| {
+            messages
+                .iter()
+                .find(|msg| msg.id == id)
+                .map(|msg| msg.expires_at)
+        };
+        assert_eq!(
+            expires_at(kept.id),
+            Some(kept.timestamp + Duration::minutes(CONFIG.max_message_age_minutes))
+        );
+        assert_eq!(
+            expires_at(short.id),
+            Some(short.timestamp + Duration::minutes(1))
+        );
+        let short_out = messages
+            .iter()
+            .find(|msg| msg.id == short.id)
+            .expect("couldn't find message");
+        assert!(
+            (55..=60).contains(&short_out.expires_in_seconds),
+            "countdown independent of the client's clock"
+        );
     }
 }
 
@@ -434,6 +2745,48 @@ pub enum NoTrace {
         current_speed: f64,
         required_speed: f64,
     },
+    // too slow, but moved recently, e.g. stopped at a station,
+    // keeps the last speed and heading at the current location
+    Stopped {
+        trace: Trace,
+        seconds_left: i64,
+    },
+}
+
+impl NoTrace {
+    // all kinds, see `kind`
+    pub const KINDS: [&'static str; 8] = [
+        "loading_config",
+        "no_permission",
+        "position_unavailable",
+        "timeout",
+        "waiting_for_more_locations",
+        "waiting_for_time_to_pass",
+        "too_slow",
+        "stopped",
+    ];
+
+    // name without the details, e.g. for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NoTrace::LoadingConfig => "loading_config",
+            NoTrace::NoPermission => "no_permission",
+            NoTrace::PositionUnavailable => "position_unavailable",
+            NoTrace::Timeout => "timeout",
+            NoTrace::WaitingForMoreLocations { .. } => "waiting_for_more_locations",
+            NoTrace::WaitingForTimeToPass => "waiting_for_time_to_pass",
+            NoTrace::TooSlow { .. } => "too_slow",
+            NoTrace::Stopped { .. } => "stopped",
+        }
+    }
+}
+
+// trace to match with, also while stopped
+pub fn usable_trace(trace: &Result<Trace, NoTrace>) -> Option<Trace> {
+    match trace {
+        Ok(trace) | Err(NoTrace::Stopped { trace, .. }) => Some(trace.clone()),
+        _ => None,
+    }
 }
 
 impl From<PositionError> for NoTrace {
@@ -447,13 +2800,31 @@ impl From<PositionError> for NoTrace {
     }
 }
 
+// mean earth radius in meters, precise enough for the few hundred meters covered by a history
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+// a location reported by the device, `accuracy_meters` is the radius it's probably within
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fix {
+    pub location: Point<f64>,
+    pub accuracy_meters: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LocationHistory {
-    locations: Vec<(Point<f64>, DateTime<Utc>)>,
+    locations: Vec<Fix>, // newest first
     size: usize,
-    max_location_age_seconds: usize,      // seconds
-    min_location_time_delta_seconds: f64, // seconds
-    min_speed_meters_pers_second: f64,    // meters per second
+    max_location_age_seconds: usize,            // seconds
+    min_location_time_delta_seconds: f64,       // seconds
+    min_speed_meters_pers_second: f64,          // meters per second
+    stop_grace_seconds: i64,                    // seconds
+    max_location_accuracy_meters: f64,          // meters
+    max_plausible_speed_meters_per_second: f64, // meters per second
+    // last valid trace and when it was computed
+    last_trace: Option<(Trace, DateTime<Utc>)>,
+    // fixes rejected in a row as impossible jumps, too many mean the history itself is off
+    rejected_jumps: usize,
 }
 
 impl LocationHistory {
@@ -465,16 +2836,78 @@ impl LocationHistory {
             max_location_age_seconds: config.max_location_age_seconds,
             min_location_time_delta_seconds: config.min_location_time_delta_seconds,
             min_speed_meters_pers_second: config.min_speed_meters_per_second,
+            stop_grace_seconds: config.stop_grace_seconds,
+            max_location_accuracy_meters: config.max_location_accuracy_meters,
+            max_plausible_speed_meters_per_second: config.max_plausible_speed_meters_per_second,
+            last_trace: None,
+            rejected_jumps: 0,
         }
     }
 
-    pub fn add_location(&mut self, location: Point<f64>) {
-        self.locations.insert(0, (location, Utc::now()));
+    // adds a location unless it's too inaccurate or impossible to reach from the previous one
+    pub fn add_location(&mut self, location: Point<f64>, accuracy_meters: f64) {
+        self.add_fix(Fix {
+            location,
+            accuracy_meters,
+            timestamp: Utc::now(),
+        });
+    }
+
+    fn add_fix(&mut self, fix: Fix) {
+        if fix.accuracy_meters.is_nan() || fix.accuracy_meters > self.max_location_accuracy_meters {
+            log::info!(
+                "dropping inaccurate location, accuracy: {} m",
+                fix.accuracy_meters
+            );
+            return;
+        }
+
+        self.remove_old_locations();
+
+        if let Some(latest) = self.locations.first() {
+            let duration = (fix.timestamp - latest.timestamp).num_milliseconds() as f64 / 1000.0;
+            if duration <= 0.0 {
+                return;
+            }
+            // the jump may be explained by the inaccuracy of both fixes
+            let distance = (latest.location.geodesic_distance(&fix.location)
+                - latest.accuracy_meters
+                - fix.accuracy_meters)
+                .max(0.0);
+            if distance / duration > self.max_plausible_speed_meters_per_second {
+                self.rejected_jumps += 1;
+                if self.rejected_jumps <= self.size {
+                    log::info!(
+                        "dropping impossible jump of {} m in {} s",
+                        distance,
+                        duration
+                    );
+                    return;
+                }
+                // more impossible jumps in a row than locations in history,
+                // so the outlier is more likely in the history
+                log::info!(
+                    "resetting location history after {} impossible jumps",
+                    self.rejected_jumps
+                );
+                self.locations.clear();
+            }
+        }
+
+        self.rejected_jumps = 0;
+        self.locations.insert(0, fix);
         if self.locations.len() > self.size {
             self.locations.pop();
         }
     }
 
+    fn remove_old_locations(&mut self) {
+        self.locations.retain(|fix| {
+            let duration = Utc::now() - fix.timestamp;
+            duration.num_seconds() < self.max_location_age_seconds as i64
+        });
+    }
+
     pub fn trace(&mut self) -> Result<Trace, NoTrace> {
         // return Ok(Trace::new((0.0, 0.0), 5.0, 20.0));
         // return Ok(Trace::new((0.0, 0.0), 0.0, 0.0));
@@ -491,10 +2924,7 @@ impl LocationHistory {
         //     required_speed: 3.0,
         // });
 
-        self.locations.retain(|(_, timestamp)| {
-            let duration = Utc::now() - *timestamp;
-            duration.num_seconds() < self.max_location_age_seconds as i64
-        });
+        self.remove_old_locations();
 
         if self.size > self.locations.len() {
             return Err(NoTrace::WaitingForMoreLocations {
@@ -503,42 +2933,96 @@ impl LocationHistory {
             });
         }
 
-        let earliest: Option<&(Point<f64>, DateTime<Utc>)> = self.locations.get(self.size - 1);
-        let latest: Option<&(Point<f64>, DateTime<Utc>)> = self.locations.first();
-
-        match (earliest, latest) {
-            (Some((p_a, t_a)), Some((p_b, t_b))) => {
-                let duration = (*t_b - *t_a).num_milliseconds() as f64 / 1000.0;
-                if duration < self.min_location_time_delta_seconds {
-                    Err(NoTrace::WaitingForTimeToPass)
-                } else {
-                    let distance = p_a.geodesic_distance(p_b);
-                    let speed = distance / duration;
-                    if speed < self.min_speed_meters_pers_second - 0.001 {
-                        return Err(NoTrace::TooSlow {
-                            current_speed: speed,
-                            required_speed: self.min_speed_meters_pers_second,
-                        });
-                    }
-                    let line = geo::Line::new(*p_a, *p_b);
-                    let slope = line.slope().to_radians().to_degrees();
-                    log::info!(
-                        "duration: {} s, distance: {} m, speed: {} m/s, slope: {} deg",
-                        duration,
-                        distance,
-                        speed,
-                        slope
-                    );
-                    Ok(Trace::new((p_b.x(), p_b.y()), speed, slope))
+        let (Some(earliest), Some(latest)) = (self.locations.last(), self.locations.first()) else {
+            log::error!(
+                "couldn't get earliest and latest points, this shouldn't happen, deque: {:?}",
+                self.locations
+            );
+            return Err(NoTrace::WaitingForTimeToPass);
+        };
+
+        let duration = (latest.timestamp - earliest.timestamp).num_milliseconds() as f64 / 1000.0;
+        if duration < self.min_location_time_delta_seconds {
+            return Err(NoTrace::WaitingForTimeToPass);
+        }
+
+        let Some((location, (speed_east, speed_north))) = self.fit() else {
+            return Err(NoTrace::WaitingForTimeToPass);
+        };
+        let speed = speed_east.hypot(speed_north);
+
+        if speed < self.min_speed_meters_pers_second - 0.001 {
+            if let Some((last_trace, timestamp)) = &self.last_trace {
+                let seconds_left =
+                    self.stop_grace_seconds - (Utc::now() - *timestamp).num_seconds();
+                if seconds_left > 0 {
+                    return Err(NoTrace::Stopped {
+                        trace: Trace::new(location, last_trace.speed, last_trace.bearing),
+                        seconds_left,
+                    });
                 }
             }
-            _ => {
-                log::error!(
-                    "couldn't get earliest and latest points, this shouldn't happen, deque: {:?}",
-                    self.locations
-                );
-                Err(NoTrace::WaitingForTimeToPass)
-            }
+            return Err(NoTrace::TooSlow {
+                current_speed: speed,
+                required_speed: self.min_speed_meters_pers_second,
+            });
+        }
+
+        // clockwise from north, between 0 and 360 degrees
+        let bearing = speed_east.atan2(speed_north).to_degrees().rem_euclid(360.0);
+        log::info!(
+            "duration: {} s, speed: {} m/s, bearing: {} deg",
+            duration,
+            speed,
+            bearing
+        );
+        let trace = Trace::new(location, speed, bearing);
+        self.last_trace = Some((trace.clone(), Utc::now()));
+
+        Ok(trace)
+    }
+
+    // fits a constant velocity to all locations with weighted least squares, more accurate
+    // locations weigh more, returns the fitted current location and the velocity east and north
+    // in meters per second
+    fn fit(&self) -> Option<((f64, f64), (f64, f64))> {
+        let latest = self.locations.first()?;
+        let (lon_ref, lat_ref) = (latest.location.x(), latest.location.y());
+        let meters_per_degree = EARTH_RADIUS_METERS.to_radians();
+        let meters_per_degree_lon = meters_per_degree * lat_ref.to_radians().cos();
+
+        // sums for the weighted regressions of east and north over time, latest fix at t = 0
+        let (mut w, mut wt, mut wtt, mut wx, mut wtx, mut wy, mut wty) =
+            (0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+        for fix in self.locations.iter() {
+            let t = (fix.timestamp - latest.timestamp).num_milliseconds() as f64 / 1000.0;
+            // wrap around the antimeridian
+            let lon_delta = (fix.location.x() - lon_ref + 540.0).rem_euclid(360.0) - 180.0;
+            let x = lon_delta * meters_per_degree_lon;
+            let y = (fix.location.y() - lat_ref) * meters_per_degree;
+            let weight = 1.0 / fix.accuracy_meters.max(1.0).powi(2);
+
+            w += weight;
+            wt += weight * t;
+            wtt += weight * t * t;
+            wx += weight * x;
+            wtx += weight * t * x;
+            wy += weight * y;
+            wty += weight * t * y;
+        }
+
+        let denominator = w * wtt - wt * wt;
+        if denominator <= f64::EPSILON {
+            return None;
         }
+        let speed_east = (w * wtx - wt * wx) / denominator;
+        let speed_north = (w * wty - wt * wy) / denominator;
+        let x = (wx - speed_east * wt) / w;
+        let y = (wy - speed_north * wt) / w;
+
+        let lon = (lon_ref + x / meters_per_degree_lon + 540.0).rem_euclid(360.0) - 180.0;
+        let lat = lat_ref + y / meters_per_degree;
+
+        Some(((lon, lat), (speed_east, speed_north)))
     }
 }
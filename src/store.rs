@@ -0,0 +1,711 @@
+use crate::grid::Grid;
+use crate::state::ChatMessage;
+use cfg_if::cfg_if;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use uuid::Uuid;
+
+// area to search for messages in, a location and a radius in meters around it
+pub type Area = ((f64, f64), f64);
+
+// numbers about the stored messages
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageStats {
+    pub messages: usize,
+    pub authors: usize,
+    // messages sent at or after the given time
+    pub recent_messages: usize,
+    // memory used by messages, for stores on disk the size of the stored messages
+    pub estimated_bytes: usize,
+}
+
+// storage for the messages and usernames of a `Plane`
+pub trait MessageStore: std::fmt::Debug + Send {
+    // inserts a new message or replaces the stored one with the same id
+    fn save_message(&mut self, msg: &ChatMessage) -> anyhow::Result<()>;
+
+    fn message(&self, id: Uuid) -> anyhow::Result<Option<ChatMessage>>;
+
+    // messages passing `filter`, only looking at messages within `area` if one is given
+    fn find_messages(
+        &self,
+        area: Option<Area>,
+        filter: &dyn Fn(&ChatMessage) -> bool,
+    ) -> anyhow::Result<Vec<ChatMessage>>;
+
+    // messages posted to any of the given rooms at or after `since`
+    fn room_messages(
+        &self,
+        room_ids: &[Uuid],
+        since: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<ChatMessage>>;
+
+    // deletes a single message, does nothing if there is none with this id
+    fn delete_message(&mut self, id: Uuid) -> anyhow::Result<()>;
+
+    // deletes all messages sent at or before `timestamp`
+    fn delete_messages_before(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()>;
+
+    // deletes all messages with their own expiry at or before `now`
+    fn delete_expired_messages(&mut self, now: DateTime<Utc>) -> anyhow::Result<()>;
+
+    // deletes the oldest messages until at most `max_messages` remain
+    fn truncate_messages(&mut self, max_messages: usize) -> anyhow::Result<()>;
+
+    // numbers for the admin page, messages sent at or after `since` count as recent
+    fn stats(&self, since: DateTime<Utc>) -> anyhow::Result<MessageStats>;
+
+    fn username(&self, author: Uuid) -> anyhow::Result<Option<String>>;
+
+    fn save_username(&mut self, author: Uuid, username: &str) -> anyhow::Result<()>;
+}
+
+// keeps everything in memory, gone after a restart
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    messages: HashMap<Uuid, ChatMessage>,
+    // timestamps and ids of messages, newest first
+    message_ids: VecDeque<(DateTime<Utc>, Uuid)>,
+    // expiries and ids of messages with their own expiry, soonest first
+    expiring_ids: BTreeSet<(DateTime<Utc>, Uuid)>,
+    // message ids by location, to avoid checking every message in `find_messages`
+    grid: Grid,
+    message_ids_by_room: HashMap<Uuid, HashSet<Uuid>>,
+    author_usernames_by_id: HashMap<Uuid, String>,
+}
+
+impl MemoryStore {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            messages: HashMap::with_capacity(capacity),
+            message_ids: VecDeque::with_capacity(capacity),
+            expiring_ids: BTreeSet::new(),
+            grid: Grid::default(),
+            message_ids_by_room: HashMap::new(),
+            author_usernames_by_id: HashMap::new(),
+        }
+    }
+
+    fn remove_oldest_message(&mut self) {
+        let Some((_, id)) = self.message_ids.pop_back() else {
+            return;
+        };
+        if let Some(msg) = self.messages.remove(&id) {
+            self.grid.remove(&id, msg.trace.location);
+            if let Some(room_id) = msg.room_id {
+                self.remove_from_room(&id, room_id);
+            }
+            if let Some(expires_at) = msg.expires_at {
+                self.expiring_ids.remove(&(expires_at, id));
+            }
+        }
+    }
+
+    fn remove_from_room(&mut self, id: &Uuid, room_id: Uuid) {
+        if let Some(ids) = self.message_ids_by_room.get_mut(&room_id) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.message_ids_by_room.remove(&room_id);
+            }
+        }
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn save_message(&mut self, msg: &ChatMessage) -> anyhow::Result<()> {
+        match self.messages.insert(msg.id, msg.clone()) {
+            None => {
+                self.grid.insert(msg.id, msg.trace.location);
+                // new messages are usually the newest ones, so this is almost always the front
+                let index = self
+                    .message_ids
+                    .partition_point(|(timestamp, _)| *timestamp > msg.timestamp);
+                self.message_ids.insert(index, (msg.timestamp, msg.id));
+                if let Some(expires_at) = msg.expires_at {
+                    self.expiring_ids.insert((expires_at, msg.id));
+                }
+            }
+            Some(old_msg) => {
+                if old_msg.room_id == msg.room_id {
+                    return Ok(());
+                }
+                if let Some(room_id) = old_msg.room_id {
+                    self.remove_from_room(&msg.id, room_id);
+                }
+            }
+        }
+        if let Some(room_id) = msg.room_id {
+            self.message_ids_by_room
+                .entry(room_id)
+                .or_default()
+                .insert(msg.id);
+        }
+
+        Ok(())
+    }
+
+    fn message(&self, id: Uuid) -> anyhow::Result<Option<ChatMessage>> {
+        Ok(self.messages.get(&id).cloned())
+    }
+
+    fn find_messages(
+        &self,
+        area: Option<Area>,
+        filter: &dyn Fn(&ChatMessage) -> bool,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let candidates = area
+            .and_then(|(location, radius_meters)| self.grid.candidates(location, radius_meters));
+
+        let messages = match candidates {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| self.messages.get(id))
+                .filter(|msg| filter(msg))
+                .cloned()
+                .collect(),
+            None => self
+                .messages
+                .values()
+                .filter(|msg| filter(msg))
+                .cloned()
+                .collect(),
+        };
+
+        Ok(messages)
+    }
+
+    fn room_messages(
+        &self,
+        room_ids: &[Uuid],
+        since: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let messages = room_ids
+            .iter()
+            .filter_map(|room_id| self.message_ids_by_room.get(room_id))
+            .flatten()
+            .filter_map(|id| self.messages.get(id))
+            .filter(|msg| msg.timestamp >= since)
+            .cloned()
+            .collect();
+
+        Ok(messages)
+    }
+
+    fn delete_message(&mut self, id: Uuid) -> anyhow::Result<()> {
+        let Some(msg) = self.messages.remove(&id) else {
+            return Ok(());
+        };
+        self.grid.remove(&id, msg.trace.location);
+        if let Some(room_id) = msg.room_id {
+            self.remove_from_room(&id, room_id);
+        }
+        if let Some(expires_at) = msg.expires_at {
+            self.expiring_ids.remove(&(expires_at, id));
+        }
+        let index = self
+            .message_ids
+            .partition_point(|(timestamp, _)| *timestamp > msg.timestamp);
+        if let Some(offset) = self
+            .message_ids
+            .iter()
+            .skip(index)
+            .position(|(_, message_id)| *message_id == id)
+        {
+            self.message_ids.remove(index + offset);
+        }
+
+        Ok(())
+    }
+
+    fn delete_messages_before(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()> {
+        while let Some((oldest_timestamp, _)) = self.message_ids.back() {
+            if *oldest_timestamp > timestamp {
+                break;
+            }
+            self.remove_oldest_message();
+        }
+
+        Ok(())
+    }
+
+    fn delete_expired_messages(&mut self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        while let Some(&(expires_at, id)) = self.expiring_ids.first() {
+            if expires_at > now {
+                break;
+            }
+            self.delete_message(id)?;
+        }
+
+        Ok(())
+    }
+
+    fn truncate_messages(&mut self, max_messages: usize) -> anyhow::Result<()> {
+        while self.message_ids.len() > max_messages {
+            self.remove_oldest_message();
+        }
+
+        Ok(())
+    }
+
+    fn stats(&self, since: DateTime<Utc>) -> anyhow::Result<MessageStats> {
+        let authors: HashSet<Uuid> = self.messages.values().map(|msg| msg.author).collect();
+        let recent_messages = self
+            .message_ids
+            .partition_point(|(timestamp, _)| *timestamp >= since);
+        let estimated_bytes = self
+            .messages
+            .values()
+            .map(|msg| {
+                std::mem::size_of::<(Uuid, ChatMessage)>()
+                    + std::mem::size_of::<(DateTime<Utc>, Uuid)>()
+                    + msg.text.capacity()
+                    + msg.username.capacity()
+                    + (msg.upvoters.capacity() + msg.downvoters.capacity())
+                        * std::mem::size_of::<Uuid>()
+            })
+            .sum();
+
+        Ok(MessageStats {
+            messages: self.messages.len(),
+            authors: authors.len(),
+            recent_messages,
+            estimated_bytes,
+        })
+    }
+
+    fn username(&self, author: Uuid) -> anyhow::Result<Option<String>> {
+        Ok(self.author_usernames_by_id.get(&author).cloned())
+    }
+
+    fn save_username(&mut self, author: Uuid, username: &str) -> anyhow::Result<()> {
+        self.author_usernames_by_id
+            .insert(author, username.to_string());
+
+        Ok(())
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "ssr")] {
+        use crate::grid::BoundingBox;
+        use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+
+        // messages stored by older versions may not parse anymore, they expire soon anyway
+        fn parse_message(data: &str) -> Option<ChatMessage> {
+            match serde_json::from_str(data) {
+                Ok(msg) => Some(msg),
+                Err(err) => {
+                    log::warn!("couldn't parse stored message, error: {}", err);
+                    None
+                }
+            }
+        }
+
+        // keeps everything in an embedded sqlite database, survives restarts
+        //
+        // messages are stored as json, with location, timestamp and room in their own columns for lookups
+        #[derive(Debug)]
+        pub struct SqliteStore {
+            connection: Connection,
+        }
+
+        impl SqliteStore {
+            pub fn open(path: &str) -> anyhow::Result<Self> {
+                let connection = Connection::open(path)?;
+                connection.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS messages (
+                        id TEXT PRIMARY KEY,
+                        lon REAL,
+                        lat REAL,
+                        timestamp INTEGER NOT NULL,
+                        room_id TEXT,
+                        expires_at INTEGER,
+                        data TEXT NOT NULL
+                    );
+                    CREATE INDEX IF NOT EXISTS messages_location ON messages (lat, lon);
+                    CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+                    CREATE TABLE IF NOT EXISTS usernames (
+                        author TEXT PRIMARY KEY,
+                        username TEXT NOT NULL
+                    );",
+                )?;
+
+                // databases created before messages had rooms
+                let has_room_id = connection
+                    .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'room_id'")?
+                    .exists([])?;
+                if !has_room_id {
+                    connection.execute("ALTER TABLE messages ADD COLUMN room_id TEXT", [])?;
+                }
+                connection.execute(
+                    "CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room_id)",
+                    [],
+                )?;
+
+                // databases created before messages had their own expiry
+                let has_expires_at = connection
+                    .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'expires_at'")?
+                    .exists([])?;
+                if !has_expires_at {
+                    connection.execute("ALTER TABLE messages ADD COLUMN expires_at INTEGER", [])?;
+                }
+                connection.execute(
+                    "CREATE INDEX IF NOT EXISTS messages_expires_at ON messages (expires_at)",
+                    [],
+                )?;
+
+                Ok(Self { connection })
+            }
+        }
+
+        impl MessageStore for SqliteStore {
+            fn save_message(&mut self, msg: &ChatMessage) -> anyhow::Result<()> {
+                self.connection.execute(
+                    "INSERT OR REPLACE INTO messages (id, lon, lat, timestamp, room_id, expires_at, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        msg.id.to_string(),
+                        msg.trace.location.0,
+                        msg.trace.location.1,
+                        msg.timestamp.timestamp_millis(),
+                        msg.room_id.map(|room_id| room_id.to_string()),
+                        msg.expires_at.map(|expires_at| expires_at.timestamp_millis()),
+                        serde_json::to_string(msg)?,
+                    ],
+                )?;
+
+                Ok(())
+            }
+
+            fn message(&self, id: Uuid) -> anyhow::Result<Option<ChatMessage>> {
+                let data: Option<String> = self
+                    .connection
+                    .query_row(
+                        "SELECT data FROM messages WHERE id = ?1",
+                        params![id.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+            }
+
+            fn find_messages(
+                &self,
+                area: Option<Area>,
+                filter: &dyn Fn(&ChatMessage) -> bool,
+            ) -> anyhow::Result<Vec<ChatMessage>> {
+                let bounding_box = area.and_then(|(location, radius_meters)| {
+                    BoundingBox::around(location, radius_meters)
+                });
+
+                let mut sql = "SELECT data FROM messages".to_string();
+                let mut bounds: Vec<f64> = vec![];
+                if let Some(bounding_box) = bounding_box {
+                    let lon_ranges = bounding_box.lon_ranges();
+                    let lon_condition = lon_ranges
+                        .iter()
+                        .map(|_| "lon BETWEEN ? AND ?")
+                        .collect::<Vec<&str>>()
+                        .join(" OR ");
+                    // locations outside of the valid range can't be looked up by area, so they are
+                    // always included, like the unindexed ones of `MemoryStore`, NaN is stored as NULL
+                    sql.push_str(&format!(
+                        " WHERE (lat BETWEEN ? AND ? AND ({lon_condition}))
+                        OR lat IS NULL OR lon IS NULL
+                        OR NOT (lat BETWEEN -90 AND 90 AND lon BETWEEN -180 AND 180)"
+                    ));
+                    bounds.push(bounding_box.lat_min);
+                    bounds.push(bounding_box.lat_max);
+                    for (lon_min, lon_max) in lon_ranges {
+                        bounds.push(lon_min);
+                        bounds.push(lon_max);
+                    }
+                }
+
+                let mut statement = self.connection.prepare_cached(&sql)?;
+                let rows = statement.query_map(params_from_iter(bounds.iter()), |row| {
+                    row.get::<_, String>(0)
+                })?;
+
+                let mut messages = vec![];
+                for data in rows {
+                    let Some(msg) = parse_message(&data?) else {
+                        continue;
+                    };
+                    if filter(&msg) {
+                        messages.push(msg);
+                    }
+                }
+
+                Ok(messages)
+            }
+
+            fn room_messages(
+                &self,
+                room_ids: &[Uuid],
+                since: DateTime<Utc>,
+            ) -> anyhow::Result<Vec<ChatMessage>> {
+                if room_ids.is_empty() {
+                    return Ok(vec![]);
+                }
+
+                let placeholders = room_ids.iter().map(|_| "?").collect::<Vec<&str>>().join(", ");
+                let sql = format!(
+                    "SELECT data FROM messages WHERE room_id IN ({placeholders}) AND timestamp >= ?"
+                );
+                let mut values: Vec<rusqlite::types::Value> = room_ids
+                    .iter()
+                    .map(|room_id| room_id.to_string().into())
+                    .collect();
+                values.push(since.timestamp_millis().into());
+
+                let mut statement = self.connection.prepare_cached(&sql)?;
+                let rows = statement.query_map(params_from_iter(values.iter()), |row| {
+                    row.get::<_, String>(0)
+                })?;
+
+                let mut messages = vec![];
+                for data in rows {
+                    if let Some(msg) = parse_message(&data?) {
+                        messages.push(msg);
+                    }
+                }
+
+                Ok(messages)
+            }
+
+            fn delete_message(&mut self, id: Uuid) -> anyhow::Result<()> {
+                self.connection
+                    .execute("DELETE FROM messages WHERE id = ?1", params![id.to_string()])?;
+
+                Ok(())
+            }
+
+            fn delete_messages_before(&mut self, timestamp: DateTime<Utc>) -> anyhow::Result<()> {
+                self.connection.execute(
+                    "DELETE FROM messages WHERE timestamp <= ?1",
+                    params![timestamp.timestamp_millis()],
+                )?;
+
+                Ok(())
+            }
+
+            fn delete_expired_messages(&mut self, now: DateTime<Utc>) -> anyhow::Result<()> {
+                self.connection.execute(
+                    "DELETE FROM messages WHERE expires_at <= ?1",
+                    params![now.timestamp_millis()],
+                )?;
+
+                Ok(())
+            }
+
+            fn truncate_messages(&mut self, max_messages: usize) -> anyhow::Result<()> {
+                let count: i64 = self
+                    .connection
+                    .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
+                let excess = count - max_messages as i64;
+                if excess <= 0 {
+                    return Ok(());
+                }
+
+                // exactly as many as needed, messages sent at the same time are deleted in the
+                // order they were saved, like in `MemoryStore`
+                self.connection.execute(
+                    "DELETE FROM messages WHERE id IN (
+                        SELECT id FROM messages ORDER BY timestamp ASC, rowid ASC LIMIT ?1
+                    )",
+                    params![excess],
+                )?;
+
+                Ok(())
+            }
+
+            fn stats(&self, since: DateTime<Utc>) -> anyhow::Result<MessageStats> {
+                let stats = self.connection.query_row(
+                    "SELECT
+                        COUNT(*),
+                        COUNT(DISTINCT json_extract(data, '$.author')),
+                        COALESCE(SUM(CASE WHEN timestamp >= ?1 THEN 1 ELSE 0 END), 0),
+                        COALESCE(SUM(LENGTH(data)), 0)
+                    FROM messages",
+                    params![since.timestamp_millis()],
+                    |row| {
+                        Ok(MessageStats {
+                            messages: row.get::<_, i64>(0)? as usize,
+                            authors: row.get::<_, i64>(1)? as usize,
+                            recent_messages: row.get::<_, i64>(2)? as usize,
+                            estimated_bytes: row.get::<_, i64>(3)? as usize,
+                        })
+                    },
+                )?;
+
+                Ok(stats)
+            }
+
+            fn username(&self, author: Uuid) -> anyhow::Result<Option<String>> {
+                Ok(self
+                    .connection
+                    .query_row(
+                        "SELECT username FROM usernames WHERE author = ?1",
+                        params![author.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            }
+
+            fn save_username(&mut self, author: Uuid, username: &str) -> anyhow::Result<()> {
+                self.connection.execute(
+                    "INSERT OR REPLACE INTO usernames (author, username) VALUES (?1, ?2)",
+                    params![author.to_string(), username],
+                )?;
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    #[test]
+    fn sqlite_store_behaves_like_memory_store() {
+        use super::*;
+        use crate::state::{ChatMessageIn, Trace};
+        use chrono::Duration;
+
+        let mut memory_store = MemoryStore::default();
+        let mut sqlite_store = SqliteStore::open(":memory:").expect("couldn't open sqlite store");
+        let stores: [&mut dyn MessageStore; 2] = [&mut memory_store, &mut sqlite_store];
+
+        let author = Uuid::new_v4();
+        let room_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        let now = Utc::now();
+        let locations = [
+            (9.99, 53.55),
+            (10.0, 53.56),
+            (179.999, -16.5),
+            (-179.999, -16.5),
+            (200.0, 0.0),
+            (f64::NAN, 53.55),
+        ];
+        let messages: Vec<ChatMessage> = locations
+            .iter()
+            .enumerate()
+            .map(|(i, location)| {
+                let mut msg =
+                    ChatMessageIn::new(author, i.to_string(), Trace::new(*location, 10.0, 0.0));
+                // the third message is sent at the same time as the second one
+                msg.timestamp = now - Duration::minutes(if i == 2 { 1 } else { i as i64 });
+                let mut msg = ChatMessage::from((msg, "username".to_string()));
+                msg.room_id = Some(room_ids[i % 2]);
+                if i == 0 {
+                    msg.expires_at = Some(now + Duration::minutes(1));
+                }
+                msg
+            })
+            .collect();
+
+        let mut results = vec![];
+        for store in stores {
+            for msg in messages.iter() {
+                store.save_message(msg).expect("couldn't save message");
+            }
+            store
+                .save_username(author, "username")
+                .expect("couldn't save username");
+
+            let ids = |messages: Vec<ChatMessage>| {
+                let mut ids: Vec<Uuid> = messages.into_iter().map(|msg| msg.id).collect();
+                ids.sort();
+                ids
+            };
+            let near_hamburg = store
+                .find_messages(Some(((9.99, 53.55), 2000.0)), &|_| true)
+                .expect("couldn't find messages");
+            let near_antimeridian = store
+                .find_messages(Some(((180.0, -16.5), 2000.0)), &|_| true)
+                .expect("couldn't find messages");
+            let in_room = store
+                .room_messages(&room_ids[..1], now - Duration::minutes(2))
+                .expect("couldn't get room messages");
+            let stats = store
+                .stats(now - Duration::minutes(2))
+                .expect("couldn't get stats");
+
+            store
+                .delete_messages_before(now - Duration::minutes(3))
+                .expect("couldn't delete messages");
+            store
+                .truncate_messages(2)
+                .expect("couldn't truncate messages");
+            let remaining = store
+                .find_messages(None, &|_| true)
+                .expect("couldn't find messages");
+            store
+                .delete_message(messages[2].id)
+                .expect("couldn't delete message");
+            let remaining_after_delete = store
+                .find_messages(None, &|_| true)
+                .expect("couldn't find messages");
+            let newest = store
+                .message(messages[0].id)
+                .expect("couldn't get message")
+                .map(|msg| msg.id);
+            store
+                .delete_expired_messages(now)
+                .expect("couldn't delete expired messages");
+            let remaining_before_expiry = store
+                .find_messages(None, &|_| true)
+                .expect("couldn't find messages");
+            store
+                .delete_expired_messages(now + Duration::minutes(1))
+                .expect("couldn't delete expired messages");
+            let remaining_after_expiry = store
+                .find_messages(None, &|_| true)
+                .expect("couldn't find messages");
+
+            results.push((
+                ids(near_hamburg),
+                ids(near_antimeridian),
+                ids(in_room),
+                ids(remaining),
+                ids(remaining_after_delete),
+                newest,
+                store.username(author).expect("couldn't get username"),
+                (stats.messages, stats.authors, stats.recent_messages),
+                ids(remaining_before_expiry),
+                ids(remaining_after_expiry),
+            ));
+        }
+
+        assert_eq!(results[0], results[1]);
+        assert_eq!(
+            results[0].0.len(),
+            4,
+            "near hamburg, including the invalid locations"
+        );
+        assert_eq!(
+            results[0].1.len(),
+            4,
+            "near antimeridian, including the invalid locations"
+        );
+        assert_eq!(results[0].2, {
+            let mut ids = vec![messages[0].id, messages[2].id];
+            ids.sort();
+            ids
+        });
+        assert_eq!(
+            results[0].3,
+            {
+                let mut ids = vec![messages[0].id, messages[2].id];
+                ids.sort();
+                ids
+            },
+            "the earlier saved of two messages sent at the same time is truncated first"
+        );
+        assert_eq!(results[0].4, vec![messages[0].id]);
+        assert_eq!(results[0].7, (6, 1, 3));
+        assert_eq!(results[0].8, vec![messages[0].id]);
+        assert!(results[0].9.is_empty(), "deleted at its own expiry");
+    }
+}
@@ -0,0 +1,183 @@
+use cfg_if::cfg_if;
+
+cfg_if! { if #[cfg(feature = "ssr")] {
+    use axum::{
+        extract::{
+            ws::{Message, WebSocket, WebSocketUpgrade},
+            State,
+        },
+        response::IntoResponse,
+        Extension,
+    };
+    use geo::{geometry::Point, GeodesicDistance};
+    use std::collections::HashSet;
+    use tokio::sync::broadcast::error::RecvError;
+    use uuid::Uuid;
+    use crate::metrics::METRICS;
+    use crate::room::Membership;
+    use crate::state::{AppState, ChatMessageOut, Push, Trace};
+
+    // meters a user may move before messages matching their new location are looked up again
+    const SNAPSHOT_DISTANCE_METERS: f64 = 500.0;
+
+    pub async fn ws_handler(
+        ws: WebSocketUpgrade,
+        Extension(user_id): Extension<Uuid>,
+        State(app_state): State<AppState>,
+    ) -> impl IntoResponse {
+        ws.on_upgrade(move |socket| handle_socket(socket, app_state, Some(user_id)))
+    }
+
+    // clients send their current trace as json, the server answers with a snapshot of matching
+    // messages for the first trace and afterwards pushes new or changed messages that match or
+    // were posted to the user's room, as well as messages that start matching once the user
+    // moved on or changed rooms, new direct messages of the user are pushed even without a trace
+    async fn handle_socket(mut socket: WebSocket, app_state: AppState, user_id: Option<Uuid>) {
+        let mut chat_msg_out_rx = app_state.chat_msg_out_tx.subscribe();
+        let mut direct_msg_out_rx = app_state.direct_msg_out_tx.subscribe();
+        let mut trace: Option<Trace> = None;
+        let mut membership: Option<Membership> = None;
+        // trace of the last snapshot and ids of the messages sent since then
+        let mut snapshot_trace: Option<Trace> = None;
+        let mut sent_ids: HashSet<Uuid> = HashSet::new();
+
+        loop {
+            let push = tokio::select! {
+                msg = socket.recv() => {
+                    let text = match msg {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(err)) => {
+                            log::warn!("couldn't receive websocket message, error: {}", err);
+                            break;
+                        }
+                    };
+                    let new_trace = match serde_json::from_str::<Trace>(&text) {
+                        Ok(new_trace) => new_trace,
+                        Err(err) => {
+                            log::warn!("couldn't parse trace from websocket, error: {}", err);
+                            continue;
+                        }
+                    };
+                    if let Err(err) = check_trace(&app_state, user_id, &new_trace).await {
+                        log::warn!("rejected trace from websocket of user {:?}, error: {}", user_id, err);
+                        continue;
+                    }
+                    trace = Some(new_trace.clone());
+                    let new_membership = join_room(&app_state, user_id, &new_trace).await;
+                    let changed_room = new_membership.as_ref().map(|membership| membership.room_id)
+                        != membership.as_ref().map(|membership| membership.room_id);
+                    membership = new_membership;
+                    let moved = snapshot_trace.as_ref().is_none_or(|snapshot_trace| {
+                        distance_meters(snapshot_trace, &new_trace) > SNAPSHOT_DISTANCE_METERS
+                    });
+                    if !changed_room && !moved {
+                        continue;
+                    }
+                    snapshot_trace = Some(new_trace.clone());
+                    // only messages the client doesn't have yet, changes of the others are pushed
+                    let messages = snapshot(&app_state, user_id, new_trace).await;
+                    let snapshot_ids = messages.iter().map(|msg| msg.id).collect();
+                    let new_messages = messages
+                        .into_iter()
+                        .filter(|msg| !sent_ids.contains(&msg.id))
+                        .collect();
+                    sent_ids = snapshot_ids;
+                    Push::Messages(new_messages)
+                }
+                msg = chat_msg_out_rx.recv() => {
+                    let Some(trace) = trace.clone() else {
+                        continue;
+                    };
+                    match msg {
+                        Ok(msg) => {
+                            let in_room = membership
+                                .as_ref()
+                                .is_some_and(|membership| membership.contains(&msg));
+                            if !in_room && !trace.overlaps_with(&msg.trace) {
+                                continue;
+                            }
+                            sent_ids.insert(msg.id);
+                            Push::Messages(vec![ChatMessageOut::from((msg, user_id))])
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            log::warn!("websocket lagged behind by {} messages, sending snapshot", skipped);
+                            METRICS.messages_dropped.inc_by("websocket_lagged", skipped);
+                            let messages = snapshot(&app_state, user_id, trace).await;
+                            sent_ids = messages.iter().map(|msg| msg.id).collect();
+                            Push::Messages(messages)
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                user_ids = direct_msg_out_rx.recv() => {
+                    let Some(user_id) = user_id else {
+                        continue;
+                    };
+                    match user_ids {
+                        Ok((a, b)) if a == user_id || b == user_id => Push::DirectMessage,
+                        Ok(_) => continue,
+                        // the client loads its conversations again anyway
+                        Err(RecvError::Lagged(skipped)) => {
+                            log::warn!("websocket lagged behind by {} direct messages", skipped);
+                            Push::DirectMessage
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            };
+
+            if matches!(&push, Push::Messages(messages) if messages.is_empty()) {
+                continue;
+            }
+
+            let json = match serde_json::to_string(&push) {
+                Ok(json) => json,
+                Err(err) => {
+                    log::error!("couldn't serialize push for websocket, error: {}", err);
+                    continue;
+                }
+            };
+            if socket.send(Message::Text(json)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn snapshot(app_state: &AppState, user_id: Option<Uuid>, trace: Trace) -> Vec<ChatMessageOut> {
+        match app_state.plane.get_messages(user_id, trace).await {
+            Ok(messages) => messages,
+            Err(err) => {
+                log::error!("couldn't get messages in websocket handler, error: {}", err);
+                vec![]
+            }
+        }
+    }
+
+    fn distance_meters(trace: &Trace, other: &Trace) -> f64 {
+        Point::new(trace.location.0, trace.location.1)
+            .geodesic_distance(&Point::new(other.location.0, other.location.1))
+    }
+
+    // a trace that couldn't be checked is rejected too, it must not reach the room
+    async fn check_trace(app_state: &AppState, user_id: Option<Uuid>, trace: &Trace) -> anyhow::Result<()> {
+        match app_state.plane.check_trace(user_id, trace.clone()).await {
+            Ok(checked_trace) => Ok(checked_trace?),
+            Err(err) => {
+                log::error!("couldn't check trace in websocket handler, error: {}", err);
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn join_room(app_state: &AppState, user_id: Option<Uuid>, trace: &Trace) -> Option<Membership> {
+        match app_state.plane.join_room(user_id?, trace.clone()).await {
+            Ok(membership) => membership,
+            Err(err) => {
+                log::error!("couldn't join room in websocket handler, error: {}", err);
+                None
+            }
+        }
+    }
+}}